@@ -17,18 +17,19 @@
 #[macro_use]
 extern crate criterion;
 extern crate melissa;
-extern crate sodiumoxide;
 
 use criterion::Criterion;
+use melissa::authentication::*;
 use melissa::crypto::aesgcm::*;
+use melissa::crypto::ciphersuite::*;
 use melissa::crypto::eckem::*;
 use melissa::crypto::hkdf::*;
+use melissa::crypto::rng::*;
 use melissa::group::*;
 use melissa::keys::*;
 use melissa::utils::*;
-use sodiumoxide::randombytes;
 
-const DATA: &'static [u8; 1 * 1024] = &[1u8; 1 * 1024];
+const DATA: &[u8; 1024] = &[1u8; 1024];
 
 // Crypto
 
@@ -37,23 +38,23 @@ fn hkdf() {
     let salt = hex_to_bytes("000102030405060708090a0b0c");
     let len = 32;
 
-    let prk = extract(Salt(&salt), Input(&ikm));
-    let _okm = expand(prk, Info(DATA), len);
+    let prk = extract(HashAlgorithm::Sha256, Salt(&salt), Input(&ikm));
+    let _okm = expand(HashAlgorithm::Sha256, &prk, Info(DATA), len);
 }
 
 fn aes128_seal() {
-    let key: Aes128Key = Aes128Key::from(randombytes::randombytes(AES128KEYBYTES));
-    let nonce = Nonce::new_random();
-    let _encrypted = aes_128_seal(DATA, &key, &nonce).unwrap();
+    let key: Aes128Key = Aes128Key::from(OsRng.random_bytes(AES128KEYBYTES));
+    let nonce = Nonce::new_random(&mut OsRng);
+    let _encrypted = aes_128_seal(DATA, &[], &key, &nonce).unwrap();
 }
 
 fn aes128_open(ciphertext: &[u8], key: &Aes128Key, nonce: &Nonce) {
-    let _decrypted = aes_128_open(ciphertext, key, nonce).unwrap();
+    let _decrypted = aes_128_open(ciphertext, &[], key, nonce).unwrap();
 }
 
 fn eckem_encrypt() {
-    let kp = X25519KeyPair::new_random();
-    let _encrypted = X25519AES::encrypt(&kp.public_key, DATA).unwrap();
+    let kp = X25519KeyPair::new_random(&mut OsRng);
+    let _encrypted = X25519AES::encrypt(&kp.public_key, DATA, &mut OsRng).unwrap();
 }
 
 fn eckem_decrypt(private_key: &X25519PrivateKey, ciphertext: &X25519AESCiphertext) {
@@ -63,35 +64,40 @@ fn eckem_decrypt(private_key: &X25519PrivateKey, ciphertext: &X25519AESCiphertex
 // UserInitKeys
 
 fn create_uik_bundle(identity: &Identity) -> UserInitKeyBundle {
-    UserInitKeyBundle::new(&identity)
+    let credential = Credential::Basic(BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: identity.public_key,
+    });
+    UserInitKeyBundle::new(identity, credential, &mut OsRng).unwrap()
 }
 
 fn large_group() {
     const GROUPSIZE: usize = 10;
 
-    let mut identities: Vec<Identity> = Vec::new();
-    let mut credentials: Vec<BasicCredential> = Vec::new();
     let mut uiks: Vec<UserInitKeyBundle> = Vec::new();
     let mut groups: Vec<Group> = Vec::new();
 
     for i in 0..GROUPSIZE {
-        let identity = Identity::random();
-        identities.push(identity.clone());
-        let credential = BasicCredential {
+        let identity = Identity::random(&mut OsRng);
+        let credential = Credential::Basic(BasicCredential {
             identity: format!("Member {}", i).as_bytes().to_vec(),
             public_key: identity.public_key,
-        };
-        credentials.push(credential.clone());
-        uiks.push(UserInitKeyBundle::new(&identity));
-        groups.push(Group::new(identity, credential, GroupId::random()));
+        });
+        uiks.push(UserInitKeyBundle::new(&identity, credential.clone(), &mut OsRng).unwrap());
+        groups.push(Group::new(
+            Box::new(identity),
+            credential,
+            GroupId::random(&mut OsRng),
+            &SUITE_CURVE25519_AES128GCM_SHA256,
+            Box::new(AllowAll),
+        ));
     }
 
-    for i in 0..GROUPSIZE {
-        for j in 0..GROUPSIZE {
+    for (i, group) in groups.iter_mut().enumerate() {
+        for (j, uik) in uiks.iter().enumerate() {
             if i != j {
-                let (_welcome_alice_bob, add_alice_bob) =
-                    groups[i].create_add(credentials[j].clone(), &uiks[j].init_key);
-                groups[i].process_add(&add_alice_bob);
+                let (_welcome_alice_bob, add_alice_bob) = group.create_add(&uik.init_key).unwrap();
+                group.process_add(&add_alice_bob).unwrap();
             }
         }
     }
@@ -101,59 +107,76 @@ fn large_group() {
 
 fn create_group() {
     // Define identities
-    let alice_identity = Identity::random();
-    let bob_identity = Identity::random();
+    let alice_identity = Identity::random(&mut OsRng);
+    let bob_identity = Identity::random(&mut OsRng);
 
-    let alice_credential = BasicCredential {
+    let alice_credential = Credential::Basic(BasicCredential {
         identity: "Alice".as_bytes().to_vec(),
         public_key: alice_identity.public_key,
-    };
-    let bob_credential = BasicCredential {
+    });
+    let bob_credential = Credential::Basic(BasicCredential {
         identity: "Bob".as_bytes().to_vec(),
         public_key: bob_identity.public_key,
-    };
+    });
 
     // Generate UserInitKeys
-    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let bob_init_key_bundle =
+        UserInitKeyBundle::new(&bob_identity, bob_credential, &mut OsRng).unwrap();
     let bob_init_key = bob_init_key_bundle.init_key.clone();
 
     // Create a group with Alice
-    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let mut group_alice = Group::new(
+        Box::new(alice_identity),
+        alice_credential,
+        GroupId::random(&mut OsRng),
+        &SUITE_CURVE25519_AES128GCM_SHA256,
+        Box::new(AllowAll),
+    );
 
     // Alice adds Bob
-    let (welcome_alice_bob, add_alice_bob) = group_alice.create_add(bob_credential, &bob_init_key);
-    group_alice.process_add(&add_alice_bob);
-
-    let mut group_bob = Group::new_from_welcome(bob_identity, &welcome_alice_bob);
-    assert_eq!(group_alice.get_init_secret(), group_bob.get_init_secret());
+    let (welcome_alice_bob, add_alice_bob) = group_alice.create_add(&bob_init_key).unwrap();
+    group_alice.process_add(&add_alice_bob).unwrap();
+
+    let mut group_bob = Group::join(
+        Box::new(bob_identity),
+        welcome_alice_bob,
+        GroupConfig::default(),
+        Box::new(AllowAll),
+    )
+    .unwrap();
+    assert!(group_alice
+        .get_init_secret()
+        .ct_eq(group_bob.get_init_secret()));
 
     // Bob updates
     let update_bob = group_bob.create_update();
-    group_bob.process_update(1, &update_bob);
-    group_alice.process_update(1, &update_bob);
-    assert_eq!(group_alice.get_init_secret(), group_bob.get_init_secret());
+    group_bob.process_update(1, &update_bob).unwrap();
+    group_alice.process_update(1, &update_bob).unwrap();
+    assert!(group_alice
+        .get_init_secret()
+        .ct_eq(group_bob.get_init_secret()));
 }
 
 fn criterion_benchmark(c: &mut Criterion) {
-    c.bench_function("HKDF extract & expand", |b| b.iter(|| hkdf()));
-    c.bench_function("ECKEM encrypt", |b| b.iter(|| eckem_encrypt()));
+    c.bench_function("HKDF extract & expand", |b| b.iter(hkdf));
+    c.bench_function("ECKEM encrypt", |b| b.iter(eckem_encrypt));
     c.bench_function("ECKEM decrypt", |b| {
         b.iter_with_setup(
             || {
-                let kp = X25519KeyPair::new_random();
-                let encrypted = X25519AES::encrypt(&kp.public_key, DATA).unwrap();
+                let kp = X25519KeyPair::new_random(&mut OsRng);
+                let encrypted = X25519AES::encrypt(&kp.public_key, DATA, &mut OsRng).unwrap();
                 (kp.private_key, encrypted)
             },
             |(public_key, encrypted)| eckem_decrypt(&public_key, &encrypted),
         )
     });
-    c.bench_function("AES128GCM encrypt", |b| b.iter(|| aes128_seal()));
+    c.bench_function("AES128GCM encrypt", |b| b.iter(aes128_seal));
     c.bench_function("AES128GCM decrypt", |b| {
         b.iter_with_setup(
             || {
-                let key: Aes128Key = Aes128Key::from(randombytes::randombytes(AES128KEYBYTES));
-                let nonce = Nonce::new_random();
-                let ciphertext = aes_128_seal(DATA, &key, &nonce).unwrap();
+                let key: Aes128Key = Aes128Key::from(OsRng.random_bytes(AES128KEYBYTES));
+                let nonce = Nonce::new_random(&mut OsRng);
+                let ciphertext = aes_128_seal(DATA, &[], &key, &nonce).unwrap();
                 (ciphertext, key, nonce)
             },
             |(ciphertext, key, nonce)| aes128_open(&ciphertext, &key, &nonce),
@@ -161,15 +184,12 @@ fn criterion_benchmark(c: &mut Criterion) {
     });
     c.bench_function("UserInitKey create bundle", |b| {
         b.iter_with_setup(
-            || {
-                let identity = Identity::random();
-                identity
-            },
+            || Identity::random(&mut OsRng),
             |identity| create_uik_bundle(&identity),
         )
     });
-    c.bench_function("Create group: Alice & Bob", |b| b.iter(|| create_group()));
-    c.bench_function("Create large group", |b| b.iter(|| large_group()));
+    c.bench_function("Create group: Alice & Bob", |b| b.iter(create_group));
+    c.bench_function("Create large group", |b| b.iter(large_group));
 }
 
 criterion_group!(benches, criterion_benchmark);
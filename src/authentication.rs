@@ -0,0 +1,117 @@
+// Wire
+// Copyright (C) 2019 Wire Swiss GmbH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see http://www.gnu.org/licenses/.
+
+use keys::*;
+
+// Decides whether a credential may enter the group, e.g. by looking the
+// identity up in a directory. X.509 chains are validated against the trust
+// store of the group before the service is asked.
+pub trait AuthenticationService: Send + Sync {
+    // The identity is the one of a basic credential, or the one mapped from
+    // the subject of an X.509 credential
+    fn authenticate(&self, identity: &[u8], credential: &Credential) -> bool;
+    fn box_clone(&self) -> Box<dyn AuthenticationService>;
+}
+
+impl Clone for Box<dyn AuthenticationService> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+// Accepts every credential, groups only use it when told to
+#[derive(Clone, Copy, Default)]
+pub struct AllowAll;
+
+impl AuthenticationService for AllowAll {
+    fn authenticate(&self, _identity: &[u8], _credential: &Credential) -> bool {
+        true
+    }
+    fn box_clone(&self) -> Box<dyn AuthenticationService> {
+        Box::new(*self)
+    }
+}
+
+// Rejects every credential, the service of decoded groups until the
+// application sets theirs again
+#[derive(Clone, Copy, Default)]
+pub struct DenyAll;
+
+impl AuthenticationService for DenyAll {
+    fn authenticate(&self, _identity: &[u8], _credential: &Credential) -> bool {
+        false
+    }
+    fn box_clone(&self) -> Box<dyn AuthenticationService> {
+        Box::new(*self)
+    }
+}
+
+// Accepts the listed identities, optionally only with a given key
+#[derive(Clone, Default)]
+pub struct AllowList {
+    entries: Vec<(Vec<u8>, Option<SignaturePublicKey>)>,
+}
+
+impl AllowList {
+    pub fn new() -> Self {
+        AllowList::default()
+    }
+    pub fn allow(&mut self, identity: &[u8]) {
+        self.entries.push((identity.to_vec(), None));
+    }
+    pub fn allow_key(&mut self, identity: &[u8], public_key: SignaturePublicKey) {
+        self.entries.push((identity.to_vec(), Some(public_key)));
+    }
+}
+
+impl AuthenticationService for AllowList {
+    fn authenticate(&self, identity: &[u8], credential: &Credential) -> bool {
+        self.entries.iter().any(|&(ref allowed, public_key)| {
+            allowed[..] == identity[..]
+                && (public_key.is_none() || public_key == Some(credential.public_key()))
+        })
+    }
+    fn box_clone(&self) -> Box<dyn AuthenticationService> {
+        Box::new(self.clone())
+    }
+}
+
+#[test]
+fn test_allow_list() {
+    use utils::*;
+
+    let mut rng = test_rng("test_allow_list");
+    let alice = Identity::random(&mut rng);
+    let bob = Identity::random(&mut rng);
+    let credential = |identity: &str, public_key| {
+        Credential::Basic(BasicCredential {
+            identity: identity.as_bytes().to_vec(),
+            public_key,
+        })
+    };
+
+    let mut allow_list = AllowList::new();
+    allow_list.allow(b"Alice");
+    allow_list.allow_key(b"Bob", bob.public_key);
+    let service = allow_list.box_clone();
+    assert!(service.authenticate(b"Alice", &credential("Alice", alice.public_key)));
+    assert!(service.authenticate(b"Alice", &credential("Alice", bob.public_key)));
+    assert!(service.authenticate(b"Bob", &credential("Bob", bob.public_key)));
+    assert!(!service.authenticate(b"Bob", &credential("Bob", alice.public_key)));
+    assert!(!service.authenticate(b"Charlie", &credential("Charlie", alice.public_key)));
+    assert!(AllowAll.authenticate(b"Charlie", &credential("Charlie", alice.public_key)));
+    assert!(!DenyAll.authenticate(b"Alice", &credential("Alice", alice.public_key)));
+}
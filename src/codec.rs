@@ -27,7 +27,7 @@ pub struct Cursor {
     offset: usize,
 }
 
-impl<'a> Cursor {
+impl Cursor {
     pub fn new(bytes: &[u8]) -> Cursor {
         Cursor {
             buffer: bytes.to_vec(),
@@ -46,7 +46,7 @@ impl<'a> Cursor {
     }
 
     pub fn sub_cursor(&mut self, length: usize) -> Result<Cursor, CodecError> {
-        self.take(length).and_then(|buffer| Ok(Cursor::new(buffer)))
+        self.take(length).map(Cursor::new)
     }
 
     pub fn sub_cursor_u8(&mut self) -> Result<Cursor, CodecError> {
@@ -90,7 +90,7 @@ impl<'a> Cursor {
 pub trait Codec: Sized {
     fn encode(&self, buffer: &mut Vec<u8>);
 
-    fn decode(&mut Cursor) -> Result<Self, CodecError>;
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError>;
 
     fn encode_detached(&self) -> Vec<u8> {
         let mut buffer = vec![];
@@ -106,7 +106,7 @@ pub trait Codec: Sized {
 
 impl Codec for u8 {
     fn encode(&self, buffer: &mut Vec<u8>) {
-        buffer.push(*self as u8);
+        buffer.push(*self);
     }
 
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
@@ -207,7 +207,7 @@ pub fn encode_vec_u8<T: Codec>(bytes: &mut Vec<u8>, slice: &[T]) {
     let mut sub_cursor: Vec<u8> = Vec::new();
     slice.iter().for_each(|e| e.encode(&mut sub_cursor));
 
-    assert!(sub_cursor.len() <= u8::max_value() as usize);
+    assert!(sub_cursor.len() <= u8::MAX as usize);
     (sub_cursor.len() as u8).encode(bytes);
     bytes.append(&mut sub_cursor);
 }
@@ -216,7 +216,7 @@ pub fn encode_vec_u16<T: Codec>(bytes: &mut Vec<u8>, slice: &[T]) {
     let mut sub_cursor: Vec<u8> = Vec::new();
     slice.iter().for_each(|e| e.encode(&mut sub_cursor));
 
-    assert!(sub_cursor.len() <= u16::max_value() as usize);
+    assert!(sub_cursor.len() <= u16::MAX as usize);
     (sub_cursor.len() as u16).encode(bytes);
     bytes.append(&mut sub_cursor);
 }
@@ -225,7 +225,7 @@ pub fn encode_vec_u32<T: Codec>(bytes: &mut Vec<u8>, slice: &[T]) {
     let mut sub_cursor: Vec<u8> = Vec::new();
     slice.iter().for_each(|e| e.encode(&mut sub_cursor));
 
-    assert!(sub_cursor.len() <= u32::max_value() as usize);
+    assert!(sub_cursor.len() <= u32::MAX as usize);
     (sub_cursor.len() as u32).encode(bytes);
     bytes.append(&mut sub_cursor);
 }
@@ -234,7 +234,7 @@ pub fn encode_vec_u64<T: Codec>(bytes: &mut Vec<u8>, slice: &[T]) {
     let mut sub_cursor: Vec<u8> = Vec::new();
     slice.iter().for_each(|e| e.encode(&mut sub_cursor));
 
-    assert!(sub_cursor.len() <= u64::max_value() as usize);
+    assert!(sub_cursor.len() <= u64::MAX as usize);
     (sub_cursor.len() as u64).encode(bytes);
     bytes.append(&mut sub_cursor);
 }
@@ -287,6 +287,60 @@ pub fn decode_vec_u64<T: Codec>(r: &mut Cursor) -> Result<Vec<T>, CodecError> {
     Ok(ret)
 }
 
+// Variable-size length prefix of RFC 9000, limited to 30 bits as in RFC 9420
+pub const VARINT_MAX: usize = (1 << 30) - 1;
+
+pub fn encode_varint(bytes: &mut Vec<u8>, value: usize) {
+    assert!(value <= VARINT_MAX);
+    if value < 1 << 6 {
+        (value as u8).encode(bytes);
+    } else if value < 1 << 14 {
+        (0x4000 | value as u16).encode(bytes);
+    } else {
+        (0x8000_0000 | value as u32).encode(bytes);
+    }
+}
+
+pub fn decode_varint(r: &mut Cursor) -> Result<usize, CodecError> {
+    let first = r.take(1)?[0];
+    let (length, mut value) = match first >> 6 {
+        0 => (1, 0),
+        1 => (2, 1 << 6),
+        2 => (4, 1 << 14),
+        _ => return Err(CodecError::DecodingError),
+    };
+    let min = value;
+    value = usize::from(first & 0x3F);
+    for &b in r.take(length - 1)? {
+        value = (value << 8) | usize::from(b);
+    }
+    // Only the shortest encoding is valid
+    if value < min {
+        return Err(CodecError::DecodingError);
+    }
+    Ok(value)
+}
+
+pub fn encode_vec_varint<T: Codec>(bytes: &mut Vec<u8>, slice: &[T]) {
+    let mut sub_cursor: Vec<u8> = Vec::new();
+    slice.iter().for_each(|e| e.encode(&mut sub_cursor));
+
+    encode_varint(bytes, sub_cursor.len());
+    bytes.append(&mut sub_cursor);
+}
+
+pub fn decode_vec_varint<T: Codec>(r: &mut Cursor) -> Result<Vec<T>, CodecError> {
+    let mut ret: Vec<T> = Vec::new();
+    let len = decode_varint(r)?;
+    let mut sub = r.sub_cursor(len)?;
+
+    while sub.has_more() {
+        ret.push(T::decode(&mut sub)?);
+    }
+
+    Ok(ret)
+}
+
 #[test]
 fn test_primitives() {
     let uint8: u8 = 1;
@@ -350,3 +404,33 @@ fn test_encode_vec_u64() {
         ]
     );
 }
+
+#[test]
+fn test_varint() {
+    for &(value, ref encoded) in [
+        (37usize, vec![0x25u8]),
+        (15_293, vec![0x7B, 0xBD]),
+        (494_878_333, vec![0x9D, 0x7F, 0x3E, 0x7D]),
+    ]
+    .iter()
+    {
+        let mut buffer = Vec::new();
+        encode_varint(&mut buffer, value);
+        assert_eq!(&buffer, encoded);
+        assert_eq!(decode_varint(&mut Cursor::new(&buffer)).unwrap(), value);
+    }
+
+    // Non-minimal and 8-byte encodings are rejected
+    assert!(decode_varint(&mut Cursor::new(&[0x40, 0x25])).is_err());
+    assert!(decode_varint(&mut Cursor::new(&[
+        0xC2, 0x19, 0x7C, 0x5E, 0xFF, 0x14, 0xE8, 0x8C
+    ]))
+    .is_err());
+
+    let v: Vec<u8> = vec![1, 2, 3];
+    let mut buffer = Vec::new();
+    encode_vec_varint(&mut buffer, &v);
+    assert_eq!(buffer, vec![3u8, 1u8, 2u8, 3u8]);
+    let decoded: Vec<u8> = decode_vec_varint(&mut Cursor::new(&buffer)).unwrap();
+    assert_eq!(decoded, v);
+}
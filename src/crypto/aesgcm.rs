@@ -14,12 +14,8 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see http://www.gnu.org/licenses/.
 
-use libsodium_sys::{
-    crypto_aead_aes256gcm_decrypt_detached, crypto_aead_aes256gcm_encrypt_detached,
-};
-use ring::aead as ring_aead;
-use sodiumoxide::randombytes;
-use std::*;
+use crypto::provider::*;
+use crypto::rng::CryptoRng;
 use utils::*;
 
 pub enum ALGORITHM {
@@ -27,7 +23,7 @@ pub enum ALGORITHM {
     AES256GCM,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum AesError {
     EncryptionError,
     DecryptionError,
@@ -38,14 +34,13 @@ pub const AES128KEYBYTES: usize = 16;
 pub const AES256KEYBYTES: usize = 32;
 pub const TAGBYTES: usize = 16;
 
-#[derive(PartialEq, Debug)]
+#[derive(Clone, PartialEq, Debug)]
 pub struct Nonce(pub [u8; NONCEBYTES]);
 
 impl Nonce {
-    pub fn new_random() -> Nonce {
-        let random_bytes = randombytes::randombytes(NONCEBYTES);
+    pub fn new_random(rng: &mut dyn CryptoRng) -> Nonce {
         let mut bytes: [u8; NONCEBYTES] = [0u8; NONCEBYTES];
-        bytes[..NONCEBYTES].clone_from_slice(&random_bytes[..NONCEBYTES]);
+        rng.fill_bytes(&mut bytes);
         Nonce(bytes)
     }
 
@@ -57,7 +52,7 @@ impl Nonce {
     }
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(Debug)]
 pub struct Aes128Key(pub [u8; AES128KEYBYTES]);
 
 impl Aes128Key {
@@ -75,9 +70,21 @@ impl From<Vec<u8>> for Aes128Key {
     }
 }
 
+impl ConstantTimeEq for Aes128Key {
+    fn ct_eq(&self, other: &Self) -> bool {
+        constant_time_eq(&self.0, &other.0)
+    }
+}
+
+impl Zeroize for Aes128Key {
+    fn zeroize(&mut self) {
+        erase(&mut self.0)
+    }
+}
+
 impl Drop for Aes128Key {
     fn drop(&mut self) {
-        erase(&mut self.0)
+        self.zeroize()
     }
 }
 
@@ -98,124 +105,119 @@ impl From<Vec<u8>> for Aes256Key {
     }
 }
 
-impl Drop for Aes256Key {
-    fn drop(&mut self) {
-        erase(&mut self.0)
+impl ConstantTimeEq for Aes256Key {
+    fn ct_eq(&self, other: &Self) -> bool {
+        constant_time_eq(&self.0, &other.0)
     }
 }
 
-pub fn aes_128_seal(payload: &[u8], key: &Aes128Key, nonce: &Nonce) -> Result<Vec<u8>, AesError> {
-    let sealing_key = ring_aead::SealingKey::new(&ring_aead::AES_128_GCM, &key.0).unwrap();
-    let mut buffer: Vec<u8> = Vec::with_capacity(payload.len() + ring_aead::MAX_TAG_LEN);
-    for byte in payload {
-        buffer.push(*byte);
-    }
-    for _ in 0..ring_aead::MAX_TAG_LEN {
-        buffer.push(0);
+impl Zeroize for Aes256Key {
+    fn zeroize(&mut self) {
+        erase(&mut self.0)
     }
-    match ring_aead::seal_in_place(
-        &sealing_key,
-        &nonce.0,
-        &[],
-        &mut buffer,
-        ring_aead::MAX_TAG_LEN,
-    ) {
-        Ok(size) => Ok(buffer[..size].to_vec()),
-        Err(_) => Err(AesError::EncryptionError),
+}
+
+impl Drop for Aes256Key {
+    fn drop(&mut self) {
+        self.zeroize()
     }
 }
 
+pub fn aes_128_seal(
+    payload: &[u8],
+    aad: &[u8],
+    key: &Aes128Key,
+    nonce: &Nonce,
+) -> Result<Vec<u8>, AesError> {
+    provider().aead_seal(AeadAlgorithm::Aes128Gcm, &key.0, &nonce.0, aad, payload)
+}
+
 pub fn aes_128_open(
     sealed_box: &[u8],
+    aad: &[u8],
     key: &Aes128Key,
     nonce: &Nonce,
 ) -> Result<Vec<u8>, AesError> {
-    let opening_key = ring_aead::OpeningKey::new(&ring_aead::AES_128_GCM, &key.0).unwrap();
-    let mut buffer: Vec<u8> = Vec::with_capacity(sealed_box.len());
-    for byte in sealed_box {
-        buffer.push(*byte);
-    }
-    match ring_aead::open_in_place(&opening_key, &nonce.0, &[], 0, &mut buffer) {
-        Ok(bytes) => Ok(bytes.to_vec()),
-        Err(_) => Err(AesError::DecryptionError),
-    }
+    provider().aead_open(AeadAlgorithm::Aes128Gcm, &key.0, &nonce.0, aad, sealed_box)
+}
+
+// Unlike aes_256_seal, the nonce is supplied by the caller and not
+// prepended to the sealed box
+pub fn aes_256_seal_with_nonce(
+    payload: &[u8],
+    aad: &[u8],
+    key: &Aes256Key,
+    nonce: &Nonce,
+) -> Result<Vec<u8>, AesError> {
+    provider().aead_seal(AeadAlgorithm::Aes256Gcm, &key.0, &nonce.0, aad, payload)
+}
+
+pub fn aes_256_open_with_nonce(
+    sealed_box: &[u8],
+    aad: &[u8],
+    key: &Aes256Key,
+    nonce: &Nonce,
+) -> Result<Vec<u8>, AesError> {
+    provider().aead_open(AeadAlgorithm::Aes256Gcm, &key.0, &nonce.0, aad, sealed_box)
 }
 
-pub fn aes_256_seal(payload: &[u8], key: &Aes256Key) -> Result<Vec<u8>, AesError> {
-    let nonce = Nonce::new_random();
-    let mut ciphertext: Vec<u8> = vec![0; payload.len()];
-    let mut tag: Vec<u8> = vec![0; TAGBYTES];
-    let mut maclen: u64 = 0;
+// The sealed box is the random nonce followed by the ciphertext and the tag
+pub fn aes_256_seal(
+    payload: &[u8],
+    aad: &[u8],
+    key: &Aes256Key,
+    rng: &mut dyn CryptoRng,
+) -> Result<Vec<u8>, AesError> {
+    let nonce = Nonce::new_random(rng);
     let mut sealed_box = Vec::with_capacity(NONCEBYTES + TAGBYTES + payload.len());
-    unsafe {
-        crypto_aead_aes256gcm_encrypt_detached(
-            ciphertext.as_mut_ptr(),
-            tag.as_mut_ptr(),
-            &mut maclen,
-            payload.as_ptr(),
-            payload.len() as u64,
-            ptr::null_mut(),
-            0,
-            ptr::null_mut(),
-            nonce.0.as_ptr(),
-            key.0.as_ptr(),
-        );
-    }
-    if maclen != TAGBYTES as u64 {
-        return Err(AesError::EncryptionError);
-    }
     sealed_box.extend_from_slice(&nonce.0);
-    sealed_box.append(&mut ciphertext);
-    sealed_box.append(&mut tag);
-    if sealed_box.len() != (NONCEBYTES + TAGBYTES + payload.len()) {
-        return Err(AesError::EncryptionError);
-    }
+    sealed_box.append(&mut aes_256_seal_with_nonce(payload, aad, key, &nonce)?);
     Ok(sealed_box)
 }
 
-pub fn aes_256_open(sealed_box: &[u8], key: &Aes256Key) -> Result<Vec<u8>, AesError> {
-    let sb_len = sealed_box.len();
-    let payload_len = sb_len - NONCEBYTES - TAGBYTES;
-    if sb_len <= (NONCEBYTES + TAGBYTES) {
+pub fn aes_256_open(sealed_box: &[u8], aad: &[u8], key: &Aes256Key) -> Result<Vec<u8>, AesError> {
+    if sealed_box.len() < NONCEBYTES + TAGBYTES {
         return Err(AesError::DecryptionError);
     }
-    let (nonce, attached) = sealed_box.split_at(NONCEBYTES);
-    let (ciphertext, tag) = attached.split_at(payload_len);
-    let mut payload = vec![0; payload_len];
-
-    unsafe {
-        let r = crypto_aead_aes256gcm_decrypt_detached(
-            payload.as_mut_ptr(),
-            ptr::null_mut(),
-            ciphertext[..].as_ptr(),
-            ciphertext.len() as u64,
-            tag[..].as_ptr(),
-            ptr::null_mut(),
-            0,
-            nonce.as_ptr(),
-            key.0.as_ptr(),
-        );
-        if r != 0 {
-            return Err(AesError::DecryptionError);
-        }
-    }
-    Ok(payload)
+    let (nonce, ciphertext) = sealed_box.split_at(NONCEBYTES);
+    aes_256_open_with_nonce(ciphertext, aad, key, &Nonce::from_slice(nonce))
 }
 
 #[test]
 fn seal_open() {
+    let mut rng = test_rng("seal_open");
     let payload = vec![1, 2, 3];
 
     // AES128
-    let key: Aes128Key = Aes128Key::from(randombytes::randombytes(AES128KEYBYTES));
-    let nonce = Nonce::new_random();
-    let encrypted = aes_128_seal(&payload, &key, &nonce).unwrap();
-    let decrypted = aes_128_open(&encrypted, &key, &nonce).unwrap();
+    let key: Aes128Key = Aes128Key::from(rng.random_bytes(AES128KEYBYTES));
+    let nonce = Nonce::new_random(&mut rng);
+    let encrypted = aes_128_seal(&payload, &[], &key, &nonce).unwrap();
+    let decrypted = aes_128_open(&encrypted, &[], &key, &nonce).unwrap();
     assert_eq!(decrypted, payload);
 
+    // AES128 with associated data
+    let encrypted = aes_128_seal(&payload, b"aad", &key, &nonce).unwrap();
+    let decrypted = aes_128_open(&encrypted, b"aad", &key, &nonce).unwrap();
+    assert_eq!(decrypted, payload);
+    assert!(aes_128_open(&encrypted, b"other", &key, &nonce).is_err());
+    assert!(aes_128_open(&encrypted, &[], &key, &nonce).is_err());
+
     // AES256
-    let key: Aes256Key = Aes256Key::from(randombytes::randombytes(AES256KEYBYTES));
-    let encrypted = aes_256_seal(&payload, &key).unwrap();
-    let decrypted = aes_256_open(&encrypted, &key).unwrap();
+    let key: Aes256Key = Aes256Key::from(rng.random_bytes(AES256KEYBYTES));
+    let encrypted = aes_256_seal(&payload, &[], &key, &mut rng).unwrap();
+    let decrypted = aes_256_open(&encrypted, &[], &key).unwrap();
+    assert_eq!(decrypted, payload);
+
+    // AES256 with associated data
+    let encrypted = aes_256_seal(&payload, b"aad", &key, &mut rng).unwrap();
+    let decrypted = aes_256_open(&encrypted, b"aad", &key).unwrap();
+    assert_eq!(decrypted, payload);
+    assert!(aes_256_open(&encrypted, b"other", &key).is_err());
+    assert!(aes_256_open(&encrypted[..NONCEBYTES], &[], &key).is_err());
+
+    // AES256 with a given nonce and associated data
+    let encrypted = aes_256_seal_with_nonce(&payload, b"aad", &key, &nonce).unwrap();
+    let decrypted = aes_256_open_with_nonce(&encrypted, b"aad", &key, &nonce).unwrap();
     assert_eq!(decrypted, payload);
+    assert!(aes_256_open_with_nonce(&encrypted, b"other", &key, &nonce).is_err());
 }
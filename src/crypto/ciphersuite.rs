@@ -0,0 +1,449 @@
+// Wire
+// Copyright (C) 2019 Wire Swiss GmbH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see http://www.gnu.org/licenses/.
+
+use codec::*;
+use crypto::aesgcm;
+use crypto::aesgcm::AesError;
+use crypto::eckem::*;
+use crypto::hkdf;
+use crypto::hkdf::HashAlgorithm;
+use crypto::hpke::*;
+use crypto::provider::*;
+use crypto::rng::CryptoRng;
+use keys::*;
+use std::fmt;
+
+pub trait Kdf: Sync {
+    fn id(&self) -> u16;
+    fn hash_length(&self) -> usize;
+    fn hash(&self, data: &[u8]) -> Vec<u8>;
+    fn extract(&self, salt: &[u8], ikm: &[u8]) -> Vec<u8>;
+    fn expand(&self, prk: &[u8], info: &[u8], length: usize) -> Vec<u8>;
+}
+
+pub trait Aead: Sync {
+    fn id(&self) -> u16;
+    fn key_length(&self) -> usize;
+    fn nonce_length(&self) -> usize;
+    fn seal(
+        &self,
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        payload: &[u8],
+    ) -> Result<Vec<u8>, AesError>;
+    fn open(
+        &self,
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        sealed_box: &[u8],
+    ) -> Result<Vec<u8>, AesError>;
+}
+
+pub trait Kem: Sync {
+    fn derive_key_pair(&self, secret: &[u8]) -> (KemPrivateKey, KemPublicKey);
+    fn generate_key_pair(&self, rng: &mut dyn CryptoRng) -> (KemPrivateKey, KemPublicKey);
+    fn is_valid_public_key(&self, public_key: &KemPublicKey) -> bool;
+    fn encrypt(
+        &self,
+        public_key: &KemPublicKey,
+        info: &[u8],
+        aad: &[u8],
+        payload: &[u8],
+        rng: &mut dyn CryptoRng,
+    ) -> Result<KemCiphertext, EcKemError>;
+    fn decrypt(
+        &self,
+        private_key: &KemPrivateKey,
+        info: &[u8],
+        aad: &[u8],
+        ciphertext: &KemCiphertext,
+    ) -> Result<Vec<u8>, EcKemError>;
+}
+
+#[derive(Clone, Debug, Hash)]
+pub struct KemCiphertext {
+    pub public_key: KemPublicKey,
+    pub sealed_box: Vec<u8>,
+}
+
+impl Codec for KemCiphertext {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.public_key.encode(buffer);
+        encode_vec_u8(buffer, &self.sealed_box);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let public_key = KemPublicKey::decode(cursor)?;
+        let sealed_box = decode_vec_u8(cursor)?;
+        Ok(KemCiphertext {
+            public_key,
+            sealed_box,
+        })
+    }
+}
+
+pub struct HkdfSha256 {}
+
+impl Kdf for HkdfSha256 {
+    fn id(&self) -> u16 {
+        0x0001
+    }
+    fn hash_length(&self) -> usize {
+        HashAlgorithm::Sha256.output_length()
+    }
+    fn hash(&self, data: &[u8]) -> Vec<u8> {
+        HashAlgorithm::Sha256.hash(data)
+    }
+    fn extract(&self, salt: &[u8], ikm: &[u8]) -> Vec<u8> {
+        hkdf::extract(HashAlgorithm::Sha256, hkdf::Salt(salt), hkdf::Input(ikm))
+            .0
+            .clone()
+    }
+    fn expand(&self, prk: &[u8], info: &[u8], length: usize) -> Vec<u8> {
+        let prk = hkdf::Prk(prk.to_vec());
+        hkdf::expand(HashAlgorithm::Sha256, &prk, hkdf::Info(info), length)
+    }
+}
+
+pub struct HkdfSha512 {}
+
+impl Kdf for HkdfSha512 {
+    fn id(&self) -> u16 {
+        0x0003
+    }
+    fn hash_length(&self) -> usize {
+        HashAlgorithm::Sha512.output_length()
+    }
+    fn hash(&self, data: &[u8]) -> Vec<u8> {
+        HashAlgorithm::Sha512.hash(data)
+    }
+    fn extract(&self, salt: &[u8], ikm: &[u8]) -> Vec<u8> {
+        hkdf::extract(HashAlgorithm::Sha512, hkdf::Salt(salt), hkdf::Input(ikm))
+            .0
+            .clone()
+    }
+    fn expand(&self, prk: &[u8], info: &[u8], length: usize) -> Vec<u8> {
+        let prk = hkdf::Prk(prk.to_vec());
+        hkdf::expand(HashAlgorithm::Sha512, &prk, hkdf::Info(info), length)
+    }
+}
+
+pub struct Aes128Gcm {}
+
+impl Aead for Aes128Gcm {
+    fn id(&self) -> u16 {
+        0x0001
+    }
+    fn key_length(&self) -> usize {
+        aesgcm::AES128KEYBYTES
+    }
+    fn nonce_length(&self) -> usize {
+        aesgcm::NONCEBYTES
+    }
+    fn seal(
+        &self,
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        payload: &[u8],
+    ) -> Result<Vec<u8>, AesError> {
+        if key.len() != self.key_length() || nonce.len() != self.nonce_length() {
+            return Err(AesError::EncryptionError);
+        }
+        let key = aesgcm::Aes128Key::from_slice(key);
+        let nonce = aesgcm::Nonce::from_slice(nonce);
+        aesgcm::aes_128_seal(payload, aad, &key, &nonce)
+    }
+    fn open(
+        &self,
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        sealed_box: &[u8],
+    ) -> Result<Vec<u8>, AesError> {
+        if key.len() != self.key_length() || nonce.len() != self.nonce_length() {
+            return Err(AesError::DecryptionError);
+        }
+        let key = aesgcm::Aes128Key::from_slice(key);
+        let nonce = aesgcm::Nonce::from_slice(nonce);
+        aesgcm::aes_128_open(sealed_box, aad, &key, &nonce)
+    }
+}
+
+pub struct Aes256Gcm {}
+
+impl Aead for Aes256Gcm {
+    fn id(&self) -> u16 {
+        0x0002
+    }
+    fn key_length(&self) -> usize {
+        aesgcm::AES256KEYBYTES
+    }
+    fn nonce_length(&self) -> usize {
+        aesgcm::NONCEBYTES
+    }
+    fn seal(
+        &self,
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        payload: &[u8],
+    ) -> Result<Vec<u8>, AesError> {
+        if key.len() != self.key_length() || nonce.len() != self.nonce_length() {
+            return Err(AesError::EncryptionError);
+        }
+        let key = aesgcm::Aes256Key::from_slice(key);
+        let nonce = aesgcm::Nonce::from_slice(nonce);
+        aesgcm::aes_256_seal_with_nonce(payload, aad, &key, &nonce)
+    }
+    fn open(
+        &self,
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        sealed_box: &[u8],
+    ) -> Result<Vec<u8>, AesError> {
+        if key.len() != self.key_length() || nonce.len() != self.nonce_length() {
+            return Err(AesError::DecryptionError);
+        }
+        let key = aesgcm::Aes256Key::from_slice(key);
+        let nonce = aesgcm::Nonce::from_slice(nonce);
+        aesgcm::aes_256_open_with_nonce(sealed_box, aad, &key, &nonce)
+    }
+}
+
+pub struct ChaCha20Poly1305 {}
+
+impl Aead for ChaCha20Poly1305 {
+    fn id(&self) -> u16 {
+        0x0003
+    }
+    fn key_length(&self) -> usize {
+        AeadAlgorithm::ChaCha20Poly1305.key_length()
+    }
+    fn nonce_length(&self) -> usize {
+        AEAD_NONCE_BYTES
+    }
+    fn seal(
+        &self,
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        payload: &[u8],
+    ) -> Result<Vec<u8>, AesError> {
+        provider().aead_seal(AeadAlgorithm::ChaCha20Poly1305, key, nonce, aad, payload)
+    }
+    fn open(
+        &self,
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        sealed_box: &[u8],
+    ) -> Result<Vec<u8>, AesError> {
+        provider().aead_open(AeadAlgorithm::ChaCha20Poly1305, key, nonce, aad, sealed_box)
+    }
+}
+
+// Bundles the algorithms of a cipher suite. Groups, trees and ratchets keep
+// a reference to the provider of their suite and dispatch on it.
+pub struct CipherSuiteProvider {
+    pub id: CipherSuite,
+    pub kem: &'static dyn Kem,
+    pub aead: &'static dyn Aead,
+    pub kdf: &'static dyn Kdf,
+    pub signature_scheme: SignatureScheme,
+}
+
+pub static SUITE_CURVE25519_AES128GCM_SHA256: CipherSuiteProvider = CipherSuiteProvider {
+    id: AES128GCM_CURVE25519_SHA256,
+    kem: &Hpke {
+        kem: DhKem::X25519HkdfSha256,
+        kdf: &HkdfSha256 {},
+        aead: &Aes128Gcm {},
+    },
+    aead: &Aes128Gcm {},
+    kdf: &HkdfSha256 {},
+    signature_scheme: ED25519,
+};
+
+pub static SUITE_P256_AES128GCM_SHA256: CipherSuiteProvider = CipherSuiteProvider {
+    id: AES128GCM_P256_SHA256,
+    kem: &Hpke {
+        kem: DhKem::P256HkdfSha256,
+        kdf: &HkdfSha256 {},
+        aead: &Aes128Gcm {},
+    },
+    aead: &Aes128Gcm {},
+    kdf: &HkdfSha256 {},
+    signature_scheme: ECDSA_SECP256R1_SHA256,
+};
+
+pub static SUITE_CURVE25519_CHACHA20POLY1305_SHA256: CipherSuiteProvider = CipherSuiteProvider {
+    id: CHACHA20POLY1305_CURVE25519_SHA256,
+    kem: &Hpke {
+        kem: DhKem::X25519HkdfSha256,
+        kdf: &HkdfSha256 {},
+        aead: &ChaCha20Poly1305 {},
+    },
+    aead: &ChaCha20Poly1305 {},
+    kdf: &HkdfSha256 {},
+    signature_scheme: ED25519,
+};
+
+pub static SUITE_CURVE25519_AES256GCM_SHA512: CipherSuiteProvider = CipherSuiteProvider {
+    id: AES256GCM_CURVE25519_SHA512,
+    kem: &Hpke {
+        kem: DhKem::X25519HkdfSha256,
+        kdf: &HkdfSha512 {},
+        aead: &Aes256Gcm {},
+    },
+    aead: &Aes256Gcm {},
+    kdf: &HkdfSha512 {},
+    signature_scheme: ED25519,
+};
+
+impl CipherSuiteProvider {
+    pub fn from_id(id: CipherSuite) -> Option<&'static CipherSuiteProvider> {
+        match id {
+            AES128GCM_P256_SHA256 => Some(&SUITE_P256_AES128GCM_SHA256),
+            AES128GCM_CURVE25519_SHA256 => Some(&SUITE_CURVE25519_AES128GCM_SHA256),
+            CHACHA20POLY1305_CURVE25519_SHA256 => Some(&SUITE_CURVE25519_CHACHA20POLY1305_SHA256),
+            AES256GCM_CURVE25519_SHA512 => Some(&SUITE_CURVE25519_AES256GCM_SHA512),
+            _ => None,
+        }
+    }
+}
+
+impl PartialEq for CipherSuiteProvider {
+    fn eq(&self, other: &CipherSuiteProvider) -> bool {
+        self.id == other.id
+    }
+}
+
+impl fmt::Debug for CipherSuiteProvider {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CipherSuiteProvider({:#06x})", self.id)
+    }
+}
+
+impl Codec for &'static CipherSuiteProvider {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.id.encode(buffer);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let id = CipherSuite::decode(cursor)?;
+        CipherSuiteProvider::from_id(id).ok_or(CodecError::DecodingError)
+    }
+}
+
+#[test]
+fn test_cipher_suite_lookup() {
+    let cipher_suite = CipherSuiteProvider::from_id(AES128GCM_CURVE25519_SHA256).unwrap();
+    assert_eq!(cipher_suite, &SUITE_CURVE25519_AES128GCM_SHA256);
+    assert_eq!(cipher_suite.signature_scheme, ED25519);
+    assert!(CipherSuiteProvider::from_id(0xFFFF).is_none());
+
+    let encoded = cipher_suite.encode_detached();
+    assert_eq!(encoded, vec![0x00, 0x01]);
+    let decoded = <&CipherSuiteProvider>::decode_detached(&encoded).unwrap();
+    assert_eq!(decoded, cipher_suite);
+
+    // Registry ids and private use ids decode alike, the reserved id does not
+    let p256 = <&CipherSuiteProvider>::decode_detached(&[0x00, 0x02]).unwrap();
+    assert_eq!(p256, &SUITE_P256_AES128GCM_SHA256);
+    let chacha = <&CipherSuiteProvider>::decode_detached(&[0x00, 0x03]).unwrap();
+    assert_eq!(chacha, &SUITE_CURVE25519_CHACHA20POLY1305_SHA256);
+    let sha512 = <&CipherSuiteProvider>::decode_detached(&[0xF0, 0x01]).unwrap();
+    assert_eq!(sha512, &SUITE_CURVE25519_AES256GCM_SHA512);
+    assert!(<&CipherSuiteProvider>::decode_detached(&[0x00, 0x00]).is_err());
+}
+
+#[test]
+fn encrypt_decrypt_cipher_suite_kem() {
+    use utils::*;
+
+    let mut rng = test_rng("encrypt_decrypt_cipher_suite_kem");
+    for &id in [
+        AES128GCM_CURVE25519_SHA256,
+        AES128GCM_P256_SHA256,
+        CHACHA20POLY1305_CURVE25519_SHA256,
+        AES256GCM_CURVE25519_SHA512,
+    ]
+    .iter()
+    {
+        let cipher_suite = CipherSuiteProvider::from_id(id).unwrap();
+        let (private_key, public_key) = cipher_suite.kem.generate_key_pair(&mut rng);
+        let cleartext = vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
+
+        let encrypted = cipher_suite
+            .kem
+            .encrypt(&public_key, b"info", b"aad", &cleartext, &mut rng)
+            .unwrap();
+        let encrypted = KemCiphertext::decode_detached(&encrypted.encode_detached()).unwrap();
+        let decrypted = cipher_suite
+            .kem
+            .decrypt(&private_key, b"info", b"aad", &encrypted)
+            .unwrap();
+        assert_eq!(cleartext, decrypted);
+
+        // The context has to match
+        assert!(cipher_suite
+            .kem
+            .decrypt(&private_key, b"other", b"aad", &encrypted)
+            .is_err());
+        assert!(cipher_suite
+            .kem
+            .decrypt(&private_key, b"info", b"other", &encrypted)
+            .is_err());
+
+        let (other_private_key, _) = cipher_suite.kem.generate_key_pair(&mut rng);
+        assert!(cipher_suite
+            .kem
+            .decrypt(&other_private_key, b"info", b"aad", &encrypted)
+            .is_err());
+
+        // Key pairs derived from the same secret are identical
+        let secret = [0x5Au8; 32];
+        let (_, public_key_a) = cipher_suite.kem.derive_key_pair(&secret);
+        let (_, public_key_b) = cipher_suite.kem.derive_key_pair(&secret);
+        assert_eq!(public_key_a, public_key_b);
+    }
+}
+
+#[test]
+fn seal_open_cipher_suite_aead() {
+    for &id in [
+        AES128GCM_CURVE25519_SHA256,
+        CHACHA20POLY1305_CURVE25519_SHA256,
+        AES256GCM_CURVE25519_SHA512,
+    ]
+    .iter()
+    {
+        let aead = CipherSuiteProvider::from_id(id).unwrap().aead;
+        let key = vec![0x42u8; aead.key_length()];
+        let nonce = vec![0x24u8; aead.nonce_length()];
+        let payload = vec![1, 2, 3];
+
+        let sealed_box = aead.seal(&key, &nonce, b"aad", &payload).unwrap();
+        assert_eq!(
+            aead.open(&key, &nonce, b"aad", &sealed_box).unwrap(),
+            payload
+        );
+        assert!(aead.open(&key, &nonce, b"", &sealed_box).is_err());
+        assert!(aead.seal(&key[1..], &nonce, b"aad", &payload).is_err());
+    }
+}
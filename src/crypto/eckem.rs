@@ -17,8 +17,9 @@
 use codec::*;
 use crypto::aesgcm;
 use crypto::hkdf;
+use crypto::provider::*;
+use crypto::rng::CryptoRng;
 use keys::*;
-use sodiumoxide::crypto::aead;
 use std::*;
 
 pub type EcKemError = aesgcm::AesError;
@@ -26,8 +27,8 @@ pub type EcKemError = aesgcm::AesError;
 pub struct X25519AES {}
 #[derive(Clone, Debug, Hash)]
 pub struct X25519AESCiphertext {
-    public_key: X25519PublicKey,
-    sealed_box: Vec<u8>,
+    pub public_key: X25519PublicKey,
+    pub sealed_box: Vec<u8>,
 }
 
 impl Codec for X25519AESCiphertext {
@@ -46,15 +47,24 @@ impl Codec for X25519AESCiphertext {
 }
 
 pub fn derive_ecies_secrets(shared_secret: &[u8]) -> (aesgcm::Aes128Key, aesgcm::Nonce) {
-    let mut key_label_str = b"mls10 ecies key".to_vec();
+    let mut key_label_str = b"MLS 1.0 ecies key".to_vec();
     key_label_str.push(0x01);
-    let prk = hkdf::Prk::from_slice(&shared_secret).unwrap();
-    let key_hkdf = hkdf::expand(prk, hkdf::Info(&key_label_str), aesgcm::AES128KEYBYTES);
+    let prk = hkdf::Prk::from_slice(hkdf::HashAlgorithm::Sha256, shared_secret).unwrap();
+    let key_hkdf = hkdf::expand(
+        hkdf::HashAlgorithm::Sha256,
+        &prk,
+        hkdf::Info(&key_label_str),
+        aesgcm::AES128KEYBYTES,
+    );
     let ecies_key: aesgcm::Aes128Key = aesgcm::Aes128Key::from_slice(&key_hkdf);
-    let mut nonce_label_str = b"mls10 ecies nonce".to_vec();
+    let mut nonce_label_str = b"MLS 1.0 ecies nonce".to_vec();
     nonce_label_str.push(0x01);
-    let prk = hkdf::Prk::from_slice(&shared_secret).unwrap();
-    let nonce_hkdf = hkdf::expand(prk, hkdf::Info(&nonce_label_str), aesgcm::NONCEBYTES);
+    let nonce_hkdf = hkdf::expand(
+        hkdf::HashAlgorithm::Sha256,
+        &prk,
+        hkdf::Info(&nonce_label_str),
+        aesgcm::NONCEBYTES,
+    );
     let ecies_nonce: aesgcm::Nonce = aesgcm::Nonce::from_slice(&nonce_hkdf);
     (ecies_key, ecies_nonce)
 }
@@ -63,11 +73,15 @@ impl X25519AES {
     pub fn encrypt(
         public_key: &X25519PublicKey,
         payload: &[u8],
+        rng: &mut dyn CryptoRng,
     ) -> Result<X25519AESCiphertext, EcKemError> {
-        let kp = X25519KeyPair::new_random();
-        let secret = kp.private_key.shared_secret(public_key).unwrap();
+        let kp = X25519KeyPair::new_random(rng);
+        let secret = kp
+            .private_key
+            .shared_secret(public_key)
+            .map_err(|_| aesgcm::AesError::EncryptionError)?;
         let (key, nonce) = derive_ecies_secrets(&secret);
-        let sealed_box = aesgcm::aes_128_seal(payload, &key, &nonce)?;
+        let sealed_box = aesgcm::aes_128_seal(payload, &[], &key, &nonce)?;
         Ok(X25519AESCiphertext {
             public_key: kp.public_key,
             sealed_box,
@@ -77,18 +91,23 @@ impl X25519AES {
         private_key: &X25519PrivateKey,
         ciphertext: &X25519AESCiphertext,
     ) -> Result<Vec<u8>, EcKemError> {
-        let secret = private_key.shared_secret(&ciphertext.public_key).unwrap();
+        let secret = private_key
+            .shared_secret(&ciphertext.public_key)
+            .map_err(|_| aesgcm::AesError::DecryptionError)?;
         let (key, nonce) = derive_ecies_secrets(&secret);
-        aesgcm::aes_128_open(&ciphertext.sealed_box[..], &key, &nonce)
+        aesgcm::aes_128_open(&ciphertext.sealed_box[..], &[], &key, &nonce)
     }
 }
 
 #[test]
 fn encrypt_decrypt_x25519_aes() {
-    let kp = X25519KeyPair::new_random();
+    use utils::*;
+
+    let mut rng = test_rng("encrypt_decrypt_x25519_aes");
+    let kp = X25519KeyPair::new_random(&mut rng);
     let cleartext = vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
 
-    let encrypted = X25519AES::encrypt(&kp.public_key, &cleartext).unwrap();
+    let encrypted = X25519AES::encrypt(&kp.public_key, &cleartext, &mut rng).unwrap();
     let decrypted = X25519AES::decrypt(&kp.private_key, &encrypted).unwrap();
 
     assert_eq!(cleartext, decrypted);
@@ -96,69 +115,191 @@ fn encrypt_decrypt_x25519_aes() {
 
 #[test]
 fn encrypt_decrypt_x25519_aes_random() {
-    use sodiumoxide::randombytes;
+    use utils::*;
+
+    let mut rng = test_rng("encrypt_decrypt_x25519_aes_random");
     for _ in 0..1000 {
-        let kp = X25519KeyPair::new_random();
-        let cleartext = randombytes::randombytes(1000);
+        let kp = X25519KeyPair::new_random(&mut rng);
+        let cleartext = rng.random_bytes(1000);
 
-        let encrypted = X25519AES::encrypt(&kp.public_key, &cleartext).unwrap();
+        let encrypted = X25519AES::encrypt(&kp.public_key, &cleartext, &mut rng).unwrap();
         let decrypted = X25519AES::decrypt(&kp.private_key, &encrypted).unwrap();
 
         assert_eq!(cleartext, decrypted);
     }
 }
 
+pub struct P256AES {}
+#[derive(Clone, Debug, Hash)]
+pub struct P256AESCiphertext {
+    pub public_key: P256PublicKey,
+    pub sealed_box: Vec<u8>,
+}
+
+impl Codec for P256AESCiphertext {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.public_key.encode(buffer);
+        encode_vec_u8(buffer, &self.sealed_box);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let public_key = P256PublicKey::decode(cursor)?;
+        let sealed_box = decode_vec_u8(cursor)?;
+        Ok(P256AESCiphertext {
+            public_key,
+            sealed_box,
+        })
+    }
+}
+
+impl P256AES {
+    pub fn encrypt(
+        public_key: &P256PublicKey,
+        payload: &[u8],
+        rng: &mut dyn CryptoRng,
+    ) -> Result<P256AESCiphertext, EcKemError> {
+        let kp = P256KeyPair::new_random(rng);
+        let secret = kp
+            .private_key
+            .shared_secret(public_key)
+            .map_err(|_| aesgcm::AesError::EncryptionError)?;
+        let (key, nonce) = derive_ecies_secrets(&secret);
+        let sealed_box = aesgcm::aes_128_seal(payload, &[], &key, &nonce)?;
+        Ok(P256AESCiphertext {
+            public_key: kp.public_key,
+            sealed_box,
+        })
+    }
+    pub fn decrypt(
+        private_key: &P256PrivateKey,
+        ciphertext: &P256AESCiphertext,
+    ) -> Result<Vec<u8>, EcKemError> {
+        let secret = private_key
+            .shared_secret(&ciphertext.public_key)
+            .map_err(|_| aesgcm::AesError::DecryptionError)?;
+        let (key, nonce) = derive_ecies_secrets(&secret);
+        aesgcm::aes_128_open(&ciphertext.sealed_box[..], &[], &key, &nonce)
+    }
+}
+
+#[test]
+fn encrypt_decrypt_p256_aes() {
+    use utils::*;
+
+    let mut rng = test_rng("encrypt_decrypt_p256_aes");
+    let kp = P256KeyPair::new_random(&mut rng);
+    let cleartext = vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
+
+    let encrypted = P256AES::encrypt(&kp.public_key, &cleartext, &mut rng).unwrap();
+    let encrypted = P256AESCiphertext::decode_detached(&encrypted.encode_detached()).unwrap();
+    let decrypted = P256AES::decrypt(&kp.private_key, &encrypted).unwrap();
+    assert_eq!(cleartext, decrypted);
+
+    // Points that are not on the curve are rejected
+    let mut invalid = encrypted.clone();
+    invalid.public_key = P256PublicKey::from_slice(&[4u8; P256PUBLICKEYBYTES]);
+    assert!(P256AES::decrypt(&kp.private_key, &invalid).is_err());
+}
+
 pub struct X25519ChaCha20 {}
+#[derive(Clone, Debug)]
 pub struct X25519ChaCha20Ciphertext {
-    public_key: X25519PublicKey,
-    nonce: aead::Nonce,
-    ciphertext: Vec<u8>,
+    pub public_key: X25519PublicKey,
+    pub nonce: aesgcm::Nonce,
+    pub ciphertext: Vec<u8>,
+}
+
+impl Codec for X25519ChaCha20Ciphertext {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.public_key.encode(buffer);
+        encode_vec_u8(buffer, &self.nonce.0);
+        encode_vec_u16(buffer, &self.ciphertext);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let public_key = X25519PublicKey::decode(cursor)?;
+        let nonce_bytes: Vec<u8> = decode_vec_u8(cursor)?;
+        if nonce_bytes.len() != aesgcm::NONCEBYTES {
+            return Err(CodecError::DecodingError);
+        }
+        let nonce = aesgcm::Nonce::from_slice(&nonce_bytes);
+        let ciphertext = decode_vec_u16(cursor)?;
+        Ok(X25519ChaCha20Ciphertext {
+            public_key,
+            nonce,
+            ciphertext,
+        })
+    }
 }
 
 impl X25519ChaCha20 {
-    pub fn encrypt(public_key: &X25519PublicKey, payload: &[u8]) -> X25519ChaCha20Ciphertext {
-        let kp = X25519KeyPair::new_random();
-        let secret = kp.private_key.shared_secret(public_key).unwrap();
-        let key = aead::Key::from_slice(&secret[..]).unwrap();
-        let nonce = aead::gen_nonce();
-        let ciphertext = aead::seal(payload, None, &nonce, &key);
-        X25519ChaCha20Ciphertext {
+    pub fn encrypt(
+        public_key: &X25519PublicKey,
+        payload: &[u8],
+        rng: &mut dyn CryptoRng,
+    ) -> Result<X25519ChaCha20Ciphertext, EcKemError> {
+        let kp = X25519KeyPair::new_random(rng);
+        let secret = kp
+            .private_key
+            .shared_secret(public_key)
+            .map_err(|_| aesgcm::AesError::EncryptionError)?;
+        let nonce = aesgcm::Nonce::new_random(rng);
+        let ciphertext = provider().aead_seal(
+            AeadAlgorithm::ChaCha20Poly1305,
+            &secret,
+            &nonce.0,
+            &[],
+            payload,
+        )?;
+        Ok(X25519ChaCha20Ciphertext {
             public_key: kp.public_key,
             nonce,
             ciphertext,
-        }
+        })
     }
 
     pub fn decrypt(
         private_key: &X25519PrivateKey,
         ciphertext: &X25519ChaCha20Ciphertext,
-    ) -> Vec<u8> {
-        let secret = private_key.shared_secret(&ciphertext.public_key).unwrap();
-        let key = aead::Key::from_slice(&secret[..]).unwrap();
-        aead::open(&ciphertext.ciphertext, None, &ciphertext.nonce, &key).unwrap()
+    ) -> Result<Vec<u8>, EcKemError> {
+        let secret = private_key
+            .shared_secret(&ciphertext.public_key)
+            .map_err(|_| aesgcm::AesError::DecryptionError)?;
+        provider().aead_open(
+            AeadAlgorithm::ChaCha20Poly1305,
+            &secret,
+            &ciphertext.nonce.0,
+            &[],
+            &ciphertext.ciphertext,
+        )
     }
 }
 
 #[test]
 fn encrypt_decrypt_x25519_chacha20() {
-    let kp = X25519KeyPair::new_random();
+    use utils::*;
+
+    let mut rng = test_rng("encrypt_decrypt_x25519_chacha20");
+    let kp = X25519KeyPair::new_random(&mut rng);
     let cleartext = vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
 
-    let encrypted = X25519ChaCha20::encrypt(&kp.public_key, &cleartext);
-    let decrypted = X25519ChaCha20::decrypt(&kp.private_key, &encrypted);
+    let encrypted = X25519ChaCha20::encrypt(&kp.public_key, &cleartext, &mut rng).unwrap();
+    let encrypted =
+        X25519ChaCha20Ciphertext::decode_detached(&encrypted.encode_detached()).unwrap();
+    let decrypted = X25519ChaCha20::decrypt(&kp.private_key, &encrypted).unwrap();
 
     assert_eq!(cleartext, decrypted);
 }
 
 #[test]
 fn encrypt_decrypt_x25519_chacha20_random() {
-    use sodiumoxide::randombytes;
+    use utils::*;
+
+    let mut rng = test_rng("encrypt_decrypt_x25519_chacha20_random");
     for _ in 0..1000 {
-        let kp = X25519KeyPair::new_random();
-        let cleartext = randombytes::randombytes(1000);
+        let kp = X25519KeyPair::new_random(&mut rng);
+        let cleartext = rng.random_bytes(1000);
 
-        let encrypted = X25519ChaCha20::encrypt(&kp.public_key, &cleartext);
-        let decrypted = X25519ChaCha20::decrypt(&kp.private_key, &encrypted);
+        let encrypted = X25519ChaCha20::encrypt(&kp.public_key, &cleartext, &mut rng).unwrap();
+        let decrypted = X25519ChaCha20::decrypt(&kp.private_key, &encrypted).unwrap();
 
         assert_eq!(cleartext, decrypted);
     }
@@ -168,7 +309,7 @@ fn encrypt_decrypt_x25519_chacha20_random() {
 fn generate_ecies_secrets() {
     use utils::*;
 
-    let shared_secret = sodiumoxide::randombytes::randombytes(32);
+    let shared_secret = test_rng("generate_ecies_secrets").random_bytes(32);
 
     let (key, nonce) = derive_ecies_secrets(&shared_secret);
 
@@ -182,19 +323,21 @@ fn test_ecies_secrets() {
     use utils::*;
 
     let shared_secret_hex = "626409A3109BC704CA0B39BBC7F9CB3748904509E5A4564B66B2A10B315BC6D5";
-    let shared_secret = hex_to_bytes(&shared_secret_hex);
+    let shared_secret = hex_to_bytes(shared_secret_hex);
 
-    let key_hex = "2BF6DE51B5C8CD8E45EA63B4B4D997DF";
+    let key_hex = "02B42208E7BE6D3FE61373E9F6F6BD64";
     let mut key_inner = <[u8; 16]>::default();
-    key_inner.copy_from_slice(&hex_to_bytes(&key_hex)[..16]);
+    key_inner.copy_from_slice(&hex_to_bytes(key_hex)[..16]);
     let key = aesgcm::Aes128Key(key_inner);
 
-    let nonce_hex = "E66BE7FD5C91BB999D7903D9";
+    let nonce_hex = "49A8B8726FA8E929C70AA3D3";
     let mut nonce_inner = <[u8; 12]>::default();
-    nonce_inner.copy_from_slice(&hex_to_bytes(&nonce_hex)[..12]);
+    nonce_inner.copy_from_slice(&hex_to_bytes(nonce_hex)[..12]);
     let nonce = aesgcm::Nonce(nonce_inner);
 
-    assert_eq!(derive_ecies_secrets(&shared_secret), (key, nonce));
+    let (derived_key, derived_nonce) = derive_ecies_secrets(&shared_secret);
+    assert_ct_eq(&derived_key, &key);
+    assert_eq!(derived_nonce, nonce);
 }
 
 #[test]
@@ -204,21 +347,22 @@ fn test_eckem() {
     let alice_dh_private_key_hex =
         "5D43BE92D01AAD353B9B4B1DC32E6C828B00DD20B46BDEB98976E13D881DC39A";
     let alice_dh_private_key =
-        X25519PrivateKey::from_slice(&hex_to_bytes(alice_dh_private_key_hex));
+        X25519PrivateKey::from_slice(&hex_to_bytes(alice_dh_private_key_hex)).unwrap();
 
     let alice_dh_public_key_hex =
         "626848EAB66583E12FB94577D2399D32B1EA13D2E3B9EC07C9D54778F9E27910";
     let alice_dh_public_key = X25519PublicKey::from_slice(&hex_to_bytes(alice_dh_public_key_hex));
 
     let bob_dh_private_key_hex = "FF629FC551E4B0657172E992AC543E89E0EB12EB11A8B413F140D88808B0EC40";
-    let _bob_dh_private_key = X25519PrivateKey::from_slice(&hex_to_bytes(bob_dh_private_key_hex));
+    let _bob_dh_private_key =
+        X25519PrivateKey::from_slice(&hex_to_bytes(bob_dh_private_key_hex)).unwrap();
 
     let bob_dh_public_key_hex = "A724AB6198B4D07A3E1E4FD788EF73BF8C0E8120AC7DA4C228948D408943D774";
     let bob_dh_public_key = X25519PublicKey::from_slice(&hex_to_bytes(bob_dh_public_key_hex));
 
     let cleartext = hex_to_bytes("00010203040506070809");
 
-    let ciphertext = hex_to_bytes("8127F756973BF391AE3B0891332864D367787406F306C7DD3175");
+    let ciphertext = hex_to_bytes("75F658CD7E5FAD2B55FA04CCE5E4254AE60EBBB57EDB83B2646E");
 
     let alice_kp = X25519KeyPair {
         private_key: alice_dh_private_key,
@@ -230,7 +374,7 @@ fn test_eckem() {
         .shared_secret(&bob_dh_public_key)
         .unwrap();
     let (key, nonce) = derive_ecies_secrets(&secret);
-    let sealed_box = aesgcm::aes_128_seal(&cleartext, &key, &nonce).unwrap();
+    let sealed_box = aesgcm::aes_128_seal(&cleartext, &[], &key, &nonce).unwrap();
 
     assert_eq!(&sealed_box, &ciphertext);
 }
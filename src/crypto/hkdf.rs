@@ -14,14 +14,31 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see http://www.gnu.org/licenses/.
 
-use sodiumoxide::crypto::auth::hmacsha256; //::{self, authenticate, Tag}
-use sodiumoxide::crypto::auth::hmacsha256::KEYBYTES;
-use sodiumoxide::crypto::hash::sha256::{hash, Digest};
-use std::io::Write;
+use crypto::provider::provider;
 use std::vec::Vec;
 use utils::*;
 
-pub const HASH_LEN: usize = 32;
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HashAlgorithm {
+    Sha256,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    pub fn output_length(self) -> usize {
+        match self {
+            HashAlgorithm::Sha256 => 32,
+            HashAlgorithm::Sha512 => 64,
+        }
+    }
+    pub fn hash(self, data: &[u8]) -> Vec<u8> {
+        provider().hash(self, data)
+    }
+    // Keys of any length are accepted, as in RFC 2104
+    pub fn hmac(self, key: &[u8], data: &[u8]) -> Vec<u8> {
+        provider().hmac(self, key, data)
+    }
+}
 
 pub struct Input<'r>(pub &'r [u8]);
 
@@ -31,66 +48,71 @@ pub struct Info<'r>(pub &'r [u8]);
 
 pub struct Key(pub Vec<u8>);
 
+impl ConstantTimeEq for Key {
+    fn ct_eq(&self, other: &Self) -> bool {
+        constant_time_eq(&self.0, &other.0)
+    }
+}
+
+impl Zeroize for Key {
+    fn zeroize(&mut self) {
+        erase(&mut self.0)
+    }
+}
+
 impl Drop for Key {
     fn drop(&mut self) {
-        erase(&mut self.0)
+        self.zeroize()
     }
 }
 
-#[derive(Clone, Copy)]
-pub struct Prk(pub [u8; 32]);
+pub struct Prk(pub Vec<u8>);
 
 impl Prk {
-    pub fn from_slice(bytes: &[u8]) -> Option<Self> {
-        if bytes.len() == 32 {
-            let mut inner = [0u8; 32];
-            inner.copy_from_slice(&bytes[..32]);
-            Some(Prk(inner))
+    pub fn from_slice(hash: HashAlgorithm, bytes: &[u8]) -> Option<Self> {
+        if bytes.len() == hash.output_length() {
+            Some(Prk(bytes.to_vec()))
         } else {
             None
         }
     }
 }
 
-/// Length of output keying material in octets (`<= 255 * HASH_LEN`).
-#[derive(Copy, Clone, PartialEq, Eq, Debug)]
-pub struct Len(u16);
+impl ConstantTimeEq for Prk {
+    fn ct_eq(&self, other: &Self) -> bool {
+        constant_time_eq(&self.0, &other.0)
+    }
+}
+
+impl Zeroize for Prk {
+    fn zeroize(&mut self) {
+        erase(&mut self.0)
+    }
+}
 
-impl Len {
-    pub fn new(i: u16) -> Self {
-        assert!((i as usize) <= HASH_LEN);
-        Len(i)
+impl Drop for Prk {
+    fn drop(&mut self) {
+        self.zeroize()
     }
 }
 
 /// HMAC-based KDF implementing RFC 5869.
-/// Only SHA-256 is supported as hash function.
-pub fn hkdf(salt: Salt, input: Input, info: Info, Len(len): Len) -> Key {
-    Key(expand(extract(salt, input), info, len as usize))
+/// The output length is at most `255 * hash.output_length()` octets.
+pub fn hkdf(hash: HashAlgorithm, salt: Salt, input: Input, info: Info, len: usize) -> Key {
+    Key(expand(hash, &extract(hash, salt, input), info, len))
 }
 
 // Step1: HKDF-Extract(salt, IKM) -> PRK
-pub fn extract(Salt(s): Salt, Input(i): Input) -> Prk {
-    Prk(hmacsha256::authenticate(i, &hmacsha256::Key(mk_salt(s))).0)
-}
-
-// The salt is used as key for HMAC-Hash. It is either padded to the right
-// with extra zeroes to the input block size of the hash function, or the
-// hash of the original key if it's longer than that block size.
-fn mk_salt(input: &[u8]) -> [u8; KEYBYTES] {
-    if input.len() > KEYBYTES {
-        let Digest(d) = hash(input);
-        d
-    } else {
-        let mut b = [0; KEYBYTES];
-        b.as_mut().write_all(&input).unwrap();
-        b
-    }
+// An empty salt is the same HMAC key as a string of zeroes of hash length.
+pub fn extract(hash: HashAlgorithm, Salt(s): Salt, Input(i): Input) -> Prk {
+    Prk(hash.hmac(s, i))
 }
 
 // Step2: HKDF-Expand(PRK, info, L) -> OKM
-pub fn expand(prk: Prk, Info(info): Info, len: usize) -> Vec<u8> {
-    let n = (len as f32 / HASH_LEN as f32).ceil() as usize;
+pub fn expand(hash: HashAlgorithm, prk: &Prk, Info(info): Info, len: usize) -> Vec<u8> {
+    let hash_len = hash.output_length();
+    let n = len.div_ceil(hash_len);
+    assert!(n <= 255);
     let mut t = Vec::new();
     let mut okm = Vec::new();
 
@@ -100,15 +122,16 @@ pub fn expand(prk: Prk, Info(info): Info, len: usize) -> Vec<u8> {
         buf.extend(info);
         buf.push(i as u8);
 
-        let key = hmacsha256::Key::from_slice(&prk.0).unwrap();
-        let t_i = hmacsha256::authenticate(&buf, &key);
-        okm.extend(&t_i.0);
+        let t_i = hash.hmac(&prk.0, &buf);
+        okm.extend(&t_i);
 
-        t.clear();
-        t.extend(&t_i.0);
+        erase(&mut t);
+        t = t_i;
     }
+    erase(&mut t);
 
-    okm.into_iter().take(len).collect()
+    okm.truncate(len);
+    okm
 }
 
 #[test]
@@ -126,8 +149,8 @@ fn test_case_1() {
         "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf34007208d5b887185865",
     );
 
-    let prk = extract(Salt(&salt), Input(&ikm));
-    let okm = expand(prk, Info(&info), len);
+    let prk = extract(HashAlgorithm::Sha256, Salt(&salt), Input(&ikm));
+    let okm = expand(HashAlgorithm::Sha256, &prk, Info(&info), len);
 
     assert_eq!(&expected_prk, &prk.0);
     assert_eq!(&expected_okm, &okm);
@@ -146,8 +169,8 @@ fn test_case_2() {
         hex_to_bytes("06a6b88c5853361a06104c9ceb35b45cef760014904671014a193f40c15fc244");
     let expected_okm = hex_to_bytes("b11e398dc80327a1c8e7f78c596a49344f012eda2d4efad8a050cc4c19afa97c59045a99cac7827271cb41c65e590e09da3275600c2f09b8367793a9aca3db71cc30c58179ec3e87c14c01d5c1f3434f1d87");
 
-    let prk = extract(Salt(&salt), Input(&ikm));
-    let okm = expand(prk, Info(&info), len);
+    let prk = extract(HashAlgorithm::Sha256, Salt(&salt), Input(&ikm));
+    let okm = expand(HashAlgorithm::Sha256, &prk, Info(&info), len);
 
     assert_eq!(&expected_prk, &prk.0);
     assert_eq!(&expected_okm, &okm);
@@ -168,9 +191,40 @@ fn test_case_3() {
         "8da4e775a563c18f715f802a063c5a31b8a11f5c5ee1879ec3454e5f3c738d2d9d201395faa4b61a96c8",
     );
 
-    let prk = extract(Salt(salt), Input(&ikm));
-    let okm = expand(prk, Info(info), len);
+    let prk = extract(HashAlgorithm::Sha256, Salt(salt), Input(&ikm));
+    let okm = expand(HashAlgorithm::Sha256, &prk, Info(info), len);
 
     assert_eq!(&expected_prk, &prk.0);
     assert_eq!(&expected_okm, &okm);
 }
+
+// RFC 5869 test cases 1 and 3 with SHA-512 as hash function
+#[test]
+fn test_case_sha512() {
+    use utils::*;
+
+    let ikm = hex_to_bytes("0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b");
+    let salt = hex_to_bytes("000102030405060708090a0b0c");
+    let info = hex_to_bytes("f0f1f2f3f4f5f6f7f8f9");
+
+    let prk = extract(HashAlgorithm::Sha512, Salt(&salt), Input(&ikm));
+    let okm = expand(HashAlgorithm::Sha512, &prk, Info(&info), 42);
+    assert_eq!(
+        prk.0,
+        hex_to_bytes("665799823737ded04a88e47e54a5890bb2c3d247c7a4254a8e61350723590a26c36238127d8661b88cf80ef802d57e2f7cebcf1e00e083848be19929c61b4237")
+    );
+    assert_eq!(
+        okm,
+        hex_to_bytes(
+            "832390086cda71fb47625bb5ceb168e4c8e26a1a16ed34d9fc7fe92c1481579338da362cb8d9f925d7cb"
+        )
+    );
+
+    let okm = hkdf(HashAlgorithm::Sha512, Salt(b""), Input(&ikm), Info(b""), 42);
+    assert_eq!(
+        okm.0,
+        hex_to_bytes(
+            "f5fa02b18298a72a8c23898a8703472c6eb179dc204c03425c970e3b164bf90fff22d04836d0e2343bac"
+        )
+    );
+}
@@ -0,0 +1,641 @@
+// Wire
+// Copyright (C) 2018 Wire Swiss GmbH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see http://www.gnu.org/licenses/.
+
+// Hybrid Public Key Encryption (RFC 9180), base mode only
+
+use crypto::aesgcm::AesError;
+use crypto::ciphersuite::*;
+use crypto::eckem::EcKemError;
+use crypto::rng::CryptoRng;
+use keys::*;
+use utils::*;
+
+pub type HpkeMode = u8;
+
+pub const MODE_BASE: HpkeMode = 0x00;
+
+pub const KEM_DHKEM_P256_HKDF_SHA256: u16 = 0x0010;
+pub const KEM_DHKEM_X25519_HKDF_SHA256: u16 = 0x0020;
+
+const VERSION_LABEL: &[u8] = b"HPKE-v1";
+
+#[derive(Debug, PartialEq)]
+pub enum HpkeError {
+    InvalidKey,
+    DeriveKeyPairError,
+    EncryptionError,
+    DecryptionError,
+    MessageLimitReached,
+}
+
+impl From<HpkeError> for EcKemError {
+    fn from(error: HpkeError) -> EcKemError {
+        match error {
+            HpkeError::DecryptionError => AesError::DecryptionError,
+            _ => AesError::EncryptionError,
+        }
+    }
+}
+
+fn i2osp(value: u64, length: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; length];
+    for (i, byte) in bytes.iter_mut().rev().enumerate().take(8) {
+        *byte = (value >> (8 * i)) as u8;
+    }
+    bytes
+}
+
+pub fn labeled_extract(
+    kdf: &dyn Kdf,
+    suite_id: &[u8],
+    salt: &[u8],
+    label: &[u8],
+    ikm: &[u8],
+) -> Vec<u8> {
+    let mut labeled_ikm = VERSION_LABEL.to_vec();
+    labeled_ikm.extend_from_slice(suite_id);
+    labeled_ikm.extend_from_slice(label);
+    labeled_ikm.extend_from_slice(ikm);
+    let prk = kdf.extract(salt, &labeled_ikm);
+    erase(&mut labeled_ikm);
+    prk
+}
+
+pub fn labeled_expand(
+    kdf: &dyn Kdf,
+    suite_id: &[u8],
+    prk: &[u8],
+    label: &[u8],
+    info: &[u8],
+    length: usize,
+) -> Vec<u8> {
+    let mut labeled_info = i2osp(length as u64, 2);
+    labeled_info.extend_from_slice(VERSION_LABEL);
+    labeled_info.extend_from_slice(suite_id);
+    labeled_info.extend_from_slice(label);
+    labeled_info.extend_from_slice(info);
+    kdf.expand(prk, &labeled_info, length)
+}
+
+// DHKEM over one of the supported curves, always with HKDF-SHA256
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DhKem {
+    P256HkdfSha256,
+    X25519HkdfSha256,
+}
+
+impl DhKem {
+    pub fn id(self) -> u16 {
+        match self {
+            DhKem::P256HkdfSha256 => KEM_DHKEM_P256_HKDF_SHA256,
+            DhKem::X25519HkdfSha256 => KEM_DHKEM_X25519_HKDF_SHA256,
+        }
+    }
+    pub fn private_key_length(self) -> usize {
+        match self {
+            DhKem::P256HkdfSha256 => P256PRIVATEKEYBYTES,
+            DhKem::X25519HkdfSha256 => X25519PRIVATEKEYBYTES,
+        }
+    }
+    pub fn public_key_length(self) -> usize {
+        match self {
+            DhKem::P256HkdfSha256 => P256PUBLICKEYBYTES,
+            DhKem::X25519HkdfSha256 => X25519PUBLICKEYBYTES,
+        }
+    }
+    fn kdf(self) -> &'static dyn Kdf {
+        &HkdfSha256 {}
+    }
+    fn suite_id(self) -> Vec<u8> {
+        let mut suite_id = b"KEM".to_vec();
+        suite_id.extend_from_slice(&i2osp(u64::from(self.id()), 2));
+        suite_id
+    }
+    pub fn derive_key_pair(self, ikm: &[u8]) -> Result<(KemPrivateKey, KemPublicKey), HpkeError> {
+        let suite_id = self.suite_id();
+        let dkp_prk = labeled_extract(self.kdf(), &suite_id, &[], b"dkp_prk", ikm);
+        let private_key = match self {
+            DhKem::X25519HkdfSha256 => {
+                let mut sk = labeled_expand(
+                    self.kdf(),
+                    &suite_id,
+                    &dkp_prk,
+                    b"sk",
+                    &[],
+                    self.private_key_length(),
+                );
+                let private_key = KemPrivateKey::from_slice(&sk);
+                erase(&mut sk);
+                private_key
+            }
+            DhKem::P256HkdfSha256 => {
+                let mut private_key = None;
+                for counter in 0..=255u8 {
+                    let mut candidate = labeled_expand(
+                        self.kdf(),
+                        &suite_id,
+                        &dkp_prk,
+                        b"candidate",
+                        &[counter],
+                        self.private_key_length(),
+                    );
+                    if let Some(sk) = P256PrivateKey::from_slice(&candidate) {
+                        private_key = Some(KemPrivateKey::from_slice(sk.as_slice()));
+                    }
+                    erase(&mut candidate);
+                    if private_key.is_some() {
+                        break;
+                    }
+                }
+                private_key.ok_or(HpkeError::DeriveKeyPairError)?
+            }
+        };
+        let public_key = self.public_key(&private_key)?;
+        Ok((private_key, public_key))
+    }
+    pub fn generate_key_pair(self, rng: &mut dyn CryptoRng) -> (KemPrivateKey, KemPublicKey) {
+        let mut ikm = rng.random_bytes(self.private_key_length());
+        let key_pair = self.derive_key_pair(&ikm).unwrap();
+        erase(&mut ikm);
+        key_pair
+    }
+    pub fn public_key(self, private_key: &KemPrivateKey) -> Result<KemPublicKey, HpkeError> {
+        if private_key.as_slice().len() != self.private_key_length() {
+            return Err(HpkeError::InvalidKey);
+        }
+        match self {
+            DhKem::X25519HkdfSha256 => {
+                let sk = X25519PrivateKey::from_slice(private_key.as_slice())
+                    .ok_or(HpkeError::InvalidKey)?;
+                Ok(KemPublicKey::from_slice(&sk.derive_public_key().to_bytes()))
+            }
+            DhKem::P256HkdfSha256 => {
+                let sk = P256PrivateKey::from_slice(private_key.as_slice())
+                    .ok_or(HpkeError::InvalidKey)?;
+                Ok(KemPublicKey::from_slice(&sk.derive_public_key().to_bytes()))
+            }
+        }
+    }
+    pub fn is_valid_public_key(self, public_key: &KemPublicKey) -> bool {
+        if public_key.as_slice().len() != self.public_key_length() {
+            return false;
+        }
+        match self {
+            DhKem::X25519HkdfSha256 => {
+                X25519PublicKey::from_slice(public_key.as_slice()).is_valid()
+            }
+            DhKem::P256HkdfSha256 => P256PublicKey::from_slice(public_key.as_slice()).is_valid(),
+        }
+    }
+    fn dh(
+        self,
+        private_key: &KemPrivateKey,
+        public_key: &KemPublicKey,
+    ) -> Result<Vec<u8>, HpkeError> {
+        if private_key.as_slice().len() != self.private_key_length()
+            || !self.is_valid_public_key(public_key)
+        {
+            return Err(HpkeError::InvalidKey);
+        }
+        match self {
+            DhKem::X25519HkdfSha256 => {
+                let sk = X25519PrivateKey::from_slice(private_key.as_slice())
+                    .ok_or(HpkeError::InvalidKey)?;
+                let pk = X25519PublicKey::from_slice(public_key.as_slice());
+                match sk.shared_secret(&pk) {
+                    Ok(secret) => Ok(secret.to_vec()),
+                    Err(_) => Err(HpkeError::InvalidKey),
+                }
+            }
+            DhKem::P256HkdfSha256 => {
+                let sk = P256PrivateKey::from_slice(private_key.as_slice())
+                    .ok_or(HpkeError::InvalidKey)?;
+                let pk = P256PublicKey::from_slice(public_key.as_slice());
+                match sk.shared_secret(&pk) {
+                    Ok(secret) => Ok(secret.to_vec()),
+                    Err(_) => Err(HpkeError::InvalidKey),
+                }
+            }
+        }
+    }
+    fn extract_and_expand(self, dh: &mut [u8], kem_context: &[u8]) -> Vec<u8> {
+        let suite_id = self.suite_id();
+        let mut eae_prk = labeled_extract(self.kdf(), &suite_id, &[], b"eae_prk", dh);
+        erase(dh);
+        let shared_secret = labeled_expand(
+            self.kdf(),
+            &suite_id,
+            &eae_prk,
+            b"shared_secret",
+            kem_context,
+            self.kdf().hash_length(),
+        );
+        erase(&mut eae_prk);
+        shared_secret
+    }
+    // Encapsulation with an ephemeral key derived from ikm, for test vectors
+    pub fn encap_with_ikm(
+        self,
+        public_key: &KemPublicKey,
+        ikm: &[u8],
+    ) -> Result<(Vec<u8>, KemPublicKey), HpkeError> {
+        let (ephemeral_private_key, enc) = self.derive_key_pair(ikm)?;
+        let mut dh = self.dh(&ephemeral_private_key, public_key)?;
+        let mut kem_context = enc.as_slice().to_vec();
+        kem_context.extend_from_slice(public_key.as_slice());
+        Ok((self.extract_and_expand(&mut dh, &kem_context), enc))
+    }
+    pub fn encap(
+        self,
+        public_key: &KemPublicKey,
+        rng: &mut dyn CryptoRng,
+    ) -> Result<(Vec<u8>, KemPublicKey), HpkeError> {
+        let mut ikm = rng.random_bytes(self.private_key_length());
+        let result = self.encap_with_ikm(public_key, &ikm);
+        erase(&mut ikm);
+        result
+    }
+    pub fn decap(
+        self,
+        enc: &KemPublicKey,
+        private_key: &KemPrivateKey,
+    ) -> Result<Vec<u8>, HpkeError> {
+        let mut dh = self.dh(private_key, enc)?;
+        let mut kem_context = enc.as_slice().to_vec();
+        kem_context.extend_from_slice(self.public_key(private_key)?.as_slice());
+        Ok(self.extract_and_expand(&mut dh, &kem_context))
+    }
+}
+
+pub struct HpkeContext {
+    aead: &'static dyn Aead,
+    kdf: &'static dyn Kdf,
+    suite_id: Vec<u8>,
+    key: Vec<u8>,
+    base_nonce: Vec<u8>,
+    exporter_secret: Vec<u8>,
+    sequence_number: u64,
+}
+
+impl HpkeContext {
+    fn compute_nonce(&self) -> Vec<u8> {
+        let sequence = i2osp(self.sequence_number, self.base_nonce.len());
+        self.base_nonce
+            .iter()
+            .zip(sequence.iter())
+            .map(|(n, s)| n ^ s)
+            .collect()
+    }
+    fn increment_sequence(&mut self) -> Result<(), HpkeError> {
+        if self.sequence_number == u64::MAX {
+            return Err(HpkeError::MessageLimitReached);
+        }
+        self.sequence_number += 1;
+        Ok(())
+    }
+    pub fn seal(&mut self, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, HpkeError> {
+        let nonce = self.compute_nonce();
+        let ciphertext = match self.aead.seal(&self.key, &nonce, aad, plaintext) {
+            Ok(ciphertext) => ciphertext,
+            Err(_) => return Err(HpkeError::EncryptionError),
+        };
+        self.increment_sequence()?;
+        Ok(ciphertext)
+    }
+    pub fn open(&mut self, aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, HpkeError> {
+        let nonce = self.compute_nonce();
+        let plaintext = match self.aead.open(&self.key, &nonce, aad, ciphertext) {
+            Ok(plaintext) => plaintext,
+            Err(_) => return Err(HpkeError::DecryptionError),
+        };
+        self.increment_sequence()?;
+        Ok(plaintext)
+    }
+    pub fn export(&self, exporter_context: &[u8], length: usize) -> Vec<u8> {
+        labeled_expand(
+            self.kdf,
+            &self.suite_id,
+            &self.exporter_secret,
+            b"sec",
+            exporter_context,
+            length,
+        )
+    }
+}
+
+impl Zeroize for HpkeContext {
+    fn zeroize(&mut self) {
+        erase(&mut self.key);
+        erase(&mut self.base_nonce);
+        erase(&mut self.exporter_secret);
+    }
+}
+
+impl Drop for HpkeContext {
+    fn drop(&mut self) {
+        self.zeroize()
+    }
+}
+
+// A full HPKE suite. Used as the KEM of the cipher suites: TreeKEM and
+// Welcome messages are encrypted with single-shot base mode HPKE.
+pub struct Hpke {
+    pub kem: DhKem,
+    pub kdf: &'static dyn Kdf,
+    pub aead: &'static dyn Aead,
+}
+
+impl Hpke {
+    fn suite_id(&self) -> Vec<u8> {
+        let mut suite_id = b"HPKE".to_vec();
+        suite_id.extend_from_slice(&i2osp(u64::from(self.kem.id()), 2));
+        suite_id.extend_from_slice(&i2osp(u64::from(self.kdf.id()), 2));
+        suite_id.extend_from_slice(&i2osp(u64::from(self.aead.id()), 2));
+        suite_id
+    }
+    fn key_schedule(&self, mut shared_secret: Vec<u8>, info: &[u8]) -> HpkeContext {
+        let suite_id = self.suite_id();
+        let psk_id_hash = labeled_extract(self.kdf, &suite_id, &[], b"psk_id_hash", &[]);
+        let info_hash = labeled_extract(self.kdf, &suite_id, &[], b"info_hash", info);
+        let mut context = vec![MODE_BASE];
+        context.extend_from_slice(&psk_id_hash);
+        context.extend_from_slice(&info_hash);
+
+        let mut secret = labeled_extract(self.kdf, &suite_id, &shared_secret, b"secret", &[]);
+        erase(&mut shared_secret);
+        let key = labeled_expand(
+            self.kdf,
+            &suite_id,
+            &secret,
+            b"key",
+            &context,
+            self.aead.key_length(),
+        );
+        let base_nonce = labeled_expand(
+            self.kdf,
+            &suite_id,
+            &secret,
+            b"base_nonce",
+            &context,
+            self.aead.nonce_length(),
+        );
+        let exporter_secret = labeled_expand(
+            self.kdf,
+            &suite_id,
+            &secret,
+            b"exp",
+            &context,
+            self.kdf.hash_length(),
+        );
+        erase(&mut secret);
+        HpkeContext {
+            aead: self.aead,
+            kdf: self.kdf,
+            suite_id,
+            key,
+            base_nonce,
+            exporter_secret,
+            sequence_number: 0,
+        }
+    }
+    pub fn setup_base_sender(
+        &self,
+        public_key: &KemPublicKey,
+        info: &[u8],
+        rng: &mut dyn CryptoRng,
+    ) -> Result<(KemPublicKey, HpkeContext), HpkeError> {
+        let (shared_secret, enc) = self.kem.encap(public_key, rng)?;
+        Ok((enc, self.key_schedule(shared_secret, info)))
+    }
+    pub fn setup_base_sender_with_ikm(
+        &self,
+        public_key: &KemPublicKey,
+        info: &[u8],
+        ikm: &[u8],
+    ) -> Result<(KemPublicKey, HpkeContext), HpkeError> {
+        let (shared_secret, enc) = self.kem.encap_with_ikm(public_key, ikm)?;
+        Ok((enc, self.key_schedule(shared_secret, info)))
+    }
+    pub fn setup_base_receiver(
+        &self,
+        enc: &KemPublicKey,
+        private_key: &KemPrivateKey,
+        info: &[u8],
+    ) -> Result<HpkeContext, HpkeError> {
+        let shared_secret = self.kem.decap(enc, private_key)?;
+        Ok(self.key_schedule(shared_secret, info))
+    }
+    pub fn seal_base(
+        &self,
+        public_key: &KemPublicKey,
+        info: &[u8],
+        aad: &[u8],
+        plaintext: &[u8],
+        rng: &mut dyn CryptoRng,
+    ) -> Result<(KemPublicKey, Vec<u8>), HpkeError> {
+        let (enc, mut context) = self.setup_base_sender(public_key, info, rng)?;
+        Ok((enc, context.seal(aad, plaintext)?))
+    }
+    pub fn open_base(
+        &self,
+        enc: &KemPublicKey,
+        private_key: &KemPrivateKey,
+        info: &[u8],
+        aad: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, HpkeError> {
+        let mut context = self.setup_base_receiver(enc, private_key, info)?;
+        context.open(aad, ciphertext)
+    }
+}
+
+impl Kem for Hpke {
+    // Node keys are derived from node secrets as before, not with
+    // DeriveKeyPair, so that tree key derivation stays unchanged. Node
+    // secrets longer than an X25519 key are truncated.
+    fn derive_key_pair(&self, secret: &[u8]) -> (KemPrivateKey, KemPublicKey) {
+        match self.kem {
+            DhKem::X25519HkdfSha256 => {
+                let private_key = secret
+                    .get(..X25519PRIVATEKEYBYTES)
+                    .and_then(X25519PrivateKey::from_slice)
+                    .expect("node secrets are at least as long as an X25519 key");
+                let public_key = private_key.derive_public_key();
+                (
+                    KemPrivateKey::from_slice(private_key.as_slice()),
+                    KemPublicKey::from_slice(&public_key.to_bytes()),
+                )
+            }
+            DhKem::P256HkdfSha256 => {
+                let kp = P256KeyPair::new_from_secret(secret);
+                (
+                    KemPrivateKey::from_slice(kp.private_key.as_slice()),
+                    KemPublicKey::from_slice(&kp.public_key.to_bytes()),
+                )
+            }
+        }
+    }
+    fn generate_key_pair(&self, rng: &mut dyn CryptoRng) -> (KemPrivateKey, KemPublicKey) {
+        self.kem.generate_key_pair(rng)
+    }
+    fn is_valid_public_key(&self, public_key: &KemPublicKey) -> bool {
+        self.kem.is_valid_public_key(public_key)
+    }
+    fn encrypt(
+        &self,
+        public_key: &KemPublicKey,
+        info: &[u8],
+        aad: &[u8],
+        payload: &[u8],
+        rng: &mut dyn CryptoRng,
+    ) -> Result<KemCiphertext, EcKemError> {
+        let (enc, sealed_box) = self.seal_base(public_key, info, aad, payload, rng)?;
+        Ok(KemCiphertext {
+            public_key: enc,
+            sealed_box,
+        })
+    }
+    fn decrypt(
+        &self,
+        private_key: &KemPrivateKey,
+        info: &[u8],
+        aad: &[u8],
+        ciphertext: &KemCiphertext,
+    ) -> Result<Vec<u8>, EcKemError> {
+        match self.open_base(
+            &ciphertext.public_key,
+            private_key,
+            info,
+            aad,
+            &ciphertext.sealed_box,
+        ) {
+            Ok(plaintext) => Ok(plaintext),
+            Err(_) => Err(AesError::DecryptionError),
+        }
+    }
+}
+
+// RFC 9180, A.1.1: DHKEM(X25519, HKDF-SHA256), HKDF-SHA256, AES-128-GCM
+#[test]
+fn hpke_base_x25519_aes128gcm_test_vector() {
+    let hpke = Hpke {
+        kem: DhKem::X25519HkdfSha256,
+        kdf: &HkdfSha256 {},
+        aead: &Aes128Gcm {},
+    };
+    let info = hex_to_bytes("4f6465206f6e2061204772656369616e2055726e");
+    let ikm_e = hex_to_bytes("7268600d403fce431561aef583ee1613527cff655c1343f29812e66706df3234");
+    let ikm_r = hex_to_bytes("6db9df30aa07dd42ee5e8181afdb977e538f5e1fec8a06223f33f7013e525037");
+
+    let (sk_e, pk_e) = hpke.kem.derive_key_pair(&ikm_e).unwrap();
+    assert_eq!(
+        bytes_to_hex(sk_e.as_slice()),
+        "52C4A758A802CD8B936ECEEA314432798D5BAF2D7E9235DC084AB1B9CFA2F736"
+    );
+    assert_eq!(
+        bytes_to_hex(pk_e.as_slice()),
+        "37FDA3567BDBD628E88668C3C8D7E97D1D1253B6D4EA6D44C150F741F1BF4431"
+    );
+    let (sk_r, pk_r) = hpke.kem.derive_key_pair(&ikm_r).unwrap();
+    assert_eq!(
+        bytes_to_hex(sk_r.as_slice()),
+        "4612C550263FC8AD58375DF3F557AAC531D26850903E55A9F23F21D8534E8AC8"
+    );
+    assert_eq!(
+        bytes_to_hex(pk_r.as_slice()),
+        "3948CFE0AD1DDB695D780E59077195DA6C56506B027329794AB02BCA80815C4D"
+    );
+
+    let (shared_secret, enc) = hpke.kem.encap_with_ikm(&pk_r, &ikm_e).unwrap();
+    assert_eq!(enc, pk_e);
+    assert_eq!(
+        bytes_to_hex(&shared_secret),
+        "FE0E18C9F024CE43799AE393C7E8FE8FCE9D218875E8227B0187C04E7D2EA1FC"
+    );
+    assert_eq!(hpke.kem.decap(&enc, &sk_r).unwrap(), shared_secret);
+
+    let (enc, mut sender) = hpke
+        .setup_base_sender_with_ikm(&pk_r, &info, &ikm_e)
+        .unwrap();
+    let mut receiver = hpke.setup_base_receiver(&enc, &sk_r, &info).unwrap();
+    assert_eq!(
+        bytes_to_hex(&sender.key),
+        "4531685D41D65F03DC48F6B8302C05B0"
+    );
+    assert_eq!(bytes_to_hex(&sender.base_nonce), "56D890E5ACCAAF011CFF4B7D");
+    assert_eq!(
+        bytes_to_hex(&sender.exporter_secret),
+        "45FF1C2E220DB587171952C0592D5F5EBE103F1561A2614E38F2FFD47E99E3F8"
+    );
+
+    let plaintext = hex_to_bytes("4265617574792069732074727574682c20747275746820626561757479");
+    let vectors = [
+        (
+            "436f756e742d30",
+            "F938558B5D72F1A23810B4BE2AB4F84331ACC02FC97BABC53A52AE8218A355A96D8770AC83D07BEA87E13C512A",
+        ),
+        (
+            "436f756e742d31",
+            "AF2D7E9AC9AE7E270F46BA1F975BE53C09F8D875BDC8535458C2494E8A6EAB251C03D0C22A56B8CA42C2063B84",
+        ),
+    ];
+    for &(aad, ciphertext) in vectors.iter() {
+        let aad = hex_to_bytes(aad);
+        let sealed = sender.seal(&aad, &plaintext).unwrap();
+        assert_eq!(bytes_to_hex(&sealed), ciphertext);
+        assert_eq!(receiver.open(&aad, &sealed).unwrap(), plaintext);
+    }
+}
+
+#[test]
+fn hpke_base_seal_open() {
+    let mut rng = test_rng("hpke_base_seal_open");
+    for &kem in [DhKem::X25519HkdfSha256, DhKem::P256HkdfSha256].iter() {
+        let hpke = Hpke {
+            kem,
+            kdf: &HkdfSha256 {},
+            aead: &Aes128Gcm {},
+        };
+        let (private_key, public_key) = hpke.kem.generate_key_pair(&mut rng);
+        let plaintext = vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
+
+        let (enc, ciphertext) = hpke
+            .seal_base(&public_key, b"info", b"aad", &plaintext, &mut rng)
+            .unwrap();
+        assert_eq!(
+            hpke.open_base(&enc, &private_key, b"info", b"aad", &ciphertext)
+                .unwrap(),
+            plaintext
+        );
+        assert_eq!(
+            hpke.open_base(&enc, &private_key, b"info", b"", &ciphertext),
+            Err(HpkeError::DecryptionError)
+        );
+        assert_eq!(
+            hpke.open_base(&enc, &private_key, b"", b"aad", &ciphertext),
+            Err(HpkeError::DecryptionError)
+        );
+
+        // Both sides export the same secret
+        let (enc, sender) = hpke
+            .setup_base_sender(&public_key, b"info", &mut rng)
+            .unwrap();
+        let receiver = hpke
+            .setup_base_receiver(&enc, &private_key, b"info")
+            .unwrap();
+        assert_eq!(sender.export(b"ctx", 32), receiver.export(b"ctx", 32));
+    }
+}
@@ -15,6 +15,14 @@
 // along with this program. If not, see http://www.gnu.org/licenses/.
 
 pub mod aesgcm;
+pub mod ciphersuite;
 pub mod eckem;
 pub mod hkdf;
+pub mod hpke;
+pub mod provider;
+pub mod rng;
+#[cfg(feature = "rust-crypto")]
+pub mod rustcrypto;
 pub mod schedule;
+#[cfg(feature = "sodium")]
+pub mod sodium;
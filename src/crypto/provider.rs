@@ -0,0 +1,265 @@
+// Wire
+// Copyright (C) 2019 Wire Swiss GmbH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see http://www.gnu.org/licenses/.
+
+use crypto::aesgcm::AesError;
+use crypto::hkdf::HashAlgorithm;
+
+#[cfg(not(any(feature = "sodium", feature = "rust-crypto")))]
+compile_error!("either the \"sodium\" or the \"rust-crypto\" feature has to be enabled");
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AeadAlgorithm {
+    Aes128Gcm,
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl AeadAlgorithm {
+    pub fn key_length(self) -> usize {
+        match self {
+            AeadAlgorithm::Aes128Gcm => 16,
+            AeadAlgorithm::Aes256Gcm | AeadAlgorithm::ChaCha20Poly1305 => 32,
+        }
+    }
+}
+
+pub const AEAD_NONCE_BYTES: usize = 12;
+pub const X25519_BYTES: usize = 32;
+pub const ED25519_SEED_BYTES: usize = 32;
+pub const ED25519_PUBLIC_KEY_BYTES: usize = 32;
+pub const ED25519_SIGNATURE_BYTES: usize = 64;
+
+// Argon2id parameters, with the memory limit in bytes as in libsodium
+pub const PWHASH_SALT_BYTES: usize = 16;
+pub const PWHASH_OPSLIMIT_MIN: u32 = 1;
+pub const PWHASH_MEMLIMIT_MIN: u32 = 8192;
+pub const PWHASH_OPSLIMIT_INTERACTIVE: u32 = 2;
+pub const PWHASH_MEMLIMIT_INTERACTIVE: u32 = 67_108_864;
+pub const PWHASH_OPSLIMIT_SENSITIVE: u32 = 4;
+pub const PWHASH_MEMLIMIT_SENSITIVE: u32 = 1_073_741_824;
+
+// The primitives the rest of the crate is built on. Implementations only
+// wrap a backend library, all protocol logic (HKDF, HPKE, ECIES, ...) is
+// written once on top of this trait.
+pub trait CryptoProvider: Sync {
+    fn name(&self) -> &'static str;
+    fn random_bytes(&self, buffer: &mut [u8]);
+    fn hash(&self, algorithm: HashAlgorithm, data: &[u8]) -> Vec<u8>;
+    // Keys of any length are accepted, as in RFC 2104
+    fn hmac(&self, algorithm: HashAlgorithm, key: &[u8], data: &[u8]) -> Vec<u8>;
+    // The tag is appended to the ciphertext
+    fn aead_seal(
+        &self,
+        algorithm: AeadAlgorithm,
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        payload: &[u8],
+    ) -> Result<Vec<u8>, AesError>;
+    fn aead_open(
+        &self,
+        algorithm: AeadAlgorithm,
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        sealed_box: &[u8],
+    ) -> Result<Vec<u8>, AesError>;
+    // Returns None if the shared secret is all zeroes
+    fn x25519(
+        &self,
+        scalar: &[u8; X25519_BYTES],
+        point: &[u8; X25519_BYTES],
+    ) -> Option<[u8; X25519_BYTES]>;
+    fn x25519_base(&self, scalar: &[u8; X25519_BYTES]) -> [u8; X25519_BYTES];
+    fn ed25519_public_key(&self, seed: &[u8; ED25519_SEED_BYTES])
+        -> [u8; ED25519_PUBLIC_KEY_BYTES];
+    fn ed25519_sign(
+        &self,
+        seed: &[u8; ED25519_SEED_BYTES],
+        payload: &[u8],
+    ) -> [u8; ED25519_SIGNATURE_BYTES];
+    fn ed25519_verify(
+        &self,
+        public_key: &[u8; ED25519_PUBLIC_KEY_BYTES],
+        payload: &[u8],
+        signature: &[u8],
+    ) -> bool;
+    // Argon2id version 1.3 with one lane, like crypto_pwhash. Returns None
+    // if the limits are out of range or the memory cannot be allocated.
+    fn pwhash(
+        &self,
+        password: &[u8],
+        salt: &[u8; PWHASH_SALT_BYTES],
+        opslimit: u32,
+        memlimit: u32,
+        length: usize,
+    ) -> Option<Vec<u8>>;
+}
+
+// libsodium is preferred when both backends are built
+#[cfg(feature = "sodium")]
+pub fn provider() -> &'static dyn CryptoProvider {
+    &::crypto::sodium::SODIUM_PROVIDER
+}
+
+#[cfg(all(feature = "rust-crypto", not(feature = "sodium")))]
+pub fn provider() -> &'static dyn CryptoProvider {
+    &::crypto::rustcrypto::RUST_CRYPTO_PROVIDER
+}
+
+#[cfg(test)]
+fn check_provider(provider: &dyn CryptoProvider) {
+    use utils::*;
+
+    // RFC 4231, test case 2
+    assert_eq!(
+        provider.hmac(
+            HashAlgorithm::Sha256,
+            b"Jefe",
+            b"what do ya want for nothing?"
+        ),
+        hex_to_bytes("5BDCC146BF60754E6A042426089575C75A003F089D2739839DEC58B964EC3843")
+    );
+    assert_eq!(
+        provider.hash(HashAlgorithm::Sha512, b"abc")[..8],
+        hex_to_bytes("DDAF35A193617ABA")[..]
+    );
+
+    // RFC 7748, section 6.1
+    let mut alice = [0u8; X25519_BYTES];
+    alice.copy_from_slice(&hex_to_bytes(
+        "77076D0A7318A57D3C16C17251B26645DF4C2F87EBC0992AB177FBA51DB92C2A",
+    ));
+    let mut bob = [0u8; X25519_BYTES];
+    bob.copy_from_slice(&hex_to_bytes(
+        "5DAB087E624A8A4B79E17F8B83800EE66F3BB1292618B6FD1C2F8B27FF88E0EB",
+    ));
+    let shared_secret = provider.x25519(&alice, &provider.x25519_base(&bob));
+    assert_eq!(
+        shared_secret.unwrap().to_vec(),
+        hex_to_bytes("4A5D9D5BA4CE2DE1728E3BF480350F25E07E21C947D19E3376F09B3C1E161742")
+    );
+    assert!(provider.x25519(&alice, &[0u8; X25519_BYTES]).is_none());
+
+    // RFC 8032, section 7.1, test 2
+    let mut seed = [0u8; ED25519_SEED_BYTES];
+    seed.copy_from_slice(&hex_to_bytes(
+        "4CCD089B28FF96DA9DB6C346EC114E0F5B8A319F35ABA624DA8CF6ED4FB8A6FB",
+    ));
+    let public_key = provider.ed25519_public_key(&seed);
+    assert_eq!(
+        public_key.to_vec(),
+        hex_to_bytes("3D4017C3E843895A92B70AA74D1B7EBC9C982CCF2EC4968CC0CD55F12AF4660C")
+    );
+    let signature = provider.ed25519_sign(&seed, &[0x72]);
+    assert_eq!(signature[..8], hex_to_bytes("92A009A9F0D4CAB8")[..]);
+    assert!(provider.ed25519_verify(&public_key, &[0x72], &signature));
+    assert!(!provider.ed25519_verify(&public_key, &[0x73], &signature));
+    assert!(!provider.ed25519_verify(&public_key, &[0x72], &signature[..63]));
+
+    for &algorithm in [
+        AeadAlgorithm::Aes128Gcm,
+        AeadAlgorithm::Aes256Gcm,
+        AeadAlgorithm::ChaCha20Poly1305,
+    ]
+    .iter()
+    {
+        let key = vec![0x2Au8; algorithm.key_length()];
+        let nonce = [0x11u8; AEAD_NONCE_BYTES];
+        let sealed_box = provider
+            .aead_seal(algorithm, &key, &nonce, b"aad", b"payload")
+            .unwrap();
+        assert_eq!(sealed_box.len(), b"payload".len() + 16);
+        assert_eq!(
+            provider
+                .aead_open(algorithm, &key, &nonce, b"aad", &sealed_box)
+                .unwrap(),
+            b"payload".to_vec()
+        );
+        assert!(provider
+            .aead_open(algorithm, &key, &nonce, b"other", &sealed_box)
+            .is_err());
+        assert!(provider
+            .aead_seal(algorithm, &key[1..], &nonce, b"aad", b"payload")
+            .is_err());
+    }
+
+    // Every parameter changes the output, test_providers_agree checks that
+    // the backends compute the same function
+    let salt = [0x5Au8; PWHASH_SALT_BYTES];
+    let key = provider
+        .pwhash(b"passphrase", &salt, 3, PWHASH_MEMLIMIT_MIN, 32)
+        .unwrap();
+    assert_eq!(key.len(), 32);
+    assert_ne!(
+        provider
+            .pwhash(b"passphrase", &salt, 3, 2 * PWHASH_MEMLIMIT_MIN, 32)
+            .unwrap(),
+        key
+    );
+    assert!(provider
+        .pwhash(b"passphrase", &salt, 0, PWHASH_MEMLIMIT_MIN, 32)
+        .is_none());
+
+    let mut random = [0u8; 32];
+    provider.random_bytes(&mut random);
+    assert!(random.iter().any(|&b| b != 0));
+}
+
+#[test]
+fn test_provider() {
+    check_provider(provider());
+}
+
+// Both backends have to produce the same output
+#[cfg(all(feature = "sodium", feature = "rust-crypto"))]
+#[test]
+fn test_providers_agree() {
+    use crypto::rustcrypto::RUST_CRYPTO_PROVIDER;
+    use crypto::sodium::SODIUM_PROVIDER;
+
+    check_provider(&RUST_CRYPTO_PROVIDER);
+
+    let key = [0x42u8; 32];
+    let nonce = [0x24u8; AEAD_NONCE_BYTES];
+    for &algorithm in [
+        AeadAlgorithm::Aes128Gcm,
+        AeadAlgorithm::Aes256Gcm,
+        AeadAlgorithm::ChaCha20Poly1305,
+    ]
+    .iter()
+    {
+        let key = &key[..algorithm.key_length()];
+        let sealed_box = SODIUM_PROVIDER
+            .aead_seal(algorithm, key, &nonce, b"aad", b"payload")
+            .unwrap();
+        assert_eq!(
+            RUST_CRYPTO_PROVIDER
+                .aead_seal(algorithm, key, &nonce, b"aad", b"payload")
+                .unwrap(),
+            sealed_box
+        );
+    }
+    assert_eq!(
+        SODIUM_PROVIDER.ed25519_sign(&key, b"payload").to_vec(),
+        RUST_CRYPTO_PROVIDER.ed25519_sign(&key, b"payload").to_vec()
+    );
+    let salt = [0x24u8; PWHASH_SALT_BYTES];
+    assert_eq!(
+        SODIUM_PROVIDER.pwhash(b"passphrase", &salt, 2, 65536, 32),
+        RUST_CRYPTO_PROVIDER.pwhash(b"passphrase", &salt, 2, 65536, 32)
+    );
+}
@@ -0,0 +1,123 @@
+// Wire
+// Copyright (C) 2019 Wire Swiss GmbH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see http://www.gnu.org/licenses/.
+
+use crypto::hkdf::HashAlgorithm;
+use crypto::provider::provider;
+use utils::*;
+
+// Source of all randomness. Every function that needs random bytes takes
+// one, so that protocol runs can be reproduced with a SeededRng.
+pub trait CryptoRng: Send + Sync {
+    fn fill_bytes(&mut self, buffer: &mut [u8]);
+    fn box_clone(&self) -> Box<dyn CryptoRng>;
+
+    fn random_bytes(&mut self, length: usize) -> Vec<u8> {
+        let mut buffer = vec![0u8; length];
+        self.fill_bytes(&mut buffer);
+        buffer
+    }
+}
+
+impl Clone for Box<dyn CryptoRng> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+// The random number generator of the operating system, through the crypto
+// provider
+#[derive(Clone, Copy, Default)]
+pub struct OsRng;
+
+impl CryptoRng for OsRng {
+    fn fill_bytes(&mut self, buffer: &mut [u8]) {
+        provider().random_bytes(buffer);
+    }
+    fn box_clone(&self) -> Box<dyn CryptoRng> {
+        Box::new(*self)
+    }
+}
+
+// Deterministic generator for tests and test vectors, it must never be used
+// for real keys. Block i of the output is HMAC-SHA256(SHA256(seed), i).
+#[derive(Clone)]
+pub struct SeededRng {
+    key: Vec<u8>,
+    counter: u64,
+    block: Vec<u8>,
+    position: usize,
+}
+
+impl SeededRng {
+    pub fn from_seed(seed: &[u8]) -> Self {
+        SeededRng {
+            key: HashAlgorithm::Sha256.hash(seed),
+            counter: 0,
+            block: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+impl CryptoRng for SeededRng {
+    fn fill_bytes(&mut self, buffer: &mut [u8]) {
+        for byte in buffer.iter_mut() {
+            if self.position == self.block.len() {
+                erase(&mut self.block);
+                self.block = HashAlgorithm::Sha256.hmac(&self.key, &self.counter.to_be_bytes());
+                self.counter += 1;
+                self.position = 0;
+            }
+            *byte = self.block[self.position];
+            self.position += 1;
+        }
+    }
+    fn box_clone(&self) -> Box<dyn CryptoRng> {
+        Box::new(self.clone())
+    }
+}
+
+impl Zeroize for SeededRng {
+    fn zeroize(&mut self) {
+        erase(&mut self.key);
+        erase(&mut self.block);
+    }
+}
+
+impl Drop for SeededRng {
+    fn drop(&mut self) {
+        self.zeroize()
+    }
+}
+
+#[test]
+fn test_seeded_rng() {
+    let mut a = SeededRng::from_seed(b"seed");
+    let mut b = SeededRng::from_seed(b"seed");
+
+    // The output does not depend on how it is split into calls
+    let bytes = a.random_bytes(100);
+    let mut split = b.random_bytes(31);
+    split.extend(b.random_bytes(69));
+    assert_eq!(bytes, split);
+    assert_eq!(
+        bytes[..32],
+        HashAlgorithm::Sha256.hmac(&HashAlgorithm::Sha256.hash(b"seed"), &[0u8; 8])[..]
+    );
+
+    assert_ne!(SeededRng::from_seed(b"other").random_bytes(100), bytes);
+    assert_ne!(OsRng.random_bytes(32), OsRng.random_bytes(32));
+}
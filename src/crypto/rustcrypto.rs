@@ -0,0 +1,174 @@
+// Wire
+// Copyright (C) 2019 Wire Swiss GmbH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see http://www.gnu.org/licenses/.
+
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes128Gcm, Aes256Gcm};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::ChaCha20Poly1305;
+use crypto::aesgcm::AesError;
+use crypto::hkdf::HashAlgorithm;
+use crypto::provider::*;
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
+use getrandom;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256, Sha512};
+use utils::*;
+use x25519_dalek;
+
+// Backend built on the RustCrypto and dalek crates only, for targets
+// without a C toolchain for libsodium
+pub struct RustCryptoProvider {}
+
+pub static RUST_CRYPTO_PROVIDER: RustCryptoProvider = RustCryptoProvider {};
+
+impl CryptoProvider for RustCryptoProvider {
+    fn name(&self) -> &'static str {
+        "rust-crypto"
+    }
+    fn random_bytes(&self, buffer: &mut [u8]) {
+        getrandom::getrandom(buffer).expect("the OS random number generator failed");
+    }
+    fn hash(&self, algorithm: HashAlgorithm, data: &[u8]) -> Vec<u8> {
+        match algorithm {
+            HashAlgorithm::Sha256 => Sha256::digest(data).to_vec(),
+            HashAlgorithm::Sha512 => Sha512::digest(data).to_vec(),
+        }
+    }
+    fn hmac(&self, algorithm: HashAlgorithm, key: &[u8], data: &[u8]) -> Vec<u8> {
+        match algorithm {
+            HashAlgorithm::Sha256 => {
+                let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).unwrap();
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+            HashAlgorithm::Sha512 => {
+                let mut mac = <Hmac<Sha512> as Mac>::new_from_slice(key).unwrap();
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+    fn aead_seal(
+        &self,
+        algorithm: AeadAlgorithm,
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        payload: &[u8],
+    ) -> Result<Vec<u8>, AesError> {
+        if key.len() != algorithm.key_length() || nonce.len() != AEAD_NONCE_BYTES {
+            return Err(AesError::EncryptionError);
+        }
+        let payload = Payload { msg: payload, aad };
+        let nonce = nonce.into();
+        let sealed_box = match algorithm {
+            AeadAlgorithm::Aes128Gcm => Aes128Gcm::new(key.into()).encrypt(nonce, payload),
+            AeadAlgorithm::Aes256Gcm => Aes256Gcm::new(key.into()).encrypt(nonce, payload),
+            AeadAlgorithm::ChaCha20Poly1305 => {
+                ChaCha20Poly1305::new(key.into()).encrypt(nonce, payload)
+            }
+        };
+        sealed_box.map_err(|_| AesError::EncryptionError)
+    }
+    fn aead_open(
+        &self,
+        algorithm: AeadAlgorithm,
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        sealed_box: &[u8],
+    ) -> Result<Vec<u8>, AesError> {
+        if key.len() != algorithm.key_length() || nonce.len() != AEAD_NONCE_BYTES {
+            return Err(AesError::DecryptionError);
+        }
+        let payload = Payload {
+            msg: sealed_box,
+            aad,
+        };
+        let nonce = nonce.into();
+        let plaintext = match algorithm {
+            AeadAlgorithm::Aes128Gcm => Aes128Gcm::new(key.into()).decrypt(nonce, payload),
+            AeadAlgorithm::Aes256Gcm => Aes256Gcm::new(key.into()).decrypt(nonce, payload),
+            AeadAlgorithm::ChaCha20Poly1305 => {
+                ChaCha20Poly1305::new(key.into()).decrypt(nonce, payload)
+            }
+        };
+        plaintext.map_err(|_| AesError::DecryptionError)
+    }
+    fn x25519(
+        &self,
+        scalar: &[u8; X25519_BYTES],
+        point: &[u8; X25519_BYTES],
+    ) -> Option<[u8; X25519_BYTES]> {
+        let shared_secret = x25519_dalek::x25519(*scalar, *point);
+        if constant_time_eq(&shared_secret, &[0u8; X25519_BYTES]) {
+            return None;
+        }
+        Some(shared_secret)
+    }
+    fn x25519_base(&self, scalar: &[u8; X25519_BYTES]) -> [u8; X25519_BYTES] {
+        x25519_dalek::x25519(*scalar, x25519_dalek::X25519_BASEPOINT_BYTES)
+    }
+    fn ed25519_public_key(
+        &self,
+        seed: &[u8; ED25519_SEED_BYTES],
+    ) -> [u8; ED25519_PUBLIC_KEY_BYTES] {
+        SigningKey::from_bytes(seed).verifying_key().to_bytes()
+    }
+    fn ed25519_sign(
+        &self,
+        seed: &[u8; ED25519_SEED_BYTES],
+        payload: &[u8],
+    ) -> [u8; ED25519_SIGNATURE_BYTES] {
+        SigningKey::from_bytes(seed).sign(payload).to_bytes()
+    }
+    // Strict verification rejects small order keys and non-canonical
+    // encodings, like libsodium does
+    fn ed25519_verify(
+        &self,
+        public_key: &[u8; ED25519_PUBLIC_KEY_BYTES],
+        payload: &[u8],
+        signature: &[u8],
+    ) -> bool {
+        let public_key = match VerifyingKey::from_bytes(public_key) {
+            Ok(public_key) => public_key,
+            Err(_) => return false,
+        };
+        match ed25519_dalek::Signature::from_slice(signature) {
+            Ok(signature) => public_key.verify_strict(payload, &signature).is_ok(),
+            Err(_) => false,
+        }
+    }
+    // libsodium rounds the memory limit down to KiB
+    fn pwhash(
+        &self,
+        password: &[u8],
+        salt: &[u8; PWHASH_SALT_BYTES],
+        opslimit: u32,
+        memlimit: u32,
+        length: usize,
+    ) -> Option<Vec<u8>> {
+        if opslimit < PWHASH_OPSLIMIT_MIN || memlimit < PWHASH_MEMLIMIT_MIN {
+            return None;
+        }
+        let params = Params::new(memlimit / 1024, opslimit, 1, Some(length)).ok()?;
+        let mut key = vec![0u8; length];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(password, salt, &mut key)
+            .ok()?;
+        Some(key)
+    }
+}
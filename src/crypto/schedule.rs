@@ -15,48 +15,145 @@
 // along with this program. If not, see http://www.gnu.org/licenses/.
 
 use codec::*;
-use crypto::hkdf;
-use sodiumoxide::crypto::hash::sha256;
+use crypto::ciphersuite::*;
+use keys::CipherSuite;
+use std::fmt;
 use utils::*;
 
-pub fn derive_secret(secret: hkdf::Prk, label: &str, context: &[u8]) -> Vec<u8> {
-    let context_hash = sha256::hash(context).0;
-    let hkdf_label = HkdfLabel::new(&context_hash, label);
-    let state = &hkdf_label.serialize();
+pub const PROTOCOL_VERSION_MLS10: u16 = 1;
 
-    println!("HKDFLabel: {}", bytes_to_hex(&state));
+// KDFLabel of RFC 9420, section 8
+pub fn expand_with_label(
+    cipher_suite: &CipherSuiteProvider,
+    secret: &[u8],
+    label: &str,
+    context: &[u8],
+    length: usize,
+) -> Vec<u8> {
+    let full_label = "MLS 1.0 ".to_owned() + label;
+    let mut kdf_label = Vec::new();
+    (length as u16).encode(&mut kdf_label);
+    encode_vec_varint(&mut kdf_label, full_label.as_bytes());
+    encode_vec_varint(&mut kdf_label, context);
+    cipher_suite.kdf.expand(secret, &kdf_label, length)
+}
 
-    let info = hkdf::Info(state);
-    hkdf::expand(secret, info, 32)
+pub fn derive_secret(cipher_suite: &CipherSuiteProvider, secret: &[u8], label: &str) -> Vec<u8> {
+    expand_with_label(
+        cipher_suite,
+        secret,
+        label,
+        &[],
+        cipher_suite.kdf.hash_length(),
+    )
 }
 
-pub const INITSECRETBYTES: usize = 32;
+// The state of the group that every secret of an epoch is bound to
+#[derive(Clone, Debug)]
+pub struct GroupContext {
+    pub version: u16,
+    pub cipher_suite: CipherSuite,
+    pub group_id: Vec<u8>,
+    pub epoch: u64,
+    pub tree_hash: Vec<u8>,
+    pub confirmed_transcript_hash: Vec<u8>,
+    pub extensions: Vec<u8>,
+}
 
-#[derive(Clone, PartialEq, Eq, Default, Debug)]
-pub struct InitSecret([u8; INITSECRETBYTES]);
+impl GroupContext {
+    pub fn new(
+        cipher_suite: &CipherSuiteProvider,
+        group_id: &[u8],
+        epoch: u64,
+        tree_hash: &[u8],
+        confirmed_transcript_hash: &[u8],
+    ) -> Self {
+        GroupContext {
+            version: PROTOCOL_VERSION_MLS10,
+            cipher_suite: cipher_suite.id,
+            group_id: group_id.to_vec(),
+            epoch,
+            tree_hash: tree_hash.to_vec(),
+            confirmed_transcript_hash: confirmed_transcript_hash.to_vec(),
+            extensions: Vec::new(),
+        }
+    }
+}
 
-impl InitSecret {
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        let mut init_secret = InitSecret::default();
-        init_secret.0.clone_from_slice(bytes);
-        init_secret
+impl Codec for GroupContext {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.version.encode(buffer);
+        self.cipher_suite.encode(buffer);
+        encode_vec_varint(buffer, &self.group_id);
+        self.epoch.encode(buffer);
+        encode_vec_varint(buffer, &self.tree_hash);
+        encode_vec_varint(buffer, &self.confirmed_transcript_hash);
+        encode_vec_varint(buffer, &self.extensions);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let version = u16::decode(cursor)?;
+        let cipher_suite = CipherSuite::decode(cursor)?;
+        let group_id = decode_vec_varint(cursor)?;
+        let epoch = u64::decode(cursor)?;
+        let tree_hash = decode_vec_varint(cursor)?;
+        let confirmed_transcript_hash = decode_vec_varint(cursor)?;
+        let extensions = decode_vec_varint(cursor)?;
+        Ok(GroupContext {
+            version,
+            cipher_suite,
+            group_id,
+            epoch,
+            tree_hash,
+            confirmed_transcript_hash,
+            extensions,
+        })
     }
-    pub fn update(&mut self, update_secret: &[u8], group_state: &[u8]) -> EpochSecrets {
-        let current_init_secret = self.0;
-        let salt = hkdf::Salt(&current_init_secret);
-        let ikm = hkdf::Input(update_secret);
-        let epoch_secret = hkdf::extract(salt, ikm);
+}
 
-        println!("Epoch secret {}", bytes_to_hex(&epoch_secret.0));
+// Secrets of the key schedule are as long as the hash output of the cipher
+// suite
+#[derive(Default)]
+pub struct InitSecret(Vec<u8>);
 
-        let application_secret = derive_secret(epoch_secret, "app", group_state);
-        let confirmation_key = derive_secret(epoch_secret, "confirm", group_state);
-        let init_secret = derive_secret(epoch_secret, "init", group_state);
-        let epoch_secrets = EpochSecrets::new(&application_secret, &confirmation_key);
-        self.0.copy_from_slice(&init_secret);
+impl fmt::Debug for InitSecret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "InitSecret(..)")
+    }
+}
 
+impl InitSecret {
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        InitSecret(bytes.to_vec())
+    }
+    // Moves the schedule to the epoch described by group_context. Without a
+    // pre-shared key, a string of zeroes is used as PSK secret.
+    pub fn update(
+        &mut self,
+        cipher_suite: &CipherSuiteProvider,
+        commit_secret: &[u8],
+        psk_secret: Option<&[u8]>,
+        group_context: &GroupContext,
+    ) -> EpochSecrets {
+        let (epoch_secrets, init_secret) = self
+            .joiner_secret(cipher_suite, commit_secret, group_context)
+            .epoch_secrets(cipher_suite, psk_secret, group_context);
+        *self = init_secret;
         epoch_secrets
     }
+    // The first step of update, without moving the schedule
+    pub fn joiner_secret(
+        &self,
+        cipher_suite: &CipherSuiteProvider,
+        commit_secret: &[u8],
+        group_context: &GroupContext,
+    ) -> JoinerSecret {
+        let hash_length = cipher_suite.kdf.hash_length();
+        let context = group_context.encode_detached();
+        let mut prk = cipher_suite.kdf.extract(&self.0, commit_secret);
+        let joiner_secret = expand_with_label(cipher_suite, &prk, "joiner", &context, hash_length);
+        erase(&mut prk);
+        JoinerSecret(joiner_secret)
+    }
 }
 
 impl Codec for InitSecret {
@@ -69,94 +166,280 @@ impl Codec for InitSecret {
     }
 }
 
+impl ConstantTimeEq for InitSecret {
+    fn ct_eq(&self, other: &Self) -> bool {
+        constant_time_eq(&self.0, &other.0)
+    }
+}
+
+impl Zeroize for InitSecret {
+    fn zeroize(&mut self) {
+        erase(&mut self.0)
+    }
+}
+
 impl Drop for InitSecret {
     fn drop(&mut self) {
+        self.zeroize()
+    }
+}
+
+// Sent to new members in the Welcome, everything else of the epoch is
+// derived from it
+pub struct JoinerSecret(Vec<u8>);
+
+impl fmt::Debug for JoinerSecret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "JoinerSecret(..)")
+    }
+}
+
+impl JoinerSecret {
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        JoinerSecret(bytes.to_vec())
+    }
+    // The rest of the schedule, returns the secrets of the epoch and the
+    // init secret of the next one
+    pub fn epoch_secrets(
+        &self,
+        cipher_suite: &CipherSuiteProvider,
+        psk_secret: Option<&[u8]>,
+        group_context: &GroupContext,
+    ) -> (EpochSecrets, InitSecret) {
+        let hash_length = cipher_suite.kdf.hash_length();
+        let context = group_context.encode_detached();
+        let psk_secret = match psk_secret {
+            Some(psk_secret) => psk_secret.to_vec(),
+            None => vec![0u8; hash_length],
+        };
+
+        let mut member_secret = cipher_suite.kdf.extract(&self.0, &psk_secret);
+        let welcome_secret = derive_secret(cipher_suite, &member_secret, "welcome");
+        let mut epoch_secret =
+            expand_with_label(cipher_suite, &member_secret, "epoch", &context, hash_length);
+        erase(&mut member_secret);
+
+        let epoch_secrets = EpochSecrets {
+            joiner_secret: self.0.clone(),
+            welcome_secret,
+            psk_secret,
+            sender_data_secret: derive_secret(cipher_suite, &epoch_secret, "sender data"),
+            encryption_secret: derive_secret(cipher_suite, &epoch_secret, "encryption"),
+            exporter_secret: derive_secret(cipher_suite, &epoch_secret, "exporter"),
+            external_secret: derive_secret(cipher_suite, &epoch_secret, "external"),
+            confirmation_key: derive_secret(cipher_suite, &epoch_secret, "confirm"),
+            membership_key: derive_secret(cipher_suite, &epoch_secret, "membership"),
+            resumption_psk: derive_secret(cipher_suite, &epoch_secret, "resumption"),
+            epoch_authenticator: derive_secret(cipher_suite, &epoch_secret, "authentication"),
+        };
+        let init_secret = InitSecret(derive_secret(cipher_suite, &epoch_secret, "init"));
+        erase(&mut epoch_secret);
+
+        (epoch_secrets, init_secret)
+    }
+}
+
+impl Codec for JoinerSecret {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        encode_vec_u8(buffer, &self.0);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let bytes = decode_vec_u8(cursor)?;
+        Ok(JoinerSecret::from_bytes(&bytes))
+    }
+}
+
+impl Zeroize for JoinerSecret {
+    fn zeroize(&mut self) {
         erase(&mut self.0)
     }
 }
 
-#[derive(Clone)]
+impl Drop for JoinerSecret {
+    fn drop(&mut self) {
+        self.zeroize()
+    }
+}
+
 pub struct EpochSecrets {
-    pub app_secret: [u8; 32],
-    pub confirmation_key: [u8; 32],
+    pub joiner_secret: Vec<u8>,
+    pub welcome_secret: Vec<u8>,
+    pub psk_secret: Vec<u8>,
+    pub sender_data_secret: Vec<u8>,
+    pub encryption_secret: Vec<u8>,
+    pub exporter_secret: Vec<u8>,
+    pub external_secret: Vec<u8>,
+    pub confirmation_key: Vec<u8>,
+    pub membership_key: Vec<u8>,
+    pub resumption_psk: Vec<u8>,
+    pub epoch_authenticator: Vec<u8>,
+}
+
+impl EpochSecrets {
+    fn secrets(&self) -> [&Vec<u8>; 11] {
+        [
+            &self.joiner_secret,
+            &self.welcome_secret,
+            &self.psk_secret,
+            &self.sender_data_secret,
+            &self.encryption_secret,
+            &self.exporter_secret,
+            &self.external_secret,
+            &self.confirmation_key,
+            &self.membership_key,
+            &self.resumption_psk,
+            &self.epoch_authenticator,
+        ]
+    }
 }
 
 impl Codec for EpochSecrets {
     fn encode(&self, buffer: &mut Vec<u8>) {
-        encode_vec_u8(buffer, &self.app_secret);
-        encode_vec_u8(buffer, &self.confirmation_key);
+        for secret in self.secrets().iter() {
+            encode_vec_u8(buffer, secret);
+        }
     }
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
-        let mut app_secret = <[u8; 32]>::default();
-        let mut confirmation_key = <[u8; 32]>::default();
-        app_secret.clone_from_slice(&decode_vec_u8(cursor)?);
-        confirmation_key.clone_from_slice(&decode_vec_u8(cursor)?);
         Ok(EpochSecrets {
-            app_secret,
-            confirmation_key,
+            joiner_secret: decode_vec_u8(cursor)?,
+            welcome_secret: decode_vec_u8(cursor)?,
+            psk_secret: decode_vec_u8(cursor)?,
+            sender_data_secret: decode_vec_u8(cursor)?,
+            encryption_secret: decode_vec_u8(cursor)?,
+            exporter_secret: decode_vec_u8(cursor)?,
+            external_secret: decode_vec_u8(cursor)?,
+            confirmation_key: decode_vec_u8(cursor)?,
+            membership_key: decode_vec_u8(cursor)?,
+            resumption_psk: decode_vec_u8(cursor)?,
+            epoch_authenticator: decode_vec_u8(cursor)?,
         })
     }
 }
 
-impl EpochSecrets {
-    pub fn new(app_secret_bytes: &[u8], confirmation_key_bytes: &[u8]) -> Self {
-        let mut app_secret = [0u8; 32];
-        let mut confirmation_key = [0u8; 32];
-        app_secret.clone_from_slice(&app_secret_bytes[..32]);
-        confirmation_key.clone_from_slice(&confirmation_key_bytes[..32]);
-        Self {
-            app_secret,
-            confirmation_key,
-        }
+impl ConstantTimeEq for EpochSecrets {
+    fn ct_eq(&self, other: &Self) -> bool {
+        // Evaluates all comparisons, so that the timing does not reveal which
+        // secret differs
+        self.secrets()
+            .iter()
+            .zip(other.secrets().iter())
+            .fold(true, |equal, (a, b)| constant_time_eq(a, b) & equal)
     }
 }
 
-pub struct HkdfLabel {
-    label: String,
-    group_state: Vec<u8>,
-}
-
-impl HkdfLabel {
-    pub fn new(context: &[u8], label: &str) -> Self {
-        let full_label = "mls10 ".to_owned() + label;
-
-        HkdfLabel {
-            label: full_label,
-            group_state: context.to_vec(),
-        }
+impl Zeroize for EpochSecrets {
+    fn zeroize(&mut self) {
+        erase(&mut self.joiner_secret);
+        erase(&mut self.welcome_secret);
+        erase(&mut self.psk_secret);
+        erase(&mut self.sender_data_secret);
+        erase(&mut self.encryption_secret);
+        erase(&mut self.exporter_secret);
+        erase(&mut self.external_secret);
+        erase(&mut self.confirmation_key);
+        erase(&mut self.membership_key);
+        erase(&mut self.resumption_psk);
+        erase(&mut self.epoch_authenticator);
     }
+}
 
-    pub fn serialize(&self) -> Vec<u8> {
-        let mut buffer = Vec::new();
-        let mut all = Vec::new();
-        //(self.length as u32).encode(&mut buffer);
-        encode_vec_u8(&mut buffer, self.label.as_bytes());
-        encode_vec_u16(&mut buffer, &self.group_state);
-        (buffer.len() as u32).encode(&mut all);
-        all.append(&mut buffer);
-        all
+impl Drop for EpochSecrets {
+    fn drop(&mut self) {
+        self.zeroize()
     }
 }
 
 #[test]
 fn test_init_secret() {
     const INIT_SECRET_0: [u8; 32] = [0; 32];
-    const UPDATE_SECRET_0: [u8; 32] = [0xAA; 32];
+    const COMMIT_SECRET_0: [u8; 32] = [0xAA; 32];
+    let cipher_suite = &SUITE_CURVE25519_AES128GCM_SHA256;
     let mut init_secret = InitSecret::from_bytes(&INIT_SECRET_0);
 
-    let epoch_secrets = init_secret.update(&UPDATE_SECRET_0, &[]);
-    let mut buffer: Vec<u8> = Vec::new();
-    init_secret.encode(&mut buffer);
+    let group_context = GroupContext::new(cipher_suite, b"group", 1, &[], &[]);
+    assert_eq!(
+        group_context.encode_detached(),
+        hex_to_bytes("000100010567726F75700000000000000001000000")
+    );
 
+    let epoch_secrets = init_secret.update(cipher_suite, &COMMIT_SECRET_0, None, &group_context);
+    assert_eq!(
+        epoch_secrets.joiner_secret,
+        hex_to_bytes("C73C549B0A53A99436E5D953EF7F703625D296398387A7BBE1F1D9F8EEA7B891")
+    );
     assert_eq!(
-        &epoch_secrets.app_secret,
-        &hex_to_bytes("7303BD1A1C6C1B90A9D4B79A179C081B59D7EDD268AC668BF8CFE309399E368F")[..32]
+        epoch_secrets.welcome_secret,
+        hex_to_bytes("9E3BA9A21818CA54EEDA07F7A60E9DA7F3816F6F2FEDAC6385D32B86532F2594")
     );
+    assert_eq!(epoch_secrets.psk_secret, vec![0u8; 32]);
+    assert_eq!(
+        epoch_secrets.encryption_secret,
+        hex_to_bytes("70D2427B498DD85D884EB4759AE9B4C9FD9DDFA020FCA3B80481BAA457EB00CF")
+    );
+    assert_eq!(
+        epoch_secrets.epoch_authenticator,
+        hex_to_bytes("6E8BF799BEAFBE0E8A39F644290FCA30FAB2E8C3154561C5EE48BDDFB3D9ED3A")
+    );
+    assert_eq!(
+        init_secret.0,
+        hex_to_bytes("D02E1329A8926D5A4DEA9079AF8632A77D23FFEEA15169DECD659FC9F2AEBA68")
+    );
+}
 
-    println!("App secret: {}", bytes_to_hex(&epoch_secrets.app_secret));
-    println!(
-        "Confirmation key: {}",
-        bytes_to_hex(&epoch_secrets.confirmation_key)
+#[test]
+fn test_init_secret_sha512() {
+    let cipher_suite = &SUITE_CURVE25519_AES256GCM_SHA512;
+    let mut init_secret = InitSecret::from_bytes(&[0u8; 64]);
+
+    let group_context = GroupContext::new(cipher_suite, b"group", 1, &[], &[]);
+    let epoch_secrets = init_secret.update(cipher_suite, &[0xAA; 64], None, &group_context);
+    assert_eq!(init_secret.0.len(), 64);
+    assert!(epoch_secrets
+        .secrets()
+        .iter()
+        .all(|secret| secret.len() == 64));
+
+    let decoded = EpochSecrets::decode_detached(&epoch_secrets.encode_detached()).unwrap();
+    assert_ct_eq(&decoded, &epoch_secrets);
+}
+
+#[test]
+fn test_epoch_secrets_depend_on_context() {
+    let cipher_suite = &SUITE_CURVE25519_AES128GCM_SHA256;
+    let group_context = GroupContext::new(cipher_suite, b"group", 1, &[], &[]);
+    let other_context = GroupContext::new(cipher_suite, b"group", 2, &[], &[]);
+
+    let epoch_secrets =
+        InitSecret::from_bytes(&[0u8; 32]).update(cipher_suite, &[0xAA; 32], None, &group_context);
+    let other_epoch =
+        InitSecret::from_bytes(&[0u8; 32]).update(cipher_suite, &[0xAA; 32], None, &other_context);
+    let with_psk = InitSecret::from_bytes(&[0u8; 32]).update(
+        cipher_suite,
+        &[0xAA; 32],
+        Some(&[0x55; 32]),
+        &group_context,
     );
-    println!("Init secret: {}", bytes_to_hex(&buffer));
+    assert!(!epoch_secrets.ct_eq(&other_epoch));
+    assert_eq!(epoch_secrets.joiner_secret, with_psk.joiner_secret);
+    assert_ne!(epoch_secrets.welcome_secret, with_psk.welcome_secret);
+    assert_ne!(epoch_secrets.encryption_secret, with_psk.encryption_secret);
+}
+
+#[test]
+fn test_secrets_are_zeroized() {
+    let cipher_suite = &SUITE_CURVE25519_AES128GCM_SHA256;
+    let mut init_secret = InitSecret::from_bytes(&[0x2A; 32]);
+    let group_context = GroupContext::new(cipher_suite, b"group", 1, &[], &[]);
+    let mut epoch_secrets = init_secret.update(cipher_suite, &[0xAA; 32], None, &group_context);
+
+    init_secret.zeroize();
+    assert!(is_zeroized(&init_secret.0));
+    let mut joiner_secret = JoinerSecret::from_bytes(&[0x2A; 32]);
+    joiner_secret.zeroize();
+    assert!(is_zeroized(&joiner_secret.0));
+    epoch_secrets.zeroize();
+    assert!(epoch_secrets
+        .secrets()
+        .iter()
+        .all(|secret| is_zeroized(secret)));
 }
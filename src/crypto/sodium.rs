@@ -0,0 +1,226 @@
+// Wire
+// Copyright (C) 2019 Wire Swiss GmbH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see http://www.gnu.org/licenses/.
+
+use crypto::aesgcm::AesError;
+use crypto::hkdf::HashAlgorithm;
+use crypto::provider::*;
+use ring::aead as ring_aead;
+use sodiumoxide::crypto::aead::chacha20poly1305_ietf;
+use sodiumoxide::crypto::auth::{hmacsha256, hmacsha512};
+use sodiumoxide::crypto::hash::{sha256, sha512};
+use sodiumoxide::crypto::pwhash::argon2id13;
+use sodiumoxide::crypto::scalarmult::curve25519;
+use sodiumoxide::crypto::sign::ed25519;
+use sodiumoxide::randombytes;
+
+// libsodium, with ring for AES-GCM as libsodium only offers AES-256-GCM on
+// CPUs with AES-NI
+pub struct SodiumProvider {}
+
+pub static SODIUM_PROVIDER: SodiumProvider = SodiumProvider {};
+
+impl CryptoProvider for SodiumProvider {
+    fn name(&self) -> &'static str {
+        "sodium"
+    }
+    fn random_bytes(&self, buffer: &mut [u8]) {
+        randombytes::randombytes_into(buffer);
+    }
+    fn hash(&self, algorithm: HashAlgorithm, data: &[u8]) -> Vec<u8> {
+        match algorithm {
+            HashAlgorithm::Sha256 => sha256::hash(data).0.to_vec(),
+            HashAlgorithm::Sha512 => sha512::hash(data).0.to_vec(),
+        }
+    }
+    fn hmac(&self, algorithm: HashAlgorithm, key: &[u8], data: &[u8]) -> Vec<u8> {
+        match algorithm {
+            HashAlgorithm::Sha256 => {
+                let mut state = hmacsha256::State::init(key);
+                state.update(data);
+                state.finalize().0.to_vec()
+            }
+            HashAlgorithm::Sha512 => {
+                let mut state = hmacsha512::State::init(key);
+                state.update(data);
+                state.finalize().0.to_vec()
+            }
+        }
+    }
+    fn aead_seal(
+        &self,
+        algorithm: AeadAlgorithm,
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        payload: &[u8],
+    ) -> Result<Vec<u8>, AesError> {
+        if key.len() != algorithm.key_length() || nonce.len() != AEAD_NONCE_BYTES {
+            return Err(AesError::EncryptionError);
+        }
+        match algorithm {
+            AeadAlgorithm::Aes128Gcm => {
+                ring_seal(&ring_aead::AES_128_GCM, key, nonce, aad, payload)
+            }
+            AeadAlgorithm::Aes256Gcm => {
+                ring_seal(&ring_aead::AES_256_GCM, key, nonce, aad, payload)
+            }
+            AeadAlgorithm::ChaCha20Poly1305 => {
+                let key =
+                    chacha20poly1305_ietf::Key::from_slice(key).ok_or(AesError::EncryptionError)?;
+                let nonce = chacha20poly1305_ietf::Nonce::from_slice(nonce)
+                    .ok_or(AesError::EncryptionError)?;
+                Ok(chacha20poly1305_ietf::seal(
+                    payload,
+                    Some(aad),
+                    &nonce,
+                    &key,
+                ))
+            }
+        }
+    }
+    fn aead_open(
+        &self,
+        algorithm: AeadAlgorithm,
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        sealed_box: &[u8],
+    ) -> Result<Vec<u8>, AesError> {
+        if key.len() != algorithm.key_length() || nonce.len() != AEAD_NONCE_BYTES {
+            return Err(AesError::DecryptionError);
+        }
+        match algorithm {
+            AeadAlgorithm::Aes128Gcm => {
+                ring_open(&ring_aead::AES_128_GCM, key, nonce, aad, sealed_box)
+            }
+            AeadAlgorithm::Aes256Gcm => {
+                ring_open(&ring_aead::AES_256_GCM, key, nonce, aad, sealed_box)
+            }
+            AeadAlgorithm::ChaCha20Poly1305 => {
+                let key =
+                    chacha20poly1305_ietf::Key::from_slice(key).ok_or(AesError::DecryptionError)?;
+                let nonce = chacha20poly1305_ietf::Nonce::from_slice(nonce)
+                    .ok_or(AesError::DecryptionError)?;
+                chacha20poly1305_ietf::open(sealed_box, Some(aad), &nonce, &key)
+                    .map_err(|_| AesError::DecryptionError)
+            }
+        }
+    }
+    fn x25519(
+        &self,
+        scalar: &[u8; X25519_BYTES],
+        point: &[u8; X25519_BYTES],
+    ) -> Option<[u8; X25519_BYTES]> {
+        let scalar = curve25519::Scalar(*scalar);
+        let point = curve25519::GroupElement(*point);
+        curve25519::scalarmult(&scalar, &point)
+            .ok()
+            .map(|shared_secret| shared_secret.0)
+    }
+    fn x25519_base(&self, scalar: &[u8; X25519_BYTES]) -> [u8; X25519_BYTES] {
+        curve25519::scalarmult_base(&curve25519::Scalar(*scalar)).0
+    }
+    fn ed25519_public_key(
+        &self,
+        seed: &[u8; ED25519_SEED_BYTES],
+    ) -> [u8; ED25519_PUBLIC_KEY_BYTES] {
+        let (public_key, _) = ed25519::keypair_from_seed(&ed25519::Seed(*seed));
+        public_key.0
+    }
+    fn ed25519_sign(
+        &self,
+        seed: &[u8; ED25519_SEED_BYTES],
+        payload: &[u8],
+    ) -> [u8; ED25519_SIGNATURE_BYTES] {
+        let (_, secret_key) = ed25519::keypair_from_seed(&ed25519::Seed(*seed));
+        ed25519::sign_detached(payload, &secret_key).0
+    }
+    fn ed25519_verify(
+        &self,
+        public_key: &[u8; ED25519_PUBLIC_KEY_BYTES],
+        payload: &[u8],
+        signature: &[u8],
+    ) -> bool {
+        match ed25519::Signature::from_slice(signature) {
+            Some(signature) => {
+                ed25519::verify_detached(&signature, payload, &ed25519::PublicKey(*public_key))
+            }
+            None => false,
+        }
+    }
+    fn pwhash(
+        &self,
+        password: &[u8],
+        salt: &[u8; PWHASH_SALT_BYTES],
+        opslimit: u32,
+        memlimit: u32,
+        length: usize,
+    ) -> Option<Vec<u8>> {
+        if opslimit < PWHASH_OPSLIMIT_MIN || memlimit < PWHASH_MEMLIMIT_MIN {
+            return None;
+        }
+        let mut key = vec![0u8; length];
+        argon2id13::derive_key(
+            &mut key,
+            password,
+            &argon2id13::Salt(*salt),
+            argon2id13::OpsLimit(opslimit as usize),
+            argon2id13::MemLimit(memlimit as usize),
+        )
+        .ok()?;
+        Some(key)
+    }
+}
+
+fn ring_seal(
+    algorithm: &'static ring_aead::Algorithm,
+    key: &[u8],
+    nonce: &[u8],
+    aad: &[u8],
+    payload: &[u8],
+) -> Result<Vec<u8>, AesError> {
+    let sealing_key =
+        ring_aead::SealingKey::new(algorithm, key).map_err(|_| AesError::EncryptionError)?;
+    let mut buffer: Vec<u8> = Vec::with_capacity(payload.len() + ring_aead::MAX_TAG_LEN);
+    buffer.extend_from_slice(payload);
+    buffer.resize(payload.len() + ring_aead::MAX_TAG_LEN, 0);
+    match ring_aead::seal_in_place(
+        &sealing_key,
+        nonce,
+        aad,
+        &mut buffer,
+        ring_aead::MAX_TAG_LEN,
+    ) {
+        Ok(size) => Ok(buffer[..size].to_vec()),
+        Err(_) => Err(AesError::EncryptionError),
+    }
+}
+
+fn ring_open(
+    algorithm: &'static ring_aead::Algorithm,
+    key: &[u8],
+    nonce: &[u8],
+    aad: &[u8],
+    sealed_box: &[u8],
+) -> Result<Vec<u8>, AesError> {
+    let opening_key =
+        ring_aead::OpeningKey::new(algorithm, key).map_err(|_| AesError::DecryptionError)?;
+    let mut buffer = sealed_box.to_vec();
+    match ring_aead::open_in_place(&opening_key, nonce, aad, 0, &mut buffer) {
+        Ok(bytes) => Ok(bytes.to_vec()),
+        Err(_) => Err(AesError::DecryptionError),
+    }
+}
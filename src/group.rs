@@ -14,15 +14,21 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see http://www.gnu.org/licenses/.
 
+use authentication::*;
 use codec::*;
+use crypto::ciphersuite::*;
+use crypto::rng::*;
 use crypto::schedule::*;
 use keys::*;
 use messages::*;
-use sodiumoxide::randombytes;
+use mp::*;
+use secrettree::*;
+use signer::*;
 use std::collections::hash_map::DefaultHasher;
 use std::hash::*;
 use tree::*;
 use treemath;
+use x509::*;
 
 #[derive(Clone)]
 pub struct Member {}
@@ -34,8 +40,8 @@ pub const GROUPIDBYTES: usize = 255;
 pub struct GroupId(pub [u8; GROUPIDBYTES]);
 
 impl GroupId {
-    pub fn random() -> Self {
-        Self::from_bytes(&randombytes::randombytes(GROUPIDBYTES))
+    pub fn random(rng: &mut dyn CryptoRng) -> Self {
+        Self::from_bytes(&rng.random_bytes(GROUPIDBYTES))
     }
     pub fn from_bytes(bytes: &[u8]) -> Self {
         let mut group_id = [0u8; GROUPIDBYTES];
@@ -56,100 +62,305 @@ impl Codec for GroupId {
 
 pub type GroupEpoch = u32;
 
+// Local policy of a member, it is neither sent nor agreed on
 #[derive(Clone)]
+pub struct GroupConfig {
+    pub padding: PaddingPolicy,
+    pub ratchet: RatchetConfig,
+    pub handshake_history: u32,
+    // Members may use signature schemes other than the one of the cipher suite
+    pub allow_mixed_signature_schemes: bool,
+    // Anchors for the X.509 credentials of members
+    pub trust_store: TrustStore,
+}
+
+impl Default for GroupConfig {
+    fn default() -> Self {
+        GroupConfig {
+            padding: PaddingPolicy::None,
+            ratchet: RatchetConfig::default(),
+            handshake_history: 8,
+            allow_mixed_signature_schemes: false,
+            trust_store: TrustStore::default(),
+        }
+    }
+}
+
+impl Codec for GroupConfig {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.padding.encode(buffer);
+        self.ratchet.encode(buffer);
+        self.handshake_history.encode(buffer);
+        (self.allow_mixed_signature_schemes as u8).encode(buffer);
+        self.trust_store.encode(buffer);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let padding = PaddingPolicy::decode(cursor)?;
+        let ratchet = RatchetConfig::decode(cursor)?;
+        let handshake_history = u32::decode(cursor)?;
+        let allow_mixed_signature_schemes = u8::decode(cursor)? != 0;
+        let trust_store = TrustStore::decode(cursor)?;
+        Ok(GroupConfig {
+            padding,
+            ratchet,
+            handshake_history,
+            allow_mixed_signature_schemes,
+            trust_store,
+        })
+    }
+}
+
 pub struct Group {
-    id: Identity,
+    // Only the public key is persisted, see set_signer
+    signer: Box<dyn Signer>,
+    config: GroupConfig,
+    cipher_suite: &'static CipherSuiteProvider,
     group_id: GroupId,
     group_epoch: GroupEpoch,
     init_secret: InitSecret,
     epoch_secrets: Option<EpochSecrets>,
-    roster: Vec<BasicCredential>,
+    secret_tree: Option<SecretTree>,
+    replay_window: ReplayWindow,
+    roster: Vec<Credential>,
     tree: Tree,
     update_secret: Option<(u64, NodeSecret)>,
     transcript: Vec<GroupOperationValue>,
+    processed_handshakes: Vec<(GroupEpoch, HandshakeDigest)>,
+    // Takes over from the signer once our identity key update is processed.
+    // Not persisted, like the signer.
+    pending_signer: Option<Box<dyn Signer>>,
+    // Not persisted either, decoded groups reject all new credentials until
+    // it is set again
+    authentication_service: Box<dyn AuthenticationService>,
+    // Not persisted, decoded groups use the OS generator
+    rng: Box<dyn CryptoRng>,
 }
 
 impl Codec for Group {
     fn encode(&self, buffer: &mut Vec<u8>) {
-        self.id.encode(buffer);
+        self.signer.public_key().encode(buffer);
+        self.config.encode(buffer);
+        self.cipher_suite.encode(buffer);
         self.group_id.encode(buffer);
         self.group_epoch.encode(buffer);
         self.init_secret.encode(buffer);
         self.epoch_secrets.encode(buffer);
+        self.secret_tree.encode(buffer);
+        self.replay_window.encode(buffer);
         encode_vec_u32(buffer, &self.roster);
         self.tree.encode(buffer);
         self.update_secret.encode(buffer);
         encode_vec_u32(buffer, &self.transcript);
+        encode_vec_u32(buffer, &self.processed_handshakes);
     }
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
-        let id = Identity::decode(cursor)?;
+        let public_key = SignaturePublicKey::decode(cursor)?;
+        let config = GroupConfig::decode(cursor)?;
+        let cipher_suite = <&CipherSuiteProvider>::decode(cursor)?;
         let group_id = GroupId::decode(cursor)?;
         let group_epoch = GroupEpoch::decode(cursor)?;
         let init_secret = InitSecret::decode(cursor)?;
         let epoch_secrets = Option::<EpochSecrets>::decode(cursor)?;
+        let secret_tree = Option::<SecretTree>::decode(cursor)?;
+        let replay_window = ReplayWindow::decode(cursor)?;
         let roster = decode_vec_u32(cursor)?;
         let tree = Tree::decode(cursor)?;
         let update_secret = Option::<(u64, NodeSecret)>::decode(cursor)?;
         let transcript = decode_vec_u32(cursor)?;
+        let processed_handshakes: Vec<(GroupEpoch, HandshakeDigest)> = decode_vec_u32(cursor)?;
+        if processed_handshakes
+            .iter()
+            .any(|(_, digest)| digest.0.len() != cipher_suite.kdf.hash_length())
+        {
+            return Err(CodecError::DecodingError);
+        }
         Ok(Group {
-            id,
+            signer: Box::new(DetachedSigner(public_key)),
+            config,
+            cipher_suite,
             group_id,
             group_epoch,
             init_secret,
             epoch_secrets,
+            secret_tree,
+            replay_window,
             roster,
             tree,
             update_secret,
             transcript,
+            processed_handshakes,
+            pending_signer: None,
+            authentication_service: Box::new(DenyAll),
+            rng: Box::new(OsRng),
         })
     }
 }
 
 impl Group {
-    pub fn new(id: Identity, credential: BasicCredential, group_id: GroupId) -> Self {
-        let secret = NodeSecret::new_random();
-        let own_leaf = Node::from_secret(&secret);
-        let init_secret = InitSecret::from_bytes(&[0u8; 32]);
-        let tree = Tree::new_from_leaf(&own_leaf);
+    // There is no default authentication service, the creator decides which
+    // credentials may enter the group
+    pub fn new(
+        signer: Box<dyn Signer>,
+        credential: Credential,
+        group_id: GroupId,
+        cipher_suite: &'static CipherSuiteProvider,
+        authentication_service: Box<dyn AuthenticationService>,
+    ) -> Self {
+        Group::new_with_rng(
+            signer,
+            credential,
+            group_id,
+            cipher_suite,
+            authentication_service,
+            Box::new(OsRng),
+        )
+    }
+    pub fn new_with_rng(
+        signer: Box<dyn Signer>,
+        credential: Credential,
+        group_id: GroupId,
+        cipher_suite: &'static CipherSuiteProvider,
+        authentication_service: Box<dyn AuthenticationService>,
+        mut rng: Box<dyn CryptoRng>,
+    ) -> Self {
+        let secret = NodeSecret::new_random(cipher_suite, &mut *rng);
+        let init_secret = InitSecret::from_bytes(&vec![0u8; cipher_suite.kdf.hash_length()]);
+        let tree = Tree::new_from_leaf_secret(cipher_suite, secret);
         Group {
-            id,
+            signer,
+            config: GroupConfig::default(),
+            cipher_suite,
             group_id,
             group_epoch: 0,
             init_secret,
             epoch_secrets: None,
+            secret_tree: None,
+            replay_window: ReplayWindow::default(),
             roster: vec![credential],
             tree,
             update_secret: None,
             transcript: vec![],
+            processed_handshakes: Vec::new(),
+            pending_signer: None,
+            authentication_service,
+            rng,
         }
     }
-    pub fn new_from_welcome(id: Identity, welcome: &Welcome) -> Self {
-        let tree_size = welcome.tree.len();
-        assert!(tree_size > 0);
-        let roster = welcome.roster.clone();
-        let own_slot = roster.iter().position(|k| k.public_key == id.public_key);
-        assert!(own_slot.is_some());
-        let tree =
-            Tree::new_from_public_keys(&welcome.tree, own_slot.unwrap() * 2, &welcome.leaf_secret);
-        Group {
-            id,
-            group_id: welcome.group_id.clone(),
+    // Only used by join, which authenticates the roster
+    fn new_from_welcome(
+        signer: Box<dyn Signer>,
+        welcome: Welcome,
+        config: GroupConfig,
+        authentication_service: Box<dyn AuthenticationService>,
+    ) -> Result<Self, HandshakeError> {
+        let cipher_suite = welcome.cipher_suite;
+        let roster = welcome.roster;
+        let own_slot = roster
+            .iter()
+            .position(|c| c.public_key() == signer.public_key());
+        let own_leaf_index = match own_slot {
+            Some(slot) if welcome.tree.len() == treemath::node_width(roster.len()) => slot * 2,
+            _ => return Err(HandshakeError::InvalidWelcome),
+        };
+        if welcome.leaf_secret.0.len() != cipher_suite.kdf.hash_length() {
+            return Err(HandshakeError::InvalidWelcome);
+        }
+        if !welcome
+            .tree
+            .iter()
+            .all(|key| cipher_suite.kem.is_valid_public_key(key))
+        {
+            return Err(HandshakeError::TreeError(TreeError::InvalidPublicKey));
+        }
+        let tree = Tree::new_from_public_keys(
+            cipher_suite,
+            &welcome.tree,
+            own_leaf_index,
+            welcome.leaf_secret,
+        );
+        let mut group = Group {
+            signer,
+            config,
+            cipher_suite,
+            group_id: welcome.group_id,
             group_epoch: welcome.epoch,
-            init_secret: welcome.init_secret.clone(),
+            init_secret: InitSecret::default(),
             epoch_secrets: None,
+            secret_tree: None,
+            replay_window: ReplayWindow::default(),
             roster,
             tree,
             update_secret: None,
-            transcript: welcome.transcript.clone(),
+            transcript: welcome.transcript,
+            processed_handshakes: Vec::new(),
+            pending_signer: None,
+            authentication_service,
+            rng: Box::new(OsRng),
+        };
+        // The joiner runs the rest of the key schedule itself
+        let group_context = group.group_context(group.group_epoch);
+        let (epoch_secrets, init_secret) =
+            welcome
+                .joiner_secret
+                .epoch_secrets(cipher_suite, None, &group_context);
+        group.epoch_secrets = Some(epoch_secrets);
+        group.init_secret = init_secret;
+        Ok(group)
+    }
+    // Joins the group described by a Welcome. The roster is checked with the
+    // trust store of the config and the authentication service. The config
+    // is not part of the Welcome, every member brings their own.
+    pub fn join(
+        signer: Box<dyn Signer>,
+        welcome: Welcome,
+        config: GroupConfig,
+        authentication_service: Box<dyn AuthenticationService>,
+    ) -> Result<Self, HandshakeError> {
+        let group = Group::new_from_welcome(signer, welcome, config, authentication_service)?;
+        for credential in group.roster.iter() {
+            group.authenticate(credential)?;
         }
+        Ok(group)
+    }
+    pub fn set_config(&mut self, config: GroupConfig) {
+        self.config = config;
     }
-    pub fn create_add(&mut self, id: BasicCredential, init_key: &UserInitKey) -> (Welcome, Add) {
-        assert!(init_key.self_verify());
+    pub fn get_config(&self) -> &GroupConfig {
+        &self.config
+    }
+    // Signers are not persisted, a decoded group needs its signer back
+    // before it can sign
+    pub fn set_signer(&mut self, signer: Box<dyn Signer>) -> Result<(), SignerError> {
+        if signer.public_key() != self.signer.public_key() {
+            return Err(SignerError::KeyMismatch);
+        }
+        self.signer = signer;
+        Ok(())
+    }
+    // Replaces the OS generator, e.g. with a SeededRng in tests
+    pub fn set_rng(&mut self, rng: Box<dyn CryptoRng>) {
+        self.rng = rng;
+    }
+    // Checks every credential that enters the group from now on. Groups
+    // decoded from storage need it set again.
+    pub fn set_authentication_service(&mut self, service: Box<dyn AuthenticationService>) {
+        self.authentication_service = service;
+    }
+    pub fn get_cipher_suite(&self) -> &'static CipherSuiteProvider {
+        self.cipher_suite
+    }
+    // The joiner is added with the credential of its init key
+    pub fn create_add(&mut self, init_key: &UserInitKey) -> Result<(Welcome, Add), HandshakeError> {
+        self.check_init_key(init_key)?;
         let size = self.tree.get_leaf_count() + 1;
         let index = self.tree.get_leaf_count() * 2;
 
-        let leaf_secret = NodeSecret::new_random();
-        let (public_nodes, ciphertexts) = self.tree.encrypt(index, size, leaf_secret);
+        let leaf_secret = NodeSecret::new_random(self.cipher_suite, &mut *self.rng);
+        let mut path_nodes = Tree::hash_up(self.cipher_suite, index, size, leaf_secret);
+        let context = self.path_secret_context();
+        let (public_nodes, ciphertexts) =
+            self.tree
+                .encrypt(index, size, &path_nodes, &context, &mut *self.rng);
         let public_path = treemath::dirpath(index, size);
         assert_eq!(public_path.len(), public_nodes.len());
 
@@ -159,111 +370,270 @@ impl Group {
             init_key: init_key.clone(),
         };
 
-        let mut welcome_group = self.clone();
-        welcome_group.process_add(&add);
-
-        let mut welcome_roster = self.roster.clone();
-        welcome_roster.push(id);
+        // The next epoch as the joiner sees it, our own state only changes
+        // once the add is processed
+        let mut tree: Vec<Option<KemPublicKey>> = self
+            .tree
+            .get_public_key_tree()
+            .into_iter()
+            .map(Some)
+            .collect();
+        tree.resize(index + 1, None);
+        let mut merge_path = public_path;
+        merge_path.push(treemath::root(size));
+        for (node_index, node) in merge_path.into_iter().zip(path_nodes.iter()) {
+            tree[node_index] = node.dh_public_key.clone();
+        }
+        let tree: Vec<KemPublicKey> = tree
+            .into_iter()
+            .map(|key| key.expect("the direct path covers the new nodes"))
+            .collect();
+        let mut roster = self.roster.clone();
+        roster.push(init_key.credential.clone());
+        let mut transcript = self.transcript.clone();
+        transcript.push(GroupOperationValue::Add(add.clone()));
+        let epoch = self.group_epoch + 1;
+        let group_context = self.group_context_of(epoch, &roster, &tree, &transcript);
 
+        // Only the joiner secret is sent, the joiner derives the other
+        // secrets of the epoch
+        let joiner_secret = {
+            let root = path_nodes.last().expect("the path ends at the root");
+            let commit_secret = &root.secret.as_ref().unwrap().0;
+            self.init_secret
+                .joiner_secret(self.cipher_suite, commit_secret, &group_context)
+        };
+        let leaf_secret = path_nodes.remove(0).secret.unwrap();
         let welcome = Welcome {
-            group_id: welcome_group.group_id.clone(),
-            epoch: welcome_group.group_epoch,
-            roster: welcome_group.roster.clone(),
-            tree: welcome_group.tree.get_public_key_tree(),
-            transcript: welcome_group.transcript.clone(),
-            init_secret: welcome_group.get_init_secret(),
+            tree,
+            group_id: self.group_id.clone(),
+            epoch,
+            cipher_suite: self.cipher_suite,
+            roster,
+            transcript,
+            joiner_secret,
             leaf_secret,
         };
-        (welcome, add)
+        Ok((welcome, add))
     }
-    pub fn process_add(&mut self, add: &Add) {
-        assert!(add.init_key.self_verify());
+    pub fn process_add(&mut self, add: &Add) -> Result<(), HandshakeError> {
+        self.check_init_key(&add.init_key)?;
         let size = self.tree.get_leaf_count() + 1;
         let index = self.tree.get_leaf_count() * 2;
         let kem_path = treemath::copath(index, size);
-        assert_eq!(kem_path.len(), add.path.len());
+        let context = self.path_secret_context();
         self.tree
-            .apply_kem_path(index, size, &kem_path, &add.path, &add.nodes);
-        let bc = BasicCredential {
-            identity: vec![],
-            public_key: add.init_key.identity_key,
-        };
-        self.roster.push(bc);
+            .apply_kem_path(index, size, &kem_path, &add.path, &add.nodes, &context)?;
+        self.roster.push(add.init_key.credential.clone());
         self.transcript.push(GroupOperationValue::Add(add.clone()));
         self.rotate_epoch_secret();
+        Ok(())
+    }
+    fn check_init_key(&self, init_key: &UserInitKey) -> Result<(), HandshakeError> {
+        if !init_key.self_verify()
+            || !init_key.cipher_suites.contains(&self.cipher_suite.id)
+            || !self.is_signature_scheme_allowed(init_key.identity_key().scheme())
+        {
+            return Err(HandshakeError::InvalidInitKey);
+        }
+        self.authenticate(&init_key.credential)?;
+        Ok(())
     }
     pub fn create_update(&mut self) -> Update {
-        let own_leaf_index = self.tree.get_own_leaf_index();
-        let size = self.tree.get_leaf_count();
-        let leaf_secret = NodeSecret::new_random();
-        let (nodes, ciphertexts) = self.tree.encrypt(own_leaf_index, size, leaf_secret);
+        let (leaf_secret, nodes, path) = self.encrypt_own_path();
         let update = Update {
             nodes,
-            path: ciphertexts,
+            path,
+            identity_key: None,
         };
-        let mut hasher = DefaultHasher::new();
-        update.hash(&mut hasher);
-        let hash = hasher.finish();
-        self.update_secret = Some((hash, leaf_secret));
+        self.update_secret = Some((Group::update_hash(&update), leaf_secret));
         update
     }
-    pub fn process_update(&mut self, sender: usize, update: &Update) {
+    // Replaces our credential and identity key along with the leaf key. The
+    // current and the new signer both sign the update, the handshake is still
+    // signed by the current one. The new one is used once the update is
+    // processed.
+    pub fn create_identity_key_update(
+        &mut self,
+        signer: Box<dyn Signer>,
+        credential: Credential,
+    ) -> Result<Update, SignerError> {
+        if credential.public_key() != signer.public_key() {
+            return Err(SignerError::KeyMismatch);
+        }
+        let (leaf_secret, nodes, path) = self.encrypt_own_path();
+        let content = IdentityKeyUpdateContent {
+            group: self.group_id.0.to_vec(),
+            epoch: self.group_epoch,
+            sender: self.tree.get_own_leaf_index() as u32 / 2,
+            nodes: nodes.clone(),
+            credential,
+        };
+        let identity_key = IdentityKeyUpdate {
+            old_key_signature: content.sign(&*self.signer)?,
+            new_key_signature: content.sign(&*signer)?,
+            credential: content.credential,
+        };
+        let update = Update {
+            nodes,
+            path,
+            identity_key: Some(identity_key),
+        };
+        self.update_secret = Some((Group::update_hash(&update), leaf_secret));
+        self.pending_signer = Some(signer);
+        Ok(update)
+    }
+    pub fn process_update(&mut self, sender: usize, update: &Update) -> Result<(), HandshakeError> {
+        if sender >= self.roster.len() || sender >= self.tree.get_leaf_count() {
+            return Err(HandshakeError::Invalid);
+        }
+        if let Some(ref identity_key) = update.identity_key {
+            self.check_identity_key_update(sender, update, identity_key)?;
+        }
         let size = self.tree.get_leaf_count();
         let index = sender * 2;
         let kem_path = treemath::copath(index, size);
-        if let Some((stored_hash, node_secret)) = self.update_secret {
-            let mut hasher = DefaultHasher::new();
-            update.hash(&mut hasher);
-            let hash = hasher.finish();
-            if stored_hash == hash {
-                let nodes = Tree::hash_up(index, size, &node_secret);
-                let mut merge_path = treemath::dirpath(index, size);
-                merge_path.push(treemath::root(size));
-                self.tree.merge(merge_path, &nodes);
-            } else {
-                self.tree
-                    .apply_kem_path(index, size, &kem_path, &update.path, &update.nodes);
-            }
+        let context = self.path_secret_context();
+        let own_update = match self.update_secret {
+            Some((stored_hash, _)) => stored_hash == Group::update_hash(update),
+            None => false,
+        };
+        if own_update {
+            let (_, node_secret) = self.update_secret.take().unwrap();
+            let nodes = Tree::hash_up(self.cipher_suite, index, size, node_secret);
+            let mut merge_path = treemath::dirpath(index, size);
+            merge_path.push(treemath::root(size));
+            self.tree.merge(merge_path, nodes);
         } else {
-            self.tree
-                .apply_kem_path(index, size, &kem_path, &update.path, &update.nodes);
+            self.tree.apply_kem_path(
+                index,
+                size,
+                &kem_path,
+                &update.path,
+                &update.nodes,
+                &context,
+            )?;
+        }
+        // The roster changes in the same epoch as the leaf key
+        if let Some(ref identity_key) = update.identity_key {
+            let public_key = identity_key.credential.public_key();
+            self.roster[sender] = identity_key.credential.clone();
+            if index == self.tree.get_own_leaf_index() {
+                self.signer = match self.pending_signer.take() {
+                    Some(signer) if signer.public_key() == public_key => signer,
+                    _ => Box::new(DetachedSigner(public_key)),
+                };
+            }
         }
         self.update_secret = None;
         self.transcript
             .push(GroupOperationValue::Update(update.clone()));
         self.rotate_epoch_secret();
+        Ok(())
+    }
+    fn check_identity_key_update(
+        &self,
+        sender: usize,
+        update: &Update,
+        identity_key: &IdentityKeyUpdate,
+    ) -> Result<(), HandshakeError> {
+        let public_key = identity_key.credential.public_key();
+        if sender >= self.roster.len()
+            || !self.is_signature_scheme_allowed(public_key.scheme())
+            || self.roster.iter().any(|c| c.public_key() == public_key)
+        {
+            return Err(HandshakeError::Invalid);
+        }
+        let content = IdentityKeyUpdateContent {
+            group: self.group_id.0.to_vec(),
+            epoch: self.group_epoch,
+            sender: sender as u32,
+            nodes: update.nodes.clone(),
+            credential: identity_key.credential.clone(),
+        };
+        let old_public_key = self.roster[sender].public_key();
+        if !content.verify(&old_public_key, &identity_key.old_key_signature)
+            || !content.verify(&public_key, &identity_key.new_key_signature)
+        {
+            return Err(HandshakeError::Invalid);
+        }
+        // The key changes, the member stays the same
+        let identity = self.authenticate(&identity_key.credential)?;
+        if identity_key.credential.credential_type() != self.roster[sender].credential_type()
+            || self.credential_identity(&self.roster[sender]) != Some(identity)
+        {
+            return Err(HandshakeError::InvalidCredential);
+        }
+        Ok(())
+    }
+    fn encrypt_own_path(&mut self) -> (NodeSecret, Vec<KemPublicKey>, Vec<KemCiphertext>) {
+        let own_leaf_index = self.tree.get_own_leaf_index();
+        let size = self.tree.get_leaf_count();
+        let leaf_secret = NodeSecret::new_random(self.cipher_suite, &mut *self.rng);
+        let mut path_nodes = Tree::hash_up(self.cipher_suite, own_leaf_index, size, leaf_secret);
+        let context = self.path_secret_context();
+        let (nodes, ciphertexts) =
+            self.tree
+                .encrypt(own_leaf_index, size, &path_nodes, &context, &mut *self.rng);
+        // The path is hashed up again from the leaf once the update is processed
+        let leaf_secret = path_nodes.remove(0).secret.unwrap();
+        (leaf_secret, nodes, ciphertexts)
     }
-    pub fn create_remove(&self, participant: usize) -> Remove {
+    fn update_hash(update: &Update) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        update.hash(&mut hasher);
+        hasher.finish()
+    }
+    pub fn create_remove(&mut self, participant: usize) -> Remove {
         assert!(participant <= self.tree.get_leaf_count());
         let index = participant * 2;
         assert!(index != self.tree.get_own_leaf_index());
         let size = self.tree.get_leaf_count();
-        let leaf_secret = NodeSecret::new_random();
-        let (nodes, ciphertexts) = self.tree.encrypt(index, size, leaf_secret);
+        let leaf_secret = NodeSecret::new_random(self.cipher_suite, &mut *self.rng);
+        let path_nodes = Tree::hash_up(self.cipher_suite, index, size, leaf_secret);
+        let context = self.path_secret_context();
+        let (nodes, ciphertexts) =
+            self.tree
+                .encrypt(index, size, &path_nodes, &context, &mut *self.rng);
         Remove {
             removed: participant,
             nodes,
             path: ciphertexts,
         }
     }
-    pub fn process_remove(&mut self, remove: &Remove) {
-        let index = remove.removed * 2; // FIXME should be checked against the roster
+    pub fn process_remove(&mut self, remove: &Remove) -> Result<(), HandshakeError> {
+        if remove.removed >= self.roster.len() || remove.removed >= self.tree.get_leaf_count() {
+            return Err(HandshakeError::Invalid);
+        }
+        let index = remove.removed * 2;
         if index != self.tree.own_leaf_index {
             let size = self.tree.get_leaf_count();
             let kem_path = treemath::copath(index, size);
-            assert_eq!(kem_path.len(), remove.path.len());
-            self.tree
-                .apply_kem_path(index, size, &kem_path, &remove.path, &remove.nodes);
+            let context = self.path_secret_context();
+            self.tree.apply_kem_path(
+                index,
+                size,
+                &kem_path,
+                &remove.path,
+                &remove.nodes,
+                &context,
+            )?;
             self.transcript
                 .push(GroupOperationValue::Remove(remove.clone()));
-            self.roster.remove(index);
+            self.roster.remove(remove.removed);
             self.rotate_epoch_secret();
         }
+        Ok(())
     }
-    pub fn create_handshake(&self, group_operation: GroupOperation) -> Handshake {
+    // The digest of our own handshake is recorded right away, so that it is
+    // recognized as a duplicate when the delivery service echoes it back
+    pub fn create_handshake(
+        &mut self,
+        group_operation: GroupOperation,
+    ) -> Result<Handshake, SignerError> {
         let signer_index = self.tree.get_own_leaf_index() as u32 / 2;
         let prior_epoch = self.group_epoch;
-        let algorithm = ED25519;
+        let algorithm = self.signer.scheme();
         let mut hs = Handshake {
             prior_epoch,
             operation: group_operation,
@@ -271,140 +641,1462 @@ impl Group {
             algorithm,
             signature: None,
         };
-        hs.signature = Some(hs.sign(&self.id));
-        hs
+        hs.signature = Some(hs.sign(&*self.signer)?);
+        let digest = hs.digest(self.cipher_suite);
+        self.record_handshake(prior_epoch, digest);
+        Ok(hs)
     }
-    pub fn process_handshake(&mut self, hs: Handshake) {
+    pub fn process_handshake(&mut self, hs: Handshake) -> Result<(), HandshakeError> {
+        let digest = hs.digest(self.cipher_suite);
+        // Digests of the current epoch belong to our own handshakes that have
+        // not been applied yet
+        if hs.prior_epoch < self.group_epoch {
+            if self
+                .processed_handshakes
+                .contains(&(hs.prior_epoch, digest))
+            {
+                return Err(HandshakeError::Duplicate);
+            }
+            return Err(HandshakeError::StaleEpoch);
+        }
+        if hs.prior_epoch > self.group_epoch {
+            return Err(HandshakeError::FutureEpoch);
+        }
         let sender = hs.signer_index as usize;
-        assert_eq!(hs.prior_epoch, self.group_epoch);
-        assert_eq!(hs.algorithm, ED25519);
-        assert!(sender < self.roster.len());
+        if sender >= self.roster.len()
+            || hs.algorithm != self.roster[sender].public_key().scheme()
+            || !self.is_signature_scheme_allowed(hs.algorithm)
         {
-            let signer = &self.roster[sender];
-            assert!(signer.verify(&hs.unsigned_payload(), &hs.signature.unwrap()));
+            return Err(HandshakeError::Invalid);
+        }
+        let signature = match hs.signature.clone() {
+            Some(signature) => signature,
+            None => return Err(HandshakeError::Invalid),
+        };
+        if !self.roster[sender].verify(&hs.unsigned_payload(), &signature) {
+            return Err(HandshakeError::Invalid);
         }
 
         let group_operation_value = hs.operation.group_operation;
         match group_operation_value {
-            GroupOperationValue::Add(add) => self.process_add(&add),
-            GroupOperationValue::Update(update) => self.process_update(sender, &update),
-            GroupOperationValue::Remove(remove) => self.process_remove(&remove),
-            _ => (),
+            GroupOperationValue::Add(add) => self.process_add(&add)?,
+            GroupOperationValue::Update(update) => self.process_update(sender, &update)?,
+            GroupOperationValue::Remove(remove) => self.process_remove(&remove)?,
+        }
+        self.record_handshake(hs.prior_epoch, digest);
+        Ok(())
+    }
+    fn record_handshake(&mut self, epoch: GroupEpoch, digest: HandshakeDigest) {
+        // Only digests of recent epochs are kept around
+        let oldest_epoch = self
+            .group_epoch
+            .saturating_sub(self.config.handshake_history);
+        self.processed_handshakes
+            .retain(|&(epoch, _)| epoch >= oldest_epoch);
+        let entry = (epoch, digest);
+        if !self.processed_handshakes.contains(&entry) {
+            self.processed_handshakes.push(entry);
         }
     }
-    pub fn get_members(&self) -> Vec<BasicCredential> {
+    pub fn create_application_message(
+        &mut self,
+        content: &[u8],
+    ) -> Result<ApplicationMessage, ApplicationMessageError> {
+        let sender = self.tree.get_own_leaf_index() as u32 / 2;
+        let generation = self.get_application_secret(sender)?.get_stage() + 1;
+
+        // Signed before the ratchet moves, so that a failing signer does not
+        // use up a generation
+        let signature_content = SignatureContent {
+            group: self.group_id.0.to_vec(),
+            epoch: self.group_epoch,
+            generation: generation as u32,
+            sender,
+            content: content.to_vec(),
+        };
+        let signature = signature_content.sign(&*self.signer)?;
+        let stage_secrets = self
+            .get_application_secret(sender)?
+            .get_secret_for_stage(generation)?;
+        let sender_data = SenderData::new(sender, generation as u32, &mut *self.rng);
+        let mut plaintext = ApplicationPlaintext {
+            content: content.to_vec(),
+            signature,
+            zeros: Vec::new(),
+        };
+        plaintext.pad(&self.config.padding);
+        let sender_data_secret = match self.epoch_secrets {
+            Some(ref epoch_secrets) => &epoch_secrets.sender_data_secret,
+            None => return Err(ApplicationMessageError::NoEpochSecrets),
+        };
+        ApplicationMessage::new(
+            self.cipher_suite,
+            &self.group_id.0,
+            self.group_epoch,
+            &sender_data,
+            &plaintext,
+            &stage_secrets,
+            sender_data_secret,
+        )
+    }
+    pub fn process_application_message(
+        &mut self,
+        message: &ApplicationMessage,
+    ) -> Result<Vec<u8>, ApplicationMessageError> {
+        if message.group[..] != self.group_id.0[..] {
+            return Err(ApplicationMessageError::WrongGroup);
+        }
+        if message.epoch != self.group_epoch {
+            return Err(ApplicationMessageError::WrongEpoch);
+        }
+        // The sender has to be known before the right ratchet can be chosen
+        let sender_data = match self.epoch_secrets {
+            Some(ref epoch_secrets) => {
+                message.decrypt_sender_data(self.cipher_suite, &epoch_secrets.sender_data_secret)?
+            }
+            None => return Err(ApplicationMessageError::NoEpochSecrets),
+        };
+        if sender_data.sender as usize >= self.roster.len() {
+            return Err(ApplicationMessageError::UnknownSender);
+        }
+        if self
+            .replay_window
+            .contains(message.epoch, sender_data.sender, sender_data.generation)
+        {
+            return Err(ApplicationMessageError::Replay);
+        }
+        // The generation is only used up once the message is authentic
+        let generation = sender_data.generation as usize;
+        let cipher_suite = self.cipher_suite;
+        let plaintext = self
+            .get_application_secret(sender_data.sender)?
+            .peek_secret_for_stage(generation, |stage_secrets| {
+                message.decrypt_content(cipher_suite, &sender_data, stage_secrets)
+            })??;
+
+        let signature_content = SignatureContent {
+            group: message.group.clone(),
+            epoch: message.epoch,
+            generation: sender_data.generation,
+            sender: sender_data.sender,
+            content: plaintext.content.clone(),
+        };
+        let signer = &self.roster[sender_data.sender as usize];
+        if !signer.verify(&signature_content.unsigned_payload(), &plaintext.signature) {
+            return Err(ApplicationMessageError::InvalidSignature);
+        }
+
+        let stage = {
+            let application_secret = self.get_application_secret(sender_data.sender)?;
+            application_secret.get_secret_for_stage(generation)?;
+            application_secret.get_stage()
+        };
+        let tolerance = self.config.ratchet.out_of_order_tolerance as usize;
+        let oldest_generation = stage.saturating_sub(tolerance) as u32;
+        self.replay_window.insert(
+            message.epoch,
+            sender_data.sender,
+            sender_data.generation,
+            oldest_generation,
+        );
+        Ok(plaintext.content)
+    }
+    pub fn is_signature_scheme_allowed(&self, scheme: SignatureScheme) -> bool {
+        self.config.allow_mixed_signature_schemes || scheme == self.cipher_suite.signature_scheme
+    }
+    // Returns the identity of a credential that enters the group. X.509
+    // chains have to lead to an anchor of the trust store, then the
+    // authentication service decides.
+    fn authenticate(&self, credential: &Credential) -> Result<Vec<u8>, HandshakeError> {
+        let identity = match *credential {
+            Credential::Basic(ref credential) => credential.identity.clone(),
+            Credential::X509(ref credential) => self
+                .config
+                .trust_store
+                .validate(credential)
+                .map_err(|_| HandshakeError::InvalidCredential)?,
+        };
+        if !self
+            .authentication_service
+            .authenticate(&identity, credential)
+        {
+            return Err(HandshakeError::AuthenticationFailed);
+        }
+        Ok(identity)
+    }
+    // The identity of a credential that is already in the roster, its
+    // certificates may have expired since
+    fn credential_identity(&self, credential: &Credential) -> Option<Vec<u8>> {
+        match *credential {
+            Credential::Basic(ref credential) => Some(credential.identity.clone()),
+            Credential::X509(ref credential) => self.config.trust_store.identity(credential).ok(),
+        }
+    }
+    pub fn get_members(&self) -> Vec<Credential> {
         self.roster.clone()
     }
-    pub fn get_init_secret(&self) -> InitSecret {
-        self.init_secret.clone()
+    // Path secrets are bound to the group and the epoch they were sent in
+    fn path_secret_context(&self) -> Vec<u8> {
+        let mut context = Vec::new();
+        self.group_id.encode(&mut context);
+        self.group_epoch.encode(&mut context);
+        context
+    }
+    pub fn get_init_secret(&self) -> &InitSecret {
+        &self.init_secret
     }
     fn rotate_epoch_secret(&mut self) {
-        let root = self.tree.get_root();
-        let update_secret = &root.secret.unwrap().0;
-        let mut group_state = Vec::new();
-        self.encode_group_state(&mut group_state);
-        self.epoch_secrets = Some(self.init_secret.update(update_secret, &group_state));
+        let group_context = self.group_context(self.group_epoch + 1);
+        let commit_secret = &self.tree.get_root().secret.as_ref().unwrap().0;
+        self.epoch_secrets =
+            Some(
+                self.init_secret
+                    .update(self.cipher_suite, commit_secret, None, &group_context),
+            );
+        self.secret_tree = None;
         self.group_epoch += 1;
     }
-    fn encode_group_state(&self, buffer: &mut Vec<u8>) {
-        self.group_id.encode(buffer);
-        self.group_epoch.encode(buffer);
-        encode_vec_u16(buffer, &self.roster);
-        encode_vec_u16(buffer, &self.tree.get_public_key_tree());
-        encode_vec_u16(buffer, &self.transcript); // FIXME
+    fn get_application_secret(
+        &mut self,
+        sender: u32,
+    ) -> Result<&mut SenderApplicationSecret, ApplicationMessageError> {
+        if self.secret_tree.is_none() {
+            let secret_tree = match self.epoch_secrets {
+                Some(ref epoch_secrets) => SecretTree::new(
+                    self.cipher_suite,
+                    &epoch_secrets.encryption_secret,
+                    self.tree.get_leaf_count(),
+                    self.config.ratchet,
+                ),
+                None => return Err(ApplicationMessageError::NoEpochSecrets),
+            };
+            self.secret_tree = Some(secret_tree);
+        }
+        let secret_tree = self.secret_tree.as_mut().unwrap();
+        Ok(secret_tree.get_application_ratchet(sender)?)
     }
+    fn group_context(&self, epoch: GroupEpoch) -> GroupContext {
+        self.group_context_of(
+            epoch,
+            &self.roster,
+            &self.tree.get_public_key_tree(),
+            &self.transcript,
+        )
+    }
+    // The context of another state of the group, e.g. the one a joiner sees
+    fn group_context_of(
+        &self,
+        epoch: GroupEpoch,
+        roster: &[Credential],
+        public_key_tree: &[KemPublicKey],
+        transcript: &[GroupOperationValue],
+    ) -> GroupContext {
+        let mut tree = Vec::new();
+        encode_vec_u32(&mut tree, roster);
+        encode_vec_u16(&mut tree, public_key_tree);
+        let mut transcript_bytes = Vec::new();
+        encode_vec_u32(&mut transcript_bytes, transcript);
+        let transcript = transcript_bytes;
+        GroupContext::new(
+            self.cipher_suite,
+            &self.group_id.0,
+            u64::from(epoch),
+            &self.cipher_suite.kdf.hash(&tree),
+            &self.cipher_suite.kdf.hash(&transcript),
+        )
+    }
+}
+
+// Identities of test members are derived from their name, so that a test
+// can get a member's key back after handing it to a group
+#[cfg(test)]
+fn test_identity(name: &str, scheme: SignatureScheme) -> Identity {
+    use utils::*;
+
+    Identity::random_for_scheme(scheme, &mut test_rng(name)).unwrap()
+}
+
+// Alice creates a group and adds Bob, the Welcome travels encrypted to his
+// init key. Both members use the same config and authentication service.
+#[cfg(test)]
+fn two_member_group<A: AuthenticationService + Clone + 'static>(
+    cipher_suite: &'static CipherSuiteProvider,
+    config: GroupConfig,
+    authentication_service: A,
+) -> (Group, Group) {
+    use utils::*;
+
+    let mut rng = test_rng("two_member_group");
+    let alice_identity = test_identity("Alice", cipher_suite.signature_scheme);
+    let bob_identity = test_identity("Bob", cipher_suite.signature_scheme);
+    let alice_credential = Credential::Basic(BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    });
+    let bob_credential = Credential::Basic(BasicCredential {
+        identity: "Bob".as_bytes().to_vec(),
+        public_key: bob_identity.public_key,
+    });
+    let bob_init_key_bundle =
+        UserInitKeyBundle::new(&bob_identity, bob_credential, &mut rng).unwrap();
+
+    let mut group_alice = Group::new_with_rng(
+        Box::new(alice_identity),
+        alice_credential,
+        GroupId::random(&mut rng),
+        cipher_suite,
+        Box::new(authentication_service.clone()),
+        Box::new(test_rng("alice")),
+    );
+    group_alice.set_config(config.clone());
+    let (welcome_alice_bob, add_alice_bob) = group_alice
+        .create_add(&bob_init_key_bundle.init_key)
+        .unwrap();
+    group_alice.process_add(&add_alice_bob).unwrap();
+    let encrypted_welcome = welcome_alice_bob
+        .encrypt(&bob_init_key_bundle.init_key, &mut rng)
+        .unwrap();
+    let encrypted_welcome =
+        EncryptedWelcome::decode_detached(&encrypted_welcome.encode_detached()).unwrap();
+    let welcome_alice_bob = encrypted_welcome.decrypt(&bob_init_key_bundle).unwrap();
+    let mut group_bob = Group::join(
+        Box::new(bob_identity),
+        welcome_alice_bob,
+        config,
+        Box::new(authentication_service),
+    )
+    .unwrap();
+    group_bob.set_rng(Box::new(test_rng("bob")));
+    (group_alice, group_bob)
 }
 
 #[test]
 fn alice_bob_charlie_walk_into_a_group() {
+    use utils::*;
+
+    let mut rng = test_rng("alice_bob_charlie_walk_into_a_group");
+
     // Define identities
-    let alice_identity = Identity::random();
-    let bob_identity = Identity::random();
-    let charlie_identity = Identity::random();
+    let alice_identity = Identity::random(&mut rng);
+    let bob_identity = Identity::random(&mut rng);
+    let charlie_identity = Identity::random(&mut rng);
 
-    let alice_credential = BasicCredential {
+    let alice_credential = Credential::Basic(BasicCredential {
         identity: "Alice".as_bytes().to_vec(),
         public_key: alice_identity.public_key,
-    };
-    let bob_credential = BasicCredential {
+    });
+    let bob_credential = Credential::Basic(BasicCredential {
         identity: "Bob".as_bytes().to_vec(),
         public_key: bob_identity.public_key,
-    };
-    let charlie_credential = BasicCredential {
+    });
+    let charlie_credential = Credential::Basic(BasicCredential {
         identity: "Charlie".as_bytes().to_vec(),
         public_key: charlie_identity.public_key,
-    };
+    });
 
     // Generate UserInitKeys
-    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let bob_init_key_bundle =
+        UserInitKeyBundle::new(&bob_identity, bob_credential.clone(), &mut rng).unwrap();
     let bob_init_key = bob_init_key_bundle.init_key.clone();
 
-    let charlie_init_key_bundle = UserInitKeyBundle::new(&charlie_identity);
+    let charlie_init_key_bundle =
+        UserInitKeyBundle::new(&charlie_identity, charlie_credential.clone(), &mut rng).unwrap();
     let charlie_init_key = charlie_init_key_bundle.init_key.clone();
 
     // Create a group with Alice
-    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let mut group_alice = Group::new_with_rng(
+        Box::new(alice_identity),
+        alice_credential,
+        GroupId::random(&mut rng),
+        &SUITE_CURVE25519_AES128GCM_SHA256,
+        Box::new(AllowAll),
+        Box::new(test_rng("alice")),
+    );
 
     // Alice adds Bob
-    let (welcome_alice_bob, add_alice_bob) = group_alice.create_add(bob_credential, &bob_init_key);
-    group_alice.process_add(&add_alice_bob);
+    let (welcome_alice_bob, add_alice_bob) = group_alice.create_add(&bob_init_key).unwrap();
+    group_alice.process_add(&add_alice_bob).unwrap();
+
+    // The Welcome travels encrypted to Bob's init key
+    let encrypted_welcome = welcome_alice_bob.encrypt(&bob_init_key, &mut rng).unwrap();
+    let encrypted_welcome =
+        EncryptedWelcome::decode_detached(&encrypted_welcome.encode_detached()).unwrap();
+    assert!(encrypted_welcome.decrypt(&charlie_init_key_bundle).is_err());
+    let welcome_alice_bob = encrypted_welcome.decrypt(&bob_init_key_bundle).unwrap();
+
+    // An init key without a key for the suite of the group cannot be used
+    let p256_init_key = UserInitKey::new(
+        &[AES128GCM_P256_SHA256],
+        &[bob_init_key
+            .get_init_key(AES128GCM_P256_SHA256)
+            .unwrap()
+            .clone()],
+        bob_credential.clone(),
+        &bob_identity,
+    )
+    .unwrap();
+    assert_eq!(
+        welcome_alice_bob.encrypt(&p256_init_key, &mut rng).err(),
+        Some(WelcomeError::UnsupportedCipherSuite)
+    );
 
-    let mut group_bob = Group::new_from_welcome(bob_identity, &welcome_alice_bob);
-    assert_eq!(group_alice.get_init_secret(), group_bob.get_init_secret());
+    // Only the joiner secret is sent, Bob derives the other secrets
+    let welcome_bytes = welcome_alice_bob.encode_detached();
+    let epoch_secrets = group_alice.epoch_secrets.as_ref().unwrap();
+    for secret in &[
+        &epoch_secrets.exporter_secret,
+        &epoch_secrets.resumption_psk,
+        &epoch_secrets.encryption_secret,
+    ] {
+        assert!(!welcome_bytes
+            .windows(secret.len())
+            .any(|window| window == &secret[..]));
+    }
+
+    let mut group_bob = Group::join(
+        Box::new(bob_identity),
+        welcome_alice_bob,
+        GroupConfig::default(),
+        Box::new(AllowAll),
+    )
+    .unwrap();
+    group_bob.set_rng(Box::new(test_rng("bob")));
+    assert_ct_eq(group_alice.get_init_secret(), group_bob.get_init_secret());
+    assert_ct_eq(
+        group_alice.epoch_secrets.as_ref().unwrap(),
+        group_bob.epoch_secrets.as_ref().unwrap(),
+    );
 
     // Bob updates
     let update_bob = group_bob.create_update();
-    group_bob.process_update(1, &update_bob);
-    group_alice.process_update(1, &update_bob);
-    assert_eq!(group_alice.get_init_secret(), group_bob.get_init_secret());
+    group_bob.process_update(1, &update_bob).unwrap();
+    group_alice.process_update(1, &update_bob).unwrap();
+    assert_ct_eq(group_alice.get_init_secret(), group_bob.get_init_secret());
 
     // Alice updates
     let update_alice = group_alice.create_update();
-    group_alice.process_update(0, &update_alice);
-    group_bob.process_update(0, &update_alice);
+    group_alice.process_update(0, &update_alice).unwrap();
+    group_bob.process_update(0, &update_alice).unwrap();
 
     // Bob adds Charlie
-    let (welcome_bob_charlie, add_bob_charlie) =
-        group_bob.create_add(charlie_credential, &charlie_init_key);
-    let mut group_charlie = Group::new_from_welcome(charlie_identity, &welcome_bob_charlie);
+    let (welcome_bob_charlie, add_bob_charlie) = group_bob.create_add(&charlie_init_key).unwrap();
+    let mut group_charlie = Group::join(
+        Box::new(charlie_identity),
+        welcome_bob_charlie,
+        GroupConfig::default(),
+        Box::new(AllowAll),
+    )
+    .unwrap();
+    group_charlie.set_rng(Box::new(test_rng("charlie")));
 
-    group_alice.process_add(&add_bob_charlie);
-    assert_eq!(
+    group_alice.process_add(&add_bob_charlie).unwrap();
+    assert_ct_eq(
         group_alice.get_init_secret(),
-        group_charlie.get_init_secret()
+        group_charlie.get_init_secret(),
     );
 
-    group_bob.process_add(&add_bob_charlie);
-    assert_eq!(group_bob.get_init_secret(), group_charlie.get_init_secret());
-    assert_eq!(group_alice.get_init_secret(), group_bob.get_init_secret());
+    group_bob.process_add(&add_bob_charlie).unwrap();
+    assert_ct_eq(group_bob.get_init_secret(), group_charlie.get_init_secret());
+    assert_ct_eq(group_alice.get_init_secret(), group_bob.get_init_secret());
+
+    // Everyone has the credentials the joiners signed
+    let roster = group_charlie.get_members();
+    assert_eq!(roster[1], bob_credential);
+    assert_eq!(roster[2], charlie_credential);
+    assert_eq!(group_alice.get_members(), roster);
+    assert_eq!(group_bob.get_members(), roster);
 
     // Charlie updates
     let update_charlie = group_charlie.create_update();
-    group_alice.process_update(2, &update_charlie);
-    group_bob.process_update(2, &update_charlie);
-    group_charlie.process_update(2, &update_charlie);
+    group_alice.process_update(2, &update_charlie).unwrap();
+    group_bob.process_update(2, &update_charlie).unwrap();
+    group_charlie.process_update(2, &update_charlie).unwrap();
 
     // Alice updates
     let update_alice = group_alice.create_update();
-    group_alice.process_update(0, &update_alice);
-    group_bob.process_update(0, &update_alice);
-    group_charlie.process_update(0, &update_alice);
-    assert_eq!(group_alice.get_init_secret(), group_bob.get_init_secret());
-    assert_eq!(
+    group_alice.process_update(0, &update_alice).unwrap();
+    group_bob.process_update(0, &update_alice).unwrap();
+    group_charlie.process_update(0, &update_alice).unwrap();
+    assert_ct_eq(group_alice.get_init_secret(), group_bob.get_init_secret());
+    assert_ct_eq(
         group_alice.get_init_secret(),
-        group_charlie.get_init_secret()
+        group_charlie.get_init_secret(),
     );
 
     // Charlie removes Bob
     let remove_charlie_bob = group_charlie.create_remove(1);
-    group_alice.process_remove(&remove_charlie_bob);
-    group_bob.process_remove(&remove_charlie_bob);
-    group_charlie.process_remove(&remove_charlie_bob);
+    group_alice.process_remove(&remove_charlie_bob).unwrap();
+    group_bob.process_remove(&remove_charlie_bob).unwrap();
+    group_charlie.process_remove(&remove_charlie_bob).unwrap();
 
+    assert_ct_eq(
+        group_alice.get_init_secret(),
+        group_charlie.get_init_secret(),
+    );
+
+    assert!(!group_alice
+        .get_init_secret()
+        .ct_eq(group_bob.get_init_secret()));
+}
+
+#[test]
+fn alice_bob_exchange_application_messages() {
+    use crypto::aesgcm;
+    use utils::*;
+
+    let mut rng = test_rng("alice_bob_exchange_application_messages");
+    let config = GroupConfig {
+        padding: PaddingPolicy::PowerOfTwo(4096),
+        ratchet: RatchetConfig::default(),
+        handshake_history: 8,
+        allow_mixed_signature_schemes: false,
+        trust_store: TrustStore::default(),
+    };
+    let (mut group_alice, mut group_bob) =
+        two_member_group(&SUITE_CURVE25519_AES128GCM_SHA256, config, AllowAll);
+
+    // Alice sends two messages to Bob
+    for content in &["Hello Bob", "How are you?"] {
+        let message = group_alice
+            .create_application_message(content.as_bytes())
+            .unwrap();
+        // Padded to a power of two, plus the AEAD tag
+        assert!((message.encrypted_content.len() - aesgcm::TAGBYTES).is_power_of_two());
+        let message = ApplicationMessage::decode_detached(&message.encode_detached()).unwrap();
+        let plaintext = group_bob.process_application_message(&message).unwrap();
+        assert_eq!(plaintext, content.as_bytes());
+    }
+
+    // Messages overtaken by later ones can still be decrypted
+    let first = group_alice.create_application_message(b"first").unwrap();
+    let second = group_alice.create_application_message(b"second").unwrap();
+    assert_eq!(
+        group_bob.process_application_message(&second).unwrap(),
+        b"second"
+    );
+    assert_eq!(
+        group_bob.process_application_message(&first).unwrap(),
+        b"first"
+    );
+
+    // A tampered message does not use up the generation of the genuine one
+    for content in &["in order", "overtaken"] {
+        let message = group_alice
+            .create_application_message(content.as_bytes())
+            .unwrap();
+        if *content == "overtaken" {
+            let later = group_alice.create_application_message(b"later").unwrap();
+            group_bob.process_application_message(&later).unwrap();
+        }
+        let mut tampered = ApplicationMessage::decode_detached(&message.encode_detached()).unwrap();
+        tampered.encrypted_content[0] ^= 1;
+        assert!(group_bob.process_application_message(&tampered).is_err());
+        assert_eq!(
+            group_bob.process_application_message(&message).unwrap(),
+            content.as_bytes()
+        );
+    }
+
+    // Replayed messages are rejected, even after the group was persisted
+    let mut group_bob = Group::decode_detached(&group_bob.encode_detached()).unwrap();
+    group_bob.set_rng(Box::new(test_rng("bob decoded")));
+    match group_bob.process_application_message(&first) {
+        Err(ApplicationMessageError::Replay) => (),
+        _ => panic!("Replayed message was not detected"),
+    }
+
+    // The identity key is not persisted with the group
+    let identity_bytes = test_identity("Bob", ED25519).encode_detached();
+    let private_key = &identity_bytes[identity_bytes.len() - ED25519SECRETKEYBYTES..];
+    assert!(!group_bob
+        .encode_detached()
+        .windows(ED25519SECRETKEYBYTES)
+        .any(|window| window == private_key));
+    match group_bob.create_application_message(b"Hi Alice") {
+        Err(ApplicationMessageError::SignerError(SignerError::NotAttached)) => (),
+        _ => panic!("Signed without a signer"),
+    }
+    assert_eq!(
+        group_bob.set_signer(Box::new(Identity::random(&mut rng))),
+        Err(SignerError::KeyMismatch)
+    );
+    group_bob
+        .set_signer(Box::new(test_identity("Bob", ED25519)))
+        .unwrap();
+
+    // Bob answers with the padding he joined with, the sender is only
+    // recovered from the encrypted sender data
+    let message = group_bob.create_application_message(b"Hi Alice").unwrap();
+    assert!((message.encrypted_content.len() - aesgcm::TAGBYTES).is_power_of_two());
+    let sender_data = message
+        .decrypt_sender_data(
+            group_alice.get_cipher_suite(),
+            &group_alice
+                .epoch_secrets
+                .as_ref()
+                .unwrap()
+                .sender_data_secret,
+        )
+        .unwrap();
+    assert_eq!(sender_data.sender, 1);
+    assert_eq!(sender_data.generation, 1);
+    let plaintext = group_alice.process_application_message(&message).unwrap();
+    assert_eq!(plaintext, b"Hi Alice");
+
+    // Messages from a previous epoch cannot be decrypted anymore
+    let update_bob = group_bob.create_update();
+    group_bob.process_update(1, &update_bob).unwrap();
+    group_alice.process_update(1, &update_bob).unwrap();
+    assert!(group_alice.process_application_message(&message).is_err());
+}
+
+#[test]
+fn duplicate_handshakes_are_detected() {
+    use utils::*;
+
+    let (mut group_alice, mut group_bob) = two_member_group(
+        &SUITE_CURVE25519_AES128GCM_SHA256,
+        GroupConfig::default(),
+        AllowAll,
+    );
+
+    let update_alice = group_alice.create_update();
+    let handshake = group_alice
+        .create_handshake(GroupOperation {
+            msg_type: GroupOperationType::Update,
+            group_operation: GroupOperationValue::Update(update_alice),
+        })
+        .unwrap();
+
+    // A handshake from a previous epoch that was never seen is stale
+    let update_bob = group_bob.create_update();
+    let stale_handshake = group_bob
+        .create_handshake(GroupOperation {
+            msg_type: GroupOperationType::Update,
+            group_operation: GroupOperationValue::Update(update_bob),
+        })
+        .unwrap();
+
+    assert_eq!(group_alice.process_handshake(handshake.clone()), Ok(()));
+    assert_eq!(group_bob.process_handshake(handshake.clone()), Ok(()));
+    assert_ct_eq(group_alice.get_init_secret(), group_bob.get_init_secret());
+
+    // Delivering the same handshake again does not change the group
+    assert_eq!(
+        group_bob.process_handshake(handshake.clone()),
+        Err(HandshakeError::Duplicate)
+    );
+    assert_ct_eq(group_alice.get_init_secret(), group_bob.get_init_secret());
+
+    // The record of processed handshakes survives persistence
+    let mut group_bob = Group::decode_detached(&group_bob.encode_detached()).unwrap();
+    assert_eq!(
+        group_bob.process_handshake(handshake.clone()),
+        Err(HandshakeError::Duplicate)
+    );
     assert_eq!(
+        group_alice.process_handshake(stale_handshake),
+        Err(HandshakeError::StaleEpoch)
+    );
+
+    // Handshakes with a bad signature are invalid
+    let update_alice = group_alice.create_update();
+    let mut forged_handshake = group_alice
+        .create_handshake(GroupOperation {
+            msg_type: GroupOperationType::Update,
+            group_operation: GroupOperationValue::Update(update_alice),
+        })
+        .unwrap();
+    forged_handshake.signer_index = 1;
+    assert_eq!(
+        group_bob.process_handshake(forged_handshake),
+        Err(HandshakeError::Invalid)
+    );
+
+    // A history reaching back past the first epoch keeps every digest
+    let mut config = group_alice.get_config().clone();
+    config.handshake_history = u32::MAX;
+    group_alice.set_config(config);
+    for _ in 0..2 {
+        let update_alice = group_alice.create_update();
+        let handshake = group_alice
+            .create_handshake(GroupOperation {
+                msg_type: GroupOperationType::Update,
+                group_operation: GroupOperationValue::Update(update_alice),
+            })
+            .unwrap();
+        assert_eq!(group_alice.process_handshake(handshake), Ok(()));
+    }
+    // The forged handshake counts as well, Alice created it
+    assert_eq!(group_alice.processed_handshakes.len(), 4);
+    let short_digest = HandshakeDigest(vec![0x2A; 16]);
+    assert!(HandshakeDigest::decode_detached(&short_digest.encode_detached()).is_err());
+
+    // Our own handshake is a duplicate when the delivery service echoes it,
+    // also when it was applied directly
+    let update_alice = group_alice.create_update();
+    let own_handshake = group_alice
+        .create_handshake(GroupOperation {
+            msg_type: GroupOperationType::Update,
+            group_operation: GroupOperationValue::Update(update_alice.clone()),
+        })
+        .unwrap();
+    group_alice.process_update(0, &update_alice).unwrap();
+    assert_eq!(
+        group_alice.process_handshake(own_handshake),
+        Err(HandshakeError::Duplicate)
+    );
+}
+
+#[test]
+fn alice_bob_charlie_use_p256() {
+    use utils::*;
+
+    let mut rng = test_rng("alice_bob_charlie_use_p256");
+    let charlie_identity = Identity::random_for_scheme(ECDSA_SECP256R1_SHA256, &mut rng).unwrap();
+    let charlie_credential = Credential::Basic(BasicCredential {
+        identity: "Charlie".as_bytes().to_vec(),
+        public_key: charlie_identity.public_key,
+    });
+    let charlie_init_key = UserInitKeyBundle::new(&charlie_identity, charlie_credential, &mut rng)
+        .unwrap()
+        .init_key;
+
+    let (mut group_alice, mut group_bob) = two_member_group(
+        &SUITE_P256_AES128GCM_SHA256,
+        GroupConfig::default(),
+        AllowAll,
+    );
+    assert_eq!(group_bob.get_cipher_suite(), &SUITE_P256_AES128GCM_SHA256);
+    assert_ct_eq(group_alice.get_init_secret(), group_bob.get_init_secret());
+
+    // Path secrets are encrypted to P-256 node keys
+    let update_bob = group_bob.create_update();
+    let update_bob = Update::decode_detached(&update_bob.encode_detached()).unwrap();
+    assert!(update_bob
+        .nodes
+        .iter()
+        .all(|key| key.as_slice().len() == P256PUBLICKEYBYTES));
+    let handshake = group_bob
+        .create_handshake(GroupOperation {
+            msg_type: GroupOperationType::Update,
+            group_operation: GroupOperationValue::Update(update_bob.clone()),
+        })
+        .unwrap();
+    assert_eq!(handshake.algorithm, ECDSA_SECP256R1_SHA256);
+    let handshake = Handshake::decode_detached(&handshake.encode_detached()).unwrap();
+    group_bob.process_update(1, &update_bob).unwrap();
+    assert_eq!(group_alice.process_handshake(handshake), Ok(()));
+    assert_ct_eq(group_alice.get_init_secret(), group_bob.get_init_secret());
+
+    let (welcome_bob_charlie, add_bob_charlie) = group_bob.create_add(&charlie_init_key).unwrap();
+    let mut group_charlie = Group::join(
+        Box::new(charlie_identity),
+        welcome_bob_charlie,
+        GroupConfig::default(),
+        Box::new(AllowAll),
+    )
+    .unwrap();
+    group_charlie.set_rng(Box::new(test_rng("charlie")));
+    group_alice.process_add(&add_bob_charlie).unwrap();
+    group_bob.process_add(&add_bob_charlie).unwrap();
+    assert_ct_eq(
+        group_alice.get_init_secret(),
+        group_charlie.get_init_secret(),
+    );
+
+    let update_charlie = group_charlie.create_update();
+    group_alice.process_update(2, &update_charlie).unwrap();
+    group_bob.process_update(2, &update_charlie).unwrap();
+    group_charlie.process_update(2, &update_charlie).unwrap();
+    assert_ct_eq(group_alice.get_init_secret(), group_bob.get_init_secret());
+    assert_ct_eq(
         group_alice.get_init_secret(),
-        group_charlie.get_init_secret()
+        group_charlie.get_init_secret(),
+    );
+
+    let message = group_charlie.create_application_message(b"P-256").unwrap();
+    assert_eq!(
+        group_alice.process_application_message(&message).unwrap(),
+        b"P-256"
+    );
+    let group_bob = Group::decode_detached(&group_bob.encode_detached()).unwrap();
+    assert_eq!(group_bob.get_cipher_suite(), &SUITE_P256_AES128GCM_SHA256);
+}
+
+#[test]
+fn alice_bob_use_other_cipher_suites() {
+    use utils::*;
+
+    for cipher_suite in [
+        &SUITE_CURVE25519_CHACHA20POLY1305_SHA256,
+        &SUITE_CURVE25519_AES256GCM_SHA512,
+    ]
+    .iter()
+    {
+        let (mut group_alice, mut group_bob) =
+            two_member_group(cipher_suite, GroupConfig::default(), AllowAll);
+        assert_eq!(group_bob.get_cipher_suite(), *cipher_suite);
+
+        let update_bob = group_bob.create_update();
+        group_bob.process_update(1, &update_bob).unwrap();
+        group_alice.process_update(1, &update_bob).unwrap();
+        assert_ct_eq(group_alice.get_init_secret(), group_bob.get_init_secret());
+
+        // Secrets have the hash length of the suite
+        let epoch_secrets = group_bob.epoch_secrets.as_ref().unwrap();
+        assert_eq!(
+            epoch_secrets.encryption_secret.len(),
+            cipher_suite.kdf.hash_length()
+        );
+        assert_ct_eq(
+            group_alice.epoch_secrets.as_ref().unwrap(),
+            group_bob.epoch_secrets.as_ref().unwrap(),
+        );
+
+        let message = group_alice.create_application_message(b"Hi Bob").unwrap();
+        let message = ApplicationMessage::decode_detached(&message.encode_detached()).unwrap();
+        assert_eq!(
+            group_bob.process_application_message(&message).unwrap(),
+            b"Hi Bob"
+        );
+        let group_bob = Group::decode_detached(&group_bob.encode_detached()).unwrap();
+        assert_eq!(group_bob.get_cipher_suite(), *cipher_suite);
+    }
+}
+
+#[test]
+fn mixed_signature_schemes_need_to_be_allowed() {
+    use utils::*;
+
+    let mut rng = test_rng("mixed_signature_schemes_need_to_be_allowed");
+    let alice_identity = Identity::random(&mut rng);
+    let bob_identity = Identity::random_for_scheme(ECDSA_SECP256R1_SHA256, &mut rng).unwrap();
+
+    let alice_credential = Credential::Basic(BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    });
+    let bob_credential = Credential::Basic(BasicCredential {
+        identity: "Bob".as_bytes().to_vec(),
+        public_key: bob_identity.public_key,
+    });
+    let bob_init_key = UserInitKeyBundle::new(&bob_identity, bob_credential.clone(), &mut rng)
+        .unwrap()
+        .init_key;
+
+    let mut group_alice = Group::new_with_rng(
+        Box::new(alice_identity),
+        alice_credential,
+        GroupId::random(&mut rng),
+        &SUITE_CURVE25519_AES128GCM_SHA256,
+        Box::new(AllowAll),
+        Box::new(test_rng("alice")),
+    );
+    assert!(group_alice.is_signature_scheme_allowed(ED25519));
+    assert!(!group_alice.is_signature_scheme_allowed(ECDSA_SECP256R1_SHA256));
+
+    let mut config = group_alice.get_config().clone();
+    config.allow_mixed_signature_schemes = true;
+    group_alice.set_config(config);
+    let (welcome_alice_bob, add_alice_bob) = group_alice.create_add(&bob_init_key).unwrap();
+    group_alice.process_add(&add_alice_bob).unwrap();
+    let mut group_bob = Group::join(
+        Box::new(bob_identity),
+        welcome_alice_bob,
+        GroupConfig::default(),
+        Box::new(AllowAll),
+    )
+    .unwrap();
+    group_bob.set_rng(Box::new(test_rng("bob")));
+    let mut config = group_bob.get_config().clone();
+    config.allow_mixed_signature_schemes = true;
+    group_bob.set_config(config);
+
+    // Each member signs with their own scheme
+    let update_bob = group_bob.create_update();
+    let handshake = group_bob
+        .create_handshake(GroupOperation {
+            msg_type: GroupOperationType::Update,
+            group_operation: GroupOperationValue::Update(update_bob.clone()),
+        })
+        .unwrap();
+    group_bob.process_update(1, &update_bob).unwrap();
+
+    // Without the policy, the ECDSA handshake is rejected
+    let mixed_config = group_alice.get_config().clone();
+    group_alice.set_config(GroupConfig::default());
+    assert_eq!(
+        group_alice.process_handshake(handshake.clone()),
+        Err(HandshakeError::Invalid)
+    );
+
+    group_alice.set_config(mixed_config);
+    assert_eq!(group_alice.process_handshake(handshake), Ok(()));
+    assert_ct_eq(group_alice.get_init_secret(), group_bob.get_init_secret());
+
+    let update_alice = group_alice.create_update();
+    let handshake = group_alice
+        .create_handshake(GroupOperation {
+            msg_type: GroupOperationType::Update,
+            group_operation: GroupOperationValue::Update(update_alice.clone()),
+        })
+        .unwrap();
+    assert_eq!(handshake.algorithm, ED25519);
+    group_alice.process_update(0, &update_alice).unwrap();
+    assert_eq!(group_bob.process_handshake(handshake), Ok(()));
+
+    let message = group_bob.create_application_message(b"ECDSA").unwrap();
+    assert_eq!(
+        group_alice.process_application_message(&message).unwrap(),
+        b"ECDSA"
+    );
+    let message = group_alice.create_application_message(b"Ed25519").unwrap();
+    assert_eq!(
+        group_bob.process_application_message(&message).unwrap(),
+        b"Ed25519"
+    );
+}
+
+#[test]
+fn invalid_updates_are_rejected() {
+    use utils::*;
+
+    let mut rng = test_rng("invalid_updates_are_rejected");
+    let alice_identity = Identity::random(&mut rng);
+    let bob_identity = test_identity("Bob", ED25519);
+    let alice_credential = Credential::Basic(BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    });
+    let bob_credential = Credential::Basic(BasicCredential {
+        identity: "Bob".as_bytes().to_vec(),
+        public_key: bob_identity.public_key,
+    });
+    let bob_init_key = UserInitKeyBundle::new(&bob_identity, bob_credential.clone(), &mut rng)
+        .unwrap()
+        .init_key;
+
+    let mut group_alice = Group::new_with_rng(
+        Box::new(alice_identity),
+        alice_credential,
+        GroupId::random(&mut rng),
+        &SUITE_CURVE25519_AES128GCM_SHA256,
+        Box::new(AllowAll),
+        Box::new(test_rng("alice")),
+    );
+    // An init key with a bad signature is not added
+    let mut forged_init_key = bob_init_key.clone();
+    forged_init_key.signature = Identity::random(&mut rng).sign(&bob_init_key.unsigned_payload());
+    assert_eq!(
+        group_alice.create_add(&forged_init_key).err(),
+        Some(HandshakeError::InvalidInitKey)
+    );
+
+    let (welcome_alice_bob, add_alice_bob) = group_alice.create_add(&bob_init_key).unwrap();
+    group_alice.process_add(&add_alice_bob).unwrap();
+
+    // Malformed Welcomes are rejected
+    let copy_welcome = || Welcome::decode_detached(&welcome_alice_bob.encode_detached()).unwrap();
+    let mut truncated_welcome = copy_welcome();
+    truncated_welcome.tree.pop();
+    assert_eq!(
+        Group::join(
+            Box::new(test_identity("Bob", ED25519)),
+            truncated_welcome,
+            GroupConfig::default(),
+            Box::new(AllowAll)
+        )
+        .err(),
+        Some(HandshakeError::InvalidWelcome)
+    );
+    let mut short_secret_welcome = copy_welcome();
+    short_secret_welcome.leaf_secret = NodeSecret::from_bytes(&[0x2A]);
+    assert_eq!(
+        Group::join(
+            Box::new(test_identity("Bob", ED25519)),
+            short_secret_welcome,
+            GroupConfig::default(),
+            Box::new(AllowAll)
+        )
+        .err(),
+        Some(HandshakeError::InvalidWelcome)
+    );
+    let mut low_order_welcome = copy_welcome();
+    low_order_welcome.tree[0] = KemPublicKey::from_slice(&[0u8; 32]);
+    assert_eq!(
+        Group::join(
+            Box::new(test_identity("Bob", ED25519)),
+            low_order_welcome,
+            GroupConfig::default(),
+            Box::new(AllowAll)
+        )
+        .err(),
+        Some(HandshakeError::TreeError(TreeError::InvalidPublicKey))
+    );
+    assert_eq!(
+        Group::join(
+            Box::new(Identity::random(&mut rng)),
+            copy_welcome(),
+            GroupConfig::default(),
+            Box::new(AllowAll)
+        )
+        .err(),
+        Some(HandshakeError::InvalidWelcome)
+    );
+
+    let mut group_bob = Group::join(
+        Box::new(bob_identity),
+        welcome_alice_bob,
+        GroupConfig::default(),
+        Box::new(AllowAll),
+    )
+    .unwrap();
+    group_bob.set_rng(Box::new(test_rng("bob")));
+    let update_bob = group_bob.create_update();
+
+    // A low order point as node key
+    let mut low_order_update = update_bob.clone();
+    low_order_update.nodes[0] = KemPublicKey::from_slice(&[0u8; 32]);
+    assert_eq!(
+        group_alice.process_update(1, &low_order_update),
+        Err(HandshakeError::TreeError(TreeError::InvalidPublicKey))
+    );
+
+    // A path secret that does not decrypt
+    let mut tampered_update = update_bob.clone();
+    tampered_update.path[0].sealed_box[0] ^= 1;
+    assert!(group_alice.process_update(1, &tampered_update).is_err());
+
+    // A truncated path
+    let mut truncated_update = update_bob.clone();
+    truncated_update.path.clear();
+    assert_eq!(
+        group_alice.process_update(1, &truncated_update),
+        Err(HandshakeError::TreeError(TreeError::InvalidPath))
+    );
+
+    // The group state is untouched and the genuine update still applies
+    group_bob.process_update(1, &update_bob).unwrap();
+    group_alice.process_update(1, &update_bob).unwrap();
+    assert_ct_eq(group_alice.get_init_secret(), group_bob.get_init_secret());
+}
+
+#[test]
+fn malformed_handshakes_are_rejected() {
+    use utils::*;
+
+    let mut rng = test_rng("malformed_handshakes_are_rejected");
+    let (mut group_alice, mut group_bob) = two_member_group(
+        &SUITE_CURVE25519_AES128GCM_SHA256,
+        GroupConfig::default(),
+        AllowAll,
+    );
+
+    // A path secret too short to derive node keys from
+    let mut short_secret_update = group_bob.create_update();
+    let alice_leaf_key = group_bob.tree.nodes[0].dh_public_key.clone().unwrap();
+    short_secret_update.path[0] = group_bob
+        .cipher_suite
+        .kem
+        .encrypt(
+            &alice_leaf_key,
+            PATH_SECRET_INFO,
+            &path_secret_aad(&group_bob.path_secret_context(), 0),
+            &[0x2A],
+            &mut rng,
+        )
+        .unwrap();
+    assert_eq!(
+        group_alice.process_update(1, &short_secret_update),
+        Err(HandshakeError::TreeError(TreeError::InvalidPath))
+    );
+
+    // Senders and removed members outside of the group
+    let update_bob = group_bob.create_update();
+    assert_eq!(
+        group_alice.process_update(2, &update_bob),
+        Err(HandshakeError::Invalid)
+    );
+    let mut remove_alice_bob = group_alice.create_remove(1);
+    remove_alice_bob.removed = 2;
+    assert_eq!(
+        group_bob.process_remove(&remove_alice_bob),
+        Err(HandshakeError::Invalid)
+    );
+
+    group_bob.process_update(1, &update_bob).unwrap();
+    group_alice.process_update(1, &update_bob).unwrap();
+    assert_ct_eq(group_alice.get_init_secret(), group_bob.get_init_secret());
+}
+
+#[test]
+fn identity_keys_are_rotated() {
+    use utils::*;
+
+    let mut rng = test_rng("identity_keys_are_rotated");
+    let (mut group_alice, mut group_bob) = two_member_group(
+        &SUITE_CURVE25519_AES128GCM_SHA256,
+        GroupConfig::default(),
+        AllowAll,
+    );
+    let bob_identity = test_identity("Bob", ED25519);
+
+    // Bob replaces his identity key, the handshake is signed with the old one
+    let new_bob_identity = test_identity("new Bob", ED25519);
+    let update_bob = group_bob
+        .create_identity_key_update(
+            Box::new(test_identity("new Bob", ED25519)),
+            Credential::Basic(BasicCredential {
+                identity: "Bob".as_bytes().to_vec(),
+                public_key: new_bob_identity.public_key,
+            }),
+        )
+        .unwrap();
+    let handshake = group_bob
+        .create_handshake(GroupOperation {
+            msg_type: GroupOperationType::Update,
+            group_operation: GroupOperationValue::Update(update_bob.clone()),
+        })
+        .unwrap();
+    assert!(bob_identity.verify(
+        &handshake.unsigned_payload(),
+        handshake.signature.as_ref().unwrap()
+    ));
+    let handshake = Handshake::decode_detached(&handshake.encode_detached()).unwrap();
+
+    // An update without valid signatures by both keys changes nothing
+    let epoch = group_alice.group_epoch;
+    let mut forged_update = update_bob.clone();
+    forged_update.identity_key.as_mut().unwrap().credential = Credential::Basic(BasicCredential {
+        identity: "Bob".as_bytes().to_vec(),
+        public_key: Identity::random(&mut rng).public_key,
+    });
+    assert_eq!(
+        group_alice.process_update(1, &forged_update),
+        Err(HandshakeError::Invalid)
+    );
+    let mut taken_update = update_bob.clone();
+    taken_update.identity_key.as_mut().unwrap().credential = Credential::Basic(BasicCredential {
+        identity: "Bob".as_bytes().to_vec(),
+        public_key: group_alice.roster[0].public_key(),
+    });
+    assert_eq!(
+        group_alice.process_update(1, &taken_update),
+        Err(HandshakeError::Invalid)
+    );
+    assert_eq!(group_alice.group_epoch, epoch);
+    assert_eq!(group_alice.roster[1].public_key(), bob_identity.public_key);
+
+    // Both keys sign the update itself, not only the handshake
+    let mut new_key_only = update_bob.clone();
+    {
+        let identity_key = new_key_only.identity_key.as_mut().unwrap();
+        identity_key.old_key_signature = identity_key.new_key_signature.clone();
+    }
+    assert_eq!(
+        group_alice.process_update(1, &new_key_only),
+        Err(HandshakeError::Invalid)
+    );
+    assert_eq!(group_alice.group_epoch, epoch);
+
+    // The signatures are bound to the sender
+    assert_eq!(
+        group_alice.process_update(0, &update_bob),
+        Err(HandshakeError::Invalid)
+    );
+
+    group_alice.process_handshake(handshake.clone()).unwrap();
+    group_bob.process_handshake(handshake).unwrap();
+    assert_ct_eq(group_alice.get_init_secret(), group_bob.get_init_secret());
+    for group in &[&group_alice, &group_bob] {
+        assert_eq!(group.roster[1].public_key(), new_bob_identity.public_key);
+    }
+
+    // Bob signs with the new key from now on
+    let message = group_bob.create_application_message(b"Hi Alice").unwrap();
+    assert_eq!(
+        group_alice.process_application_message(&message).unwrap(),
+        b"Hi Alice"
+    );
+    let update_bob = group_bob.create_update();
+    let handshake = group_bob
+        .create_handshake(GroupOperation {
+            msg_type: GroupOperationType::Update,
+            group_operation: GroupOperationValue::Update(update_bob),
+        })
+        .unwrap();
+    assert!(new_bob_identity.verify(
+        &handshake.unsigned_payload(),
+        handshake.signature.as_ref().unwrap()
+    ));
+    group_alice.process_handshake(handshake).unwrap();
+
+    // A persisted group expects the new key
+    let mut group_bob = Group::decode_detached(&group_bob.encode_detached()).unwrap();
+    assert_eq!(
+        group_bob.set_signer(Box::new(bob_identity)),
+        Err(SignerError::KeyMismatch)
+    );
+    group_bob.set_signer(Box::new(new_bob_identity)).unwrap();
+}
+
+#[test]
+fn x509_credentials_are_validated() {
+    use utils::*;
+
+    let mut rng = test_rng("x509_credentials_are_validated");
+    let alice_identity = Identity::from_pkcs8_pem(TEST_ALICE_KEY).unwrap();
+    let bob_identity = Identity::from_pkcs8_pem(TEST_BOB_KEY).unwrap();
+    let alice_chain = format!("{}{}", TEST_ALICE_CERTIFICATE, TEST_ISSUING_CA);
+    let alice_credential = Credential::X509(X509Credential::from_pem_chain(&alice_chain).unwrap());
+    let bob_chain = format!("{}{}", TEST_BOB_CERTIFICATE, TEST_ISSUING_CA);
+    let bob_credential = Credential::X509(X509Credential::from_pem_chain(&bob_chain).unwrap());
+    let bob_init_key = UserInitKeyBundle::new(&bob_identity, bob_credential.clone(), &mut rng)
+        .unwrap()
+        .init_key;
+
+    let mut group_alice = Group::new_with_rng(
+        Box::new(alice_identity),
+        alice_credential,
+        GroupId::random(&mut rng),
+        &SUITE_CURVE25519_AES128GCM_SHA256,
+        Box::new(AllowAll),
+        Box::new(test_rng("alice")),
+    );
+    let mut config = GroupConfig::default();
+    config
+        .trust_store
+        .add_anchors_from_pem(TEST_ROOT_CA)
+        .unwrap();
+    group_alice.set_config(config);
+    let (welcome_alice_bob, add_alice_bob) = group_alice.create_add(&bob_init_key).unwrap();
+
+    // Members without the anchor reject the credential
+    let trusting_config = group_alice.get_config().clone();
+    group_alice.set_config(GroupConfig::default());
+    assert_eq!(
+        group_alice.process_add(&add_alice_bob),
+        Err(HandshakeError::InvalidCredential)
+    );
+    group_alice.set_config(trusting_config);
+    // The credential is signed by the joiner
+    let mut mismatched = add_alice_bob.clone();
+    mismatched.init_key.credential = group_alice.roster[0].clone();
+    assert_eq!(
+        group_alice.process_add(&mismatched),
+        Err(HandshakeError::InvalidInitKey)
+    );
+
+    group_alice.process_add(&add_alice_bob).unwrap();
+    let mut group_bob = Group::join(
+        Box::new(bob_identity),
+        welcome_alice_bob,
+        group_alice.get_config().clone(),
+        Box::new(AllowAll),
+    )
+    .unwrap();
+    group_bob.set_rng(Box::new(test_rng("bob")));
+    assert_eq!(group_alice.get_members()[1], bob_credential);
+
+    // A basic credential with the same name does not replace the certificate
+    let new_bob_identity = Identity::random(&mut rng);
+    let new_bob_public_key = new_bob_identity.public_key;
+    let update_bob = group_bob
+        .create_identity_key_update(
+            Box::new(new_bob_identity),
+            Credential::Basic(BasicCredential {
+                identity: "bob".as_bytes().to_vec(),
+                public_key: new_bob_public_key,
+            }),
+        )
+        .unwrap();
+    assert_eq!(
+        group_alice.process_update(1, &update_bob),
+        Err(HandshakeError::InvalidCredential)
+    );
+}
+
+#[test]
+fn credentials_are_authenticated() {
+    use utils::*;
+
+    let mut rng = test_rng("credentials_are_authenticated");
+    let alice_identity = Identity::random(&mut rng);
+    let bob_identity = test_identity("Bob", ED25519);
+    let charlie_identity = Identity::random(&mut rng);
+    let alice_credential = Credential::Basic(BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    });
+    let bob_credential = Credential::Basic(BasicCredential {
+        identity: "Bob".as_bytes().to_vec(),
+        public_key: bob_identity.public_key,
+    });
+    let charlie_credential = Credential::Basic(BasicCredential {
+        identity: "Charlie".as_bytes().to_vec(),
+        public_key: charlie_identity.public_key,
+    });
+    let bob_init_key = UserInitKeyBundle::new(&bob_identity, bob_credential.clone(), &mut rng)
+        .unwrap()
+        .init_key;
+    let charlie_init_key =
+        UserInitKeyBundle::new(&charlie_identity, charlie_credential.clone(), &mut rng)
+            .unwrap()
+            .init_key;
+
+    let mut allow_list = AllowList::new();
+    allow_list.allow(b"Alice");
+    allow_list.allow_key(b"Bob", bob_identity.public_key);
+    let mut group_alice = Group::new_with_rng(
+        Box::new(alice_identity),
+        alice_credential,
+        GroupId::random(&mut rng),
+        &SUITE_CURVE25519_AES128GCM_SHA256,
+        Box::new(allow_list.clone()),
+        Box::new(test_rng("alice")),
+    );
+    assert_eq!(
+        group_alice.create_add(&charlie_init_key).err(),
+        Some(HandshakeError::AuthenticationFailed)
+    );
+    let (welcome_alice_bob, add_alice_bob) = group_alice.create_add(&bob_init_key).unwrap();
+
+    // Adds are checked by every member
+    let mut only_alice = AllowList::new();
+    only_alice.allow(b"Alice");
+    group_alice.set_authentication_service(Box::new(only_alice.clone()));
+    assert_eq!(
+        group_alice.process_add(&add_alice_bob),
+        Err(HandshakeError::AuthenticationFailed)
+    );
+    group_alice.set_authentication_service(Box::new(allow_list.clone()));
+    group_alice.process_add(&add_alice_bob).unwrap();
+
+    // The joiner checks the whole roster
+    let mut only_bob = AllowList::new();
+    only_bob.allow(b"Bob");
+    assert_eq!(
+        Group::join(
+            Box::new(test_identity("Bob", ED25519)),
+            Welcome::decode_detached(&welcome_alice_bob.encode_detached()).unwrap(),
+            GroupConfig::default(),
+            Box::new(only_bob),
+        )
+        .err(),
+        Some(HandshakeError::AuthenticationFailed)
+    );
+    let mut group_bob = Group::join(
+        Box::new(bob_identity),
+        welcome_alice_bob,
+        GroupConfig::default(),
+        Box::new(allow_list),
+    )
+    .unwrap();
+    group_bob.set_rng(Box::new(test_rng("bob")));
+    assert_ct_eq(group_alice.get_init_secret(), group_bob.get_init_secret());
+
+    // So are new credentials, Bob's key is pinned
+    let new_bob_identity = Identity::random(&mut rng);
+    let new_bob_public_key = new_bob_identity.public_key;
+    let update_bob = group_bob
+        .create_identity_key_update(
+            Box::new(new_bob_identity),
+            Credential::Basic(BasicCredential {
+                identity: "Bob".as_bytes().to_vec(),
+                public_key: new_bob_public_key,
+            }),
+        )
+        .unwrap();
+    assert_eq!(
+        group_alice.process_update(1, &update_bob),
+        Err(HandshakeError::AuthenticationFailed)
     );
 
-    assert_ne!(group_alice.get_init_secret(), group_bob.get_init_secret());
+    // A decoded group admits nobody until its service is set again
+    let mut group_alice = Group::decode_detached(&group_alice.encode_detached()).unwrap();
+    assert_eq!(
+        group_alice.process_update(1, &update_bob),
+        Err(HandshakeError::AuthenticationFailed)
+    );
+    group_alice.set_authentication_service(Box::new(AllowAll));
+    group_alice.process_update(1, &update_bob).unwrap();
+}
+
+#[test]
+fn seeded_groups_are_reproducible() {
+    use utils::*;
+
+    // Returns everything Alice and Bob put on the wire
+    fn run(seed: &str) -> Vec<Vec<u8>> {
+        let mut rng = test_rng(seed);
+        let alice_identity = Identity::random(&mut rng);
+        let bob_identity = Identity::random(&mut rng);
+        let alice_credential = Credential::Basic(BasicCredential {
+            identity: "Alice".as_bytes().to_vec(),
+            public_key: alice_identity.public_key,
+        });
+        let bob_credential = Credential::Basic(BasicCredential {
+            identity: "Bob".as_bytes().to_vec(),
+            public_key: bob_identity.public_key,
+        });
+        let bob_init_key_bundle =
+            UserInitKeyBundle::new(&bob_identity, bob_credential.clone(), &mut rng).unwrap();
+
+        let mut group_alice = Group::new_with_rng(
+            Box::new(alice_identity),
+            alice_credential,
+            GroupId::random(&mut rng),
+            &SUITE_CURVE25519_AES128GCM_SHA256,
+            Box::new(AllowAll),
+            Box::new(test_rng(&format!("{} alice", seed))),
+        );
+        let (welcome_alice_bob, add_alice_bob) = group_alice
+            .create_add(&bob_init_key_bundle.init_key)
+            .unwrap();
+        group_alice.process_add(&add_alice_bob).unwrap();
+        let encrypted_welcome = welcome_alice_bob
+            .encrypt(&bob_init_key_bundle.init_key, &mut rng)
+            .unwrap();
+        let welcome_alice_bob = encrypted_welcome.decrypt(&bob_init_key_bundle).unwrap();
+        let mut group_bob = Group::join(
+            Box::new(bob_identity),
+            welcome_alice_bob,
+            GroupConfig::default(),
+            Box::new(AllowAll),
+        )
+        .unwrap();
+        group_bob.set_rng(Box::new(test_rng(&format!("{} bob", seed))));
+
+        let update_bob = group_bob.create_update();
+        let handshake = group_bob
+            .create_handshake(GroupOperation {
+                msg_type: GroupOperationType::Update,
+                group_operation: GroupOperationValue::Update(update_bob.clone()),
+            })
+            .unwrap();
+        group_bob.process_update(1, &update_bob).unwrap();
+        group_alice.process_handshake(handshake.clone()).unwrap();
+        let message = group_alice.create_application_message(b"Hi Bob").unwrap();
+        assert_eq!(
+            group_bob.process_application_message(&message).unwrap(),
+            b"Hi Bob"
+        );
+
+        vec![
+            bob_init_key_bundle.init_key.encode_detached(),
+            add_alice_bob.encode_detached(),
+            encrypted_welcome.encode_detached(),
+            handshake.encode_detached(),
+            message.encode_detached(),
+            group_alice.encode_detached(),
+        ]
+    }
+
+    let transcript = run("seed");
+    assert_eq!(run("seed"), transcript);
+    let other = run("other seed");
+    for (a, b) in transcript.iter().zip(other.iter()) {
+        assert_ne!(a, b);
+    }
 }
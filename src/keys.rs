@@ -15,20 +15,66 @@
 // along with this program. If not, see http://www.gnu.org/licenses/.
 
 use codec::*;
-use sodiumoxide::crypto::scalarmult;
-use sodiumoxide::crypto::sign::ed25519;
-use sodiumoxide::randombytes;
+use crypto::ciphersuite::*;
+use crypto::hkdf::HashAlgorithm;
+use crypto::provider::*;
+use crypto::rng::CryptoRng;
+use p256;
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use signer::*;
+use std::fmt;
 use tree::*;
 use utils::*;
+use x509::X509Credential;
 
-pub const X25519PRIVATEKEYBYTES: usize = scalarmult::SCALARBYTES;
-pub const X25519PUBLICKEYBYTES: usize = scalarmult::GROUPELEMENTBYTES;
+pub const X25519PRIVATEKEYBYTES: usize = X25519_BYTES;
+pub const X25519PUBLICKEYBYTES: usize = X25519_BYTES;
 
-pub const P256PUBLICKEYBYTES: usize = 32;
+pub const P256PRIVATEKEYBYTES: usize = 32;
+pub const P256PUBLICKEYBYTES: usize = 65;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct Zero {}
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct InvalidKey {}
+
+// Encodings of the points of order 1, 2, 4 and 8, including the
+// non-canonical ones (p - 1, p, p + 1). The most significant bit is ignored.
+const X25519_LOW_ORDER_POINTS: [[u8; X25519PUBLICKEYBYTES]; 7] = [
+    [0x00; 32],
+    [
+        0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00,
+    ],
+    [
+        0xe0, 0xeb, 0x7a, 0x7c, 0x3b, 0x41, 0xb8, 0xae, 0x16, 0x56, 0xe3, 0xfa, 0xf1, 0x9f, 0xc4,
+        0x6a, 0xda, 0x09, 0x8d, 0xeb, 0x9c, 0x32, 0xb1, 0xfd, 0x86, 0x62, 0x05, 0x16, 0x5f, 0x49,
+        0xb8, 0x00,
+    ],
+    [
+        0x5f, 0x9c, 0x95, 0xbc, 0xa3, 0x50, 0x8c, 0x24, 0xb1, 0xd0, 0xb1, 0x55, 0x9c, 0x83, 0xef,
+        0x5b, 0x04, 0x44, 0x5c, 0xc4, 0x58, 0x1c, 0x8e, 0x86, 0xd8, 0x22, 0x4e, 0xdd, 0xd0, 0x9f,
+        0x11, 0x57,
+    ],
+    [
+        0xec, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0x7f,
+    ],
+    [
+        0xed, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0x7f,
+    ],
+    [
+        0xee, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0x7f,
+    ],
+];
+
 #[derive(Hash, PartialEq, Clone, Copy, Debug)]
 pub struct X25519PublicKey([u8; X25519PUBLICKEYBYTES]);
 
@@ -38,6 +84,15 @@ impl X25519PublicKey {
         inner.copy_from_slice(&bytes[..X25519PUBLICKEYBYTES]);
         X25519PublicKey(inner)
     }
+    pub fn to_bytes(&self) -> [u8; X25519PUBLICKEYBYTES] {
+        self.0
+    }
+    // Low order points would force the shared secret to a known value
+    pub fn is_valid(&self) -> bool {
+        let mut key = self.0;
+        key[X25519PUBLICKEYBYTES - 1] &= 0x7f;
+        !X25519_LOW_ORDER_POINTS.contains(&key)
+    }
 }
 
 impl Codec for X25519PublicKey {
@@ -45,40 +100,64 @@ impl Codec for X25519PublicKey {
         encode_vec_u16(buffer, &self.0);
     }
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
-        let mut value = [0u8; X25519PUBLICKEYBYTES];
-        value.clone_from_slice(&decode_vec_u16(cursor)?[..X25519PUBLICKEYBYTES]);
-        Ok(X25519PublicKey(value))
+        let bytes = decode_vec_u16(cursor)?;
+        if bytes.len() != X25519PUBLICKEYBYTES {
+            return Err(CodecError::DecodingError);
+        }
+        let public_key = X25519PublicKey::from_slice(&bytes);
+        if !public_key.is_valid() {
+            return Err(CodecError::DecodingError);
+        }
+        Ok(public_key)
     }
 }
 
-#[derive(PartialEq, Clone, Debug)]
 pub struct X25519PrivateKey([u8; X25519PRIVATEKEYBYTES]);
 
+impl fmt::Debug for X25519PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "X25519PrivateKey(..)")
+    }
+}
+
 impl X25519PrivateKey {
     pub fn shared_secret(&self, p: &X25519PublicKey) -> Result<[u8; 32], Zero> {
-        let group_element = scalarmult::curve25519::GroupElement::from_slice(&p.0).unwrap();
-        let scalar = scalarmult::curve25519::Scalar::from_slice(&self.0).unwrap();
-        scalarmult::curve25519::scalarmult(&scalar, &group_element)
-            .map(|ge| ge.0)
-            .map_err(|()| Zero {})
+        if !p.is_valid() {
+            return Err(Zero {});
+        }
+        provider().x25519(&self.0, &p.0).ok_or(Zero {})
     }
     pub fn derive_public_key(&self) -> X25519PublicKey {
-        let scalar = scalarmult::curve25519::Scalar::from_slice(&self.0).unwrap();
-        X25519PublicKey(scalarmult::curve25519::scalarmult_base(&scalar).0)
+        X25519PublicKey(provider().x25519_base(&self.0))
     }
-    pub fn from_slice(bytes: &[u8]) -> X25519PrivateKey {
+    pub fn from_slice(bytes: &[u8]) -> Option<X25519PrivateKey> {
+        if bytes.len() != X25519PRIVATEKEYBYTES {
+            return None;
+        }
         let mut inner = <[u8; X25519PRIVATEKEYBYTES]>::default();
-        inner.copy_from_slice(&bytes[..X25519PRIVATEKEYBYTES]);
-        X25519PrivateKey(inner)
+        inner.copy_from_slice(bytes);
+        Some(X25519PrivateKey(inner))
     }
-    pub fn to_bytes(&self) -> [u8; X25519PRIVATEKEYBYTES] {
-        self.0
+    pub fn as_slice(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl ConstantTimeEq for X25519PrivateKey {
+    fn ct_eq(&self, other: &Self) -> bool {
+        constant_time_eq(&self.0, &other.0)
+    }
+}
+
+impl Zeroize for X25519PrivateKey {
+    fn zeroize(&mut self) {
+        erase(&mut self.0)
     }
 }
 
 impl Drop for X25519PrivateKey {
     fn drop(&mut self) {
-        erase(&mut self.0)
+        self.zeroize()
     }
 }
 
@@ -87,9 +166,7 @@ impl Codec for X25519PrivateKey {
         encode_vec_u16(buffer, &self.0);
     }
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
-        let mut value = [0u8; X25519PRIVATEKEYBYTES];
-        value.clone_from_slice(&decode_vec_u16(cursor)?[..X25519PRIVATEKEYBYTES]);
-        Ok(X25519PrivateKey(value))
+        X25519PrivateKey::from_slice(&decode_vec_u16(cursor)?).ok_or(CodecError::DecodingError)
     }
 }
 
@@ -99,81 +176,479 @@ pub struct X25519KeyPair {
 }
 
 impl X25519KeyPair {
-    pub fn new_random() -> X25519KeyPair {
-        let random_bytes = randombytes::randombytes(scalarmult::curve25519::SCALARBYTES);
-        let mut private_key: scalarmult::curve25519::Scalar =
-            scalarmult::curve25519::Scalar([0u8; scalarmult::curve25519::SCALARBYTES]);
-        private_key.0[..scalarmult::curve25519::SCALARBYTES]
-            .clone_from_slice(&random_bytes[..scalarmult::curve25519::SCALARBYTES]);
-        let public_key = scalarmult::curve25519::scalarmult_base(&private_key);
+    pub fn new_random(rng: &mut dyn CryptoRng) -> X25519KeyPair {
+        let mut private_key = X25519PrivateKey([0u8; X25519PRIVATEKEYBYTES]);
+        rng.fill_bytes(&mut private_key.0);
+        let public_key = private_key.derive_public_key();
 
         X25519KeyPair {
-            private_key: X25519PrivateKey(private_key.0),
-            public_key: X25519PublicKey(public_key.0),
+            private_key,
+            public_key,
         }
     }
-    pub fn new_from_secret(secret: &NodeSecret) -> X25519KeyPair {
-        let private_key = scalarmult::curve25519::Scalar::from_slice(&secret.0[..]).unwrap();
-        let public_key = scalarmult::curve25519::scalarmult_base(&private_key);
+    // Secrets longer than a private key are truncated
+    pub fn new_from_secret(secret: &NodeSecret) -> Option<X25519KeyPair> {
+        let private_key = X25519PrivateKey::from_slice(secret.0.get(..X25519PRIVATEKEYBYTES)?)?;
+        let public_key = private_key.derive_public_key();
 
-        X25519KeyPair {
-            private_key: X25519PrivateKey(private_key.0),
-            public_key: X25519PublicKey(public_key.0),
+        Some(X25519KeyPair {
+            private_key,
+            public_key,
+        })
+    }
+}
+
+// Uncompressed SEC1 encoding of a point
+#[derive(Hash, PartialEq, Clone, Copy, Debug)]
+pub struct P256PublicKey([u8; P256PUBLICKEYBYTES]);
+
+impl P256PublicKey {
+    pub fn from_slice(bytes: &[u8]) -> P256PublicKey {
+        let mut inner = [0u8; P256PUBLICKEYBYTES];
+        inner.copy_from_slice(&bytes[..P256PUBLICKEYBYTES]);
+        P256PublicKey(inner)
+    }
+    pub fn to_bytes(&self) -> [u8; P256PUBLICKEYBYTES] {
+        self.0
+    }
+    // The encoding has to be a point on the curve
+    pub fn is_valid(&self) -> bool {
+        p256::PublicKey::from_sec1_bytes(&self.0).is_ok()
+    }
+}
+
+impl Codec for P256PublicKey {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        encode_vec_u16(buffer, &self.0);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let bytes = decode_vec_u16(cursor)?;
+        if bytes.len() != P256PUBLICKEYBYTES {
+            return Err(CodecError::DecodingError);
+        }
+        let public_key = P256PublicKey::from_slice(&bytes);
+        if !public_key.is_valid() {
+            return Err(CodecError::DecodingError);
+        }
+        Ok(public_key)
+    }
+}
+
+pub struct P256PrivateKey([u8; P256PRIVATEKEYBYTES]);
+
+impl fmt::Debug for P256PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "P256PrivateKey(..)")
+    }
+}
+
+impl P256PrivateKey {
+    // Returns None if the bytes are not a valid scalar
+    pub fn from_slice(bytes: &[u8]) -> Option<P256PrivateKey> {
+        if bytes.len() != P256PRIVATEKEYBYTES || p256::SecretKey::from_slice(bytes).is_err() {
+            return None;
+        }
+        let mut inner = [0u8; P256PRIVATEKEYBYTES];
+        inner.copy_from_slice(bytes);
+        Some(P256PrivateKey(inner))
+    }
+    pub fn shared_secret(&self, p: &P256PublicKey) -> Result<[u8; 32], InvalidKey> {
+        let public_key = p256::PublicKey::from_sec1_bytes(&p.0).map_err(|_| InvalidKey {})?;
+        let shared_secret = p256::ecdh::diffie_hellman(
+            self.secret_key().to_nonzero_scalar(),
+            public_key.as_affine(),
+        );
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(shared_secret.raw_secret_bytes());
+        Ok(bytes)
+    }
+    pub fn derive_public_key(&self) -> P256PublicKey {
+        let point = self.secret_key().public_key().to_encoded_point(false);
+        P256PublicKey::from_slice(point.as_bytes())
+    }
+    pub fn as_slice(&self) -> &[u8] {
+        &self.0
+    }
+    fn secret_key(&self) -> p256::SecretKey {
+        // The scalar was validated on construction
+        p256::SecretKey::from_slice(&self.0).unwrap()
+    }
+}
+
+impl ConstantTimeEq for P256PrivateKey {
+    fn ct_eq(&self, other: &Self) -> bool {
+        constant_time_eq(&self.0, &other.0)
+    }
+}
+
+impl Zeroize for P256PrivateKey {
+    fn zeroize(&mut self) {
+        erase(&mut self.0)
+    }
+}
+
+impl Drop for P256PrivateKey {
+    fn drop(&mut self) {
+        self.zeroize()
+    }
+}
+
+impl Codec for P256PrivateKey {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        encode_vec_u16(buffer, &self.0);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        P256PrivateKey::from_slice(&decode_vec_u16(cursor)?).ok_or(CodecError::DecodingError)
+    }
+}
+
+pub struct P256KeyPair {
+    pub private_key: P256PrivateKey,
+    pub public_key: P256PublicKey,
+}
+
+impl P256KeyPair {
+    pub fn new_random(rng: &mut dyn CryptoRng) -> P256KeyPair {
+        let mut random_bytes = rng.random_bytes(P256PRIVATEKEYBYTES);
+        let key_pair = P256KeyPair::new_from_secret(&random_bytes);
+        erase(&mut random_bytes);
+        key_pair
+    }
+    // Not every 32 byte string is a valid scalar, the secret is hashed until
+    // it is one.
+    pub fn new_from_secret(secret: &[u8]) -> P256KeyPair {
+        let mut candidate = secret.to_vec();
+        loop {
+            if let Some(private_key) = P256PrivateKey::from_slice(&candidate) {
+                erase(&mut candidate);
+                let public_key = private_key.derive_public_key();
+                return P256KeyPair {
+                    private_key,
+                    public_key,
+                };
+            }
+            let next = HashAlgorithm::Sha256.hash(&candidate);
+            erase(&mut candidate);
+            candidate = next;
         }
     }
 }
 
-pub struct P256PublicKey([u8; 65]);
+// Suite-independent key encodings, the KEM of the cipher suite gives them
+// meaning.
+#[derive(Hash, PartialEq, Clone, Debug)]
+pub struct KemPublicKey(Vec<u8>);
+
+impl KemPublicKey {
+    pub fn from_slice(bytes: &[u8]) -> KemPublicKey {
+        KemPublicKey(bytes.to_vec())
+    }
+    pub fn as_slice(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Codec for KemPublicKey {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        encode_vec_u16(buffer, &self.0);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        Ok(KemPublicKey(decode_vec_u16(cursor)?))
+    }
+}
+
+pub struct KemPrivateKey(Vec<u8>);
+
+impl fmt::Debug for KemPrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KemPrivateKey(..)")
+    }
+}
+
+impl KemPrivateKey {
+    pub fn from_slice(bytes: &[u8]) -> KemPrivateKey {
+        KemPrivateKey(bytes.to_vec())
+    }
+    pub fn as_slice(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl ConstantTimeEq for KemPrivateKey {
+    fn ct_eq(&self, other: &Self) -> bool {
+        constant_time_eq(&self.0, &other.0)
+    }
+}
+
+impl Zeroize for KemPrivateKey {
+    fn zeroize(&mut self) {
+        erase(&mut self.0)
+    }
+}
+
+impl Drop for KemPrivateKey {
+    fn drop(&mut self) {
+        self.zeroize()
+    }
+}
+
+impl Codec for KemPrivateKey {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        encode_vec_u16(buffer, &self.0);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        Ok(KemPrivateKey(decode_vec_u16(cursor)?))
+    }
+}
 
-#[derive(PartialEq, Clone)]
 pub struct LeafKey {
     pub private_key: Option<X25519PrivateKey>,
     pub public_key: X25519PublicKey,
     pub name: String,
 }
 
-pub type SignaturePublicKey = ed25519::PublicKey;
+pub const ED25519PUBLICKEYBYTES: usize = ED25519_PUBLIC_KEY_BYTES;
+// The seed followed by the public key, as in libsodium
+pub const ED25519SECRETKEYBYTES: usize = ED25519_SEED_BYTES + ED25519_PUBLIC_KEY_BYTES;
+pub const ED25519SIGNATUREBYTES: usize = ED25519_SIGNATURE_BYTES;
+
+#[derive(Hash, PartialEq, Eq, Clone, Copy, Debug)]
+pub struct Ed25519PublicKey(pub [u8; ED25519PUBLICKEYBYTES]);
+
+impl Ed25519PublicKey {
+    pub fn from_slice(bytes: &[u8]) -> Option<Ed25519PublicKey> {
+        if bytes.len() != ED25519PUBLICKEYBYTES {
+            return None;
+        }
+        let mut inner = [0u8; ED25519PUBLICKEYBYTES];
+        inner.copy_from_slice(bytes);
+        Some(Ed25519PublicKey(inner))
+    }
+    pub fn verify(&self, payload: &[u8], signature: &[u8]) -> bool {
+        provider().ed25519_verify(&self.0, payload, signature)
+    }
+}
+
+pub struct Ed25519PrivateKey(pub [u8; ED25519SECRETKEYBYTES]);
+
+impl Ed25519PrivateKey {
+    pub fn from_seed(seed: &[u8; ED25519_SEED_BYTES]) -> Ed25519PrivateKey {
+        let mut inner = [0u8; ED25519SECRETKEYBYTES];
+        inner[..ED25519_SEED_BYTES].copy_from_slice(seed);
+        inner[ED25519_SEED_BYTES..].copy_from_slice(&provider().ed25519_public_key(seed));
+        Ed25519PrivateKey(inner)
+    }
+    // Returns None if the public key half does not match the seed
+    pub fn from_slice(bytes: &[u8]) -> Option<Ed25519PrivateKey> {
+        if bytes.len() != ED25519SECRETKEYBYTES {
+            return None;
+        }
+        let private_key = Ed25519PrivateKey::from_seed(&Ed25519PrivateKey::seed_of(bytes));
+        if private_key.0[..] != bytes[..] {
+            return None;
+        }
+        Some(private_key)
+    }
+    pub fn random(rng: &mut dyn CryptoRng) -> Ed25519PrivateKey {
+        let mut seed = [0u8; ED25519_SEED_BYTES];
+        rng.fill_bytes(&mut seed);
+        let private_key = Ed25519PrivateKey::from_seed(&seed);
+        erase(&mut seed);
+        private_key
+    }
+    pub fn public_key(&self) -> Ed25519PublicKey {
+        Ed25519PublicKey::from_slice(&self.0[ED25519_SEED_BYTES..]).unwrap()
+    }
+    pub fn sign(&self, payload: &[u8]) -> [u8; ED25519SIGNATUREBYTES] {
+        let mut seed = Ed25519PrivateKey::seed_of(&self.0);
+        let signature = provider().ed25519_sign(&seed, payload);
+        erase(&mut seed);
+        signature
+    }
+    fn seed_of(bytes: &[u8]) -> [u8; ED25519_SEED_BYTES] {
+        let mut seed = [0u8; ED25519_SEED_BYTES];
+        seed.copy_from_slice(&bytes[..ED25519_SEED_BYTES]);
+        seed
+    }
+}
+
+impl Zeroize for Ed25519PrivateKey {
+    fn zeroize(&mut self) {
+        erase(&mut self.0)
+    }
+}
+
+impl Drop for Ed25519PrivateKey {
+    fn drop(&mut self) {
+        self.zeroize()
+    }
+}
+
+pub type SignatureScheme = u16;
+
+pub const ED25519: SignatureScheme = 0x0807;
+pub const ECDSA_SECP256R1_SHA256: SignatureScheme = 0x0403;
+
+#[derive(Hash, Clone, Copy, PartialEq, Debug)]
+pub enum SignaturePublicKey {
+    Ed25519(Ed25519PublicKey),
+    EcdsaP256(P256PublicKey),
+}
+
+impl SignaturePublicKey {
+    pub fn from_slice(scheme: SignatureScheme, bytes: &[u8]) -> Option<SignaturePublicKey> {
+        match scheme {
+            ED25519 => Ed25519PublicKey::from_slice(bytes).map(SignaturePublicKey::Ed25519),
+            // Only the uncompressed encoding, the key is kept as it is
+            ECDSA_SECP256R1_SHA256 => {
+                if bytes.len() != P256PUBLICKEYBYTES
+                    || bytes[0] != 0x04
+                    || p256::ecdsa::VerifyingKey::from_sec1_bytes(bytes).is_err()
+                {
+                    return None;
+                }
+                Some(SignaturePublicKey::EcdsaP256(P256PublicKey::from_slice(
+                    bytes,
+                )))
+            }
+            _ => None,
+        }
+    }
+    pub fn scheme(&self) -> SignatureScheme {
+        match *self {
+            SignaturePublicKey::Ed25519(_) => ED25519,
+            SignaturePublicKey::EcdsaP256(_) => ECDSA_SECP256R1_SHA256,
+        }
+    }
+    pub fn as_slice(&self) -> &[u8] {
+        match *self {
+            SignaturePublicKey::Ed25519(ref key) => &key.0,
+            SignaturePublicKey::EcdsaP256(ref key) => &key.0,
+        }
+    }
+    pub fn verify(&self, payload: &[u8], signature: &Signature) -> bool {
+        match *self {
+            SignaturePublicKey::Ed25519(ref key) => key.verify(payload, &signature.0),
+            SignaturePublicKey::EcdsaP256(ref key) => {
+                use p256::ecdsa::signature::Verifier;
+                let verifying_key = match p256::ecdsa::VerifyingKey::from_sec1_bytes(&key.0) {
+                    Ok(verifying_key) => verifying_key,
+                    Err(_) => return false,
+                };
+                match p256::ecdsa::Signature::from_der(&signature.0) {
+                    Ok(signature) => verifying_key.verify(payload, &signature).is_ok(),
+                    Err(_) => false,
+                }
+            }
+        }
+    }
+    // The scheme is transmitted separately
+    pub fn encode_key(&self, buffer: &mut Vec<u8>) {
+        encode_vec_u16(buffer, self.as_slice());
+    }
+    pub fn decode_key(
+        scheme: SignatureScheme,
+        cursor: &mut Cursor,
+    ) -> Result<SignaturePublicKey, CodecError> {
+        let bytes = decode_vec_u16(cursor)?;
+        SignaturePublicKey::from_slice(scheme, &bytes).ok_or(CodecError::DecodingError)
+    }
+}
 
 impl Codec for SignaturePublicKey {
     fn encode(&self, buffer: &mut Vec<u8>) {
-        encode_vec_u16(buffer, &self.0);
+        self.scheme().encode(buffer);
+        self.encode_key(buffer);
     }
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
-        let bytes = decode_vec_u16(cursor)?;
-        Ok(SignaturePublicKey::from_slice(&bytes).unwrap())
+        let scheme = SignatureScheme::decode(cursor)?;
+        SignaturePublicKey::decode_key(scheme, cursor)
     }
 }
 
-pub type SignaturePrivateKey = ed25519::SecretKey;
+pub enum SignaturePrivateKey {
+    Ed25519(Ed25519PrivateKey),
+    EcdsaP256(P256PrivateKey),
+}
+
+impl SignaturePrivateKey {
+    pub fn from_slice(scheme: SignatureScheme, bytes: &[u8]) -> Option<SignaturePrivateKey> {
+        match scheme {
+            ED25519 => Ed25519PrivateKey::from_slice(bytes).map(SignaturePrivateKey::Ed25519),
+            ECDSA_SECP256R1_SHA256 => {
+                P256PrivateKey::from_slice(bytes).map(SignaturePrivateKey::EcdsaP256)
+            }
+            _ => None,
+        }
+    }
+    pub fn scheme(&self) -> SignatureScheme {
+        match *self {
+            SignaturePrivateKey::Ed25519(_) => ED25519,
+            SignaturePrivateKey::EcdsaP256(_) => ECDSA_SECP256R1_SHA256,
+        }
+    }
+    pub fn as_slice(&self) -> &[u8] {
+        match *self {
+            SignaturePrivateKey::Ed25519(ref key) => &key.0,
+            SignaturePrivateKey::EcdsaP256(ref key) => &key.0,
+        }
+    }
+    pub fn public_key(&self) -> SignaturePublicKey {
+        match *self {
+            SignaturePrivateKey::Ed25519(ref key) => SignaturePublicKey::Ed25519(key.public_key()),
+            SignaturePrivateKey::EcdsaP256(ref key) => {
+                SignaturePublicKey::EcdsaP256(key.derive_public_key())
+            }
+        }
+    }
+    // ECDSA signatures are deterministic (RFC 6979) and DER encoded
+    pub fn sign(&self, payload: &[u8]) -> Signature {
+        match *self {
+            SignaturePrivateKey::Ed25519(ref key) => Signature::from_slice(&key.sign(payload)),
+            SignaturePrivateKey::EcdsaP256(ref key) => {
+                use p256::ecdsa::signature::Signer;
+                let signing_key = p256::ecdsa::SigningKey::from_slice(&key.0).unwrap();
+                let signature: p256::ecdsa::Signature = signing_key.sign(payload);
+                Signature::from_slice(signature.to_der().as_bytes())
+            }
+        }
+    }
+}
 
 impl Codec for SignaturePrivateKey {
     fn encode(&self, buffer: &mut Vec<u8>) {
-        encode_vec_u16(buffer, &self.0);
+        self.scheme().encode(buffer);
+        encode_vec_u16(buffer, self.as_slice());
     }
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let scheme = SignatureScheme::decode(cursor)?;
         let bytes = decode_vec_u16(cursor)?;
-        Ok(SignaturePrivateKey::from_slice(&bytes).unwrap())
+        SignaturePrivateKey::from_slice(scheme, &bytes).ok_or(CodecError::DecodingError)
     }
 }
 
-pub type Signature = ed25519::Signature;
+// Opaque, the scheme of the verifying key determines the format
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Signature(Vec<u8>);
+
+impl Signature {
+    pub fn from_slice(bytes: &[u8]) -> Signature {
+        Signature(bytes.to_vec())
+    }
+    pub fn as_slice(&self) -> &[u8] {
+        &self.0
+    }
+}
 
 impl Codec for Signature {
     fn encode(&self, buffer: &mut Vec<u8>) {
         encode_vec_u16(buffer, &self.0);
     }
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
-        let bytes = decode_vec_u16(cursor)?;
-        Ok(Signature::from_slice(&bytes).unwrap())
+        Ok(Signature(decode_vec_u16(cursor)?))
     }
 }
 
-pub type SignatureScheme = u16;
-
-pub const ED25519: SignatureScheme = 0x0807;
-pub const ECDSA_SECP256R1_SHA256: SignatureScheme = 0x0403;
-
-#[derive(Clone)]
 pub struct Identity {
     pub id: Vec<u8>,
     pub public_key: SignaturePublicKey,
@@ -190,6 +665,9 @@ impl Codec for Identity {
         let id = decode_vec_u8(cursor)?;
         let public_key = SignaturePublicKey::decode(cursor)?;
         let private_key = SignaturePrivateKey::decode(cursor)?;
+        if public_key.scheme() != private_key.scheme() {
+            return Err(CodecError::DecodingError);
+        }
         Ok(Identity {
             id,
             public_key,
@@ -199,28 +677,61 @@ impl Codec for Identity {
 }
 
 impl Identity {
-    pub fn random() -> Self {
-        let id = randombytes::randombytes(4).to_vec();
-        let (public_key, private_key) = ed25519::gen_keypair();
-        Self {
+    pub fn random(rng: &mut dyn CryptoRng) -> Self {
+        Identity::random_for_scheme(ED25519, rng).unwrap()
+    }
+    pub fn random_for_scheme(scheme: SignatureScheme, rng: &mut dyn CryptoRng) -> Option<Self> {
+        let id = rng.random_bytes(4);
+        let (public_key, private_key) = match scheme {
+            ED25519 => {
+                let private_key = Ed25519PrivateKey::random(rng);
+                (
+                    SignaturePublicKey::Ed25519(private_key.public_key()),
+                    SignaturePrivateKey::Ed25519(private_key),
+                )
+            }
+            ECDSA_SECP256R1_SHA256 => {
+                let kp = P256KeyPair::new_random(rng);
+                (
+                    SignaturePublicKey::EcdsaP256(kp.public_key),
+                    SignaturePrivateKey::EcdsaP256(kp.private_key),
+                )
+            }
+            _ => return None,
+        };
+        Some(Self {
+            id,
+            public_key,
+            private_key,
+        })
+    }
+    // For imported keys the id is derived from the public key, so that
+    // importing the same key twice gives the same identity
+    pub fn from_private_key(private_key: SignaturePrivateKey) -> Self {
+        let public_key = private_key.public_key();
+        let id = HashAlgorithm::Sha256.hash(&public_key.encode_detached())[..4].to_vec();
+        Identity {
             id,
             public_key,
             private_key,
         }
     }
-
+    pub fn private_key(&self) -> &SignaturePrivateKey {
+        &self.private_key
+    }
+    pub fn get_scheme(&self) -> SignatureScheme {
+        self.public_key.scheme()
+    }
     pub fn sign(&self, payload: &[u8]) -> Signature {
-        ed25519::sign_detached(payload, &self.private_key)
+        self.private_key.sign(payload)
     }
     pub fn verify(&self, payload: &[u8], signature: &Signature) -> bool {
-        ed25519::verify_detached(signature, payload, &self.public_key)
+        self.public_key.verify(payload, signature)
     }
 }
 
 impl Drop for Identity {
     fn drop(&mut self) {
-        erase(&mut self.private_key.0);
-        erase(&mut self.public_key.0);
         erase(&mut self.id);
     }
 }
@@ -228,14 +739,15 @@ impl Drop for Identity {
 pub trait Signable: Sized {
     fn unsigned_payload(&self) -> Vec<u8>;
 
-    fn sign(&mut self, id: &Identity) -> Signature {
-        id.sign(&self.unsigned_payload())
+    fn sign(&self, signer: &dyn Signer) -> Result<Signature, SignerError> {
+        signer.sign(&self.unsigned_payload())
     }
-    fn verify(&self, id: &Identity, signature: &Signature) -> bool {
-        id.verify(&self.unsigned_payload(), signature)
+    fn verify(&self, public_key: &SignaturePublicKey, signature: &Signature) -> bool {
+        public_key.verify(&self.unsigned_payload(), signature)
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(u8)]
 pub enum CredentialType {
     Basic = 0,
@@ -243,7 +755,7 @@ pub enum CredentialType {
     Default = 255,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Hash, PartialEq, Debug)]
 pub struct BasicCredential {
     pub identity: Vec<u8>, // <0..2^16-1>;
     pub public_key: SignaturePublicKey,
@@ -251,7 +763,7 @@ pub struct BasicCredential {
 
 impl BasicCredential {
     pub fn verify(&self, payload: &[u8], signature: &Signature) -> bool {
-        ed25519::verify_detached(signature, payload, &self.public_key)
+        self.public_key.verify(payload, signature)
     }
 }
 
@@ -270,38 +782,128 @@ impl Codec for BasicCredential {
     }
 }
 
+#[derive(Clone, Hash, PartialEq, Debug)]
+pub enum Credential {
+    Basic(BasicCredential),
+    X509(X509Credential),
+}
+
+impl Credential {
+    pub fn credential_type(&self) -> CredentialType {
+        match *self {
+            Credential::Basic(_) => CredentialType::Basic,
+            Credential::X509(_) => CredentialType::X509,
+        }
+    }
+    pub fn public_key(&self) -> SignaturePublicKey {
+        match *self {
+            Credential::Basic(ref credential) => credential.public_key,
+            Credential::X509(ref credential) => credential.public_key(),
+        }
+    }
+    pub fn verify(&self, payload: &[u8], signature: &Signature) -> bool {
+        self.public_key().verify(payload, signature)
+    }
+}
+
+impl Codec for Credential {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        (self.credential_type() as u8).encode(buffer);
+        match *self {
+            Credential::Basic(ref credential) => credential.encode(buffer),
+            Credential::X509(ref credential) => credential.encode(buffer),
+        }
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        match u8::decode(cursor)? {
+            0 => Ok(Credential::Basic(BasicCredential::decode(cursor)?)),
+            1 => Ok(Credential::X509(X509Credential::decode(cursor)?)),
+            _ => Err(CodecError::DecodingError),
+        }
+    }
+}
+
 pub type CipherSuite = u16;
 
-pub const AES128GCM_P256_SHA256: CipherSuite = 0;
-pub const AES128GCM_CURVE25519_SHA256: CipherSuite = 1;
+// Registered suites use their ids from the IANA registry of RFC 9420, the
+// others take ids from the private use range 0xF000-0xFFFF
+pub const AES128GCM_CURVE25519_SHA256: CipherSuite = 0x0001;
+pub const AES128GCM_P256_SHA256: CipherSuite = 0x0002;
+pub const CHACHA20POLY1305_CURVE25519_SHA256: CipherSuite = 0x0003;
+pub const AES256GCM_CURVE25519_SHA512: CipherSuite = 0xF001;
+
+#[derive(Debug, PartialEq)]
+pub enum UserInitKeyError {
+    // There has to be exactly one init key per cipher suite
+    InitKeyCountMismatch,
+    SignerError(SignerError),
+}
+
+impl From<SignerError> for UserInitKeyError {
+    fn from(e: SignerError) -> Self {
+        UserInitKeyError::SignerError(e)
+    }
+}
 
 #[derive(Clone)]
 pub struct UserInitKey {
     pub cipher_suites: Vec<CipherSuite>,
-    pub init_keys: Vec<X25519PublicKey>, /* [2^16-1] */
-    pub algorithm: SignatureScheme,
-    pub identity_key: SignaturePublicKey,
+    pub init_keys: Vec<KemPublicKey>, /* [2^16-1] */
+    // Signed along with the init keys, so that every member gets the
+    // credential chosen by the joiner
+    pub credential: Credential,
     pub signature: Signature,
 }
 
 impl UserInitKey {
-    pub fn new(init_keys: &[X25519PublicKey], identity: &Identity) -> Self {
+    // There is one init key per cipher suite, in the same order. The
+    // credential has to be the one of the signer.
+    pub fn new(
+        cipher_suites: &[CipherSuite],
+        init_keys: &[KemPublicKey],
+        credential: Credential,
+        signer: &dyn Signer,
+    ) -> Result<Self, UserInitKeyError> {
+        if cipher_suites.len() != init_keys.len() {
+            return Err(UserInitKeyError::InitKeyCountMismatch);
+        }
+        if credential.public_key() != signer.public_key() {
+            return Err(SignerError::KeyMismatch.into());
+        }
         let mut init_key = Self {
-            cipher_suites: vec![AES128GCM_CURVE25519_SHA256],
+            cipher_suites: cipher_suites.to_owned(),
             init_keys: init_keys.to_owned(),
-            algorithm: ED25519,
-            identity_key: identity.public_key,
-            signature: Signature::from_slice(&[0u8; ed25519::SIGNATUREBYTES]).unwrap(),
+            credential,
+            signature: Signature::from_slice(&[]),
         };
-        init_key.signature = identity.sign(&init_key.unsigned_payload());
-        init_key
+        init_key.signature = signer.sign(&init_key.unsigned_payload())?;
+        Ok(init_key)
+    }
+    pub fn identity_key(&self) -> SignaturePublicKey {
+        self.credential.public_key()
     }
     pub fn self_verify(&self) -> bool {
-        ed25519::verify_detached(
-            &self.signature,
-            &self.unsigned_payload(),
-            &self.identity_key,
-        )
+        self.cipher_suites.len() == self.init_keys.len()
+            && self.has_valid_init_keys()
+            && self
+                .credential
+                .verify(&self.unsigned_payload(), &self.signature)
+    }
+    // Keys for unknown cipher suites are never used and not checked
+    fn has_valid_init_keys(&self) -> bool {
+        self.cipher_suites
+            .iter()
+            .zip(self.init_keys.iter())
+            .all(|(&id, init_key)| match CipherSuiteProvider::from_id(id) {
+                Some(cipher_suite) => cipher_suite.kem.is_valid_public_key(init_key),
+                None => true,
+            })
+    }
+    pub fn get_init_key(&self, cipher_suite: CipherSuite) -> Option<&KemPublicKey> {
+        self.cipher_suites
+            .iter()
+            .position(|&cs| cs == cipher_suite)
+            .map(|position| &self.init_keys[position])
     }
 }
 
@@ -310,8 +912,7 @@ impl Signable for UserInitKey {
         let buffer = &mut Vec::new();
         encode_vec_u8(buffer, &self.cipher_suites);
         encode_vec_u16(buffer, &self.init_keys);
-        self.algorithm.encode(buffer);
-        self.identity_key.encode(buffer);
+        self.credential.encode(buffer);
         buffer.to_vec()
     }
 }
@@ -326,44 +927,35 @@ impl Codec for UserInitKey {
         let cipher_suites: Vec<CipherSuite> = decode_vec_u8(cursor)?;
 
         let mut cs_payload = cursor.sub_cursor_u16()?;
-        let mut x25519_key: Option<X25519PublicKey> = None;
+        let mut init_keys: Vec<KemPublicKey> = Vec::new();
 
         if !cipher_suites.is_empty() {
             for cs in cipher_suites.clone() {
                 match cs {
                     AES128GCM_P256_SHA256 => {
-                        let _pub_key: Vec<u8> = decode_vec_u16(&mut cs_payload)?;
-                    }
-                    AES128GCM_CURVE25519_SHA256 => {
-                        x25519_key = Some(X25519PublicKey::decode(&mut cs_payload)?);
+                        let key = P256PublicKey::decode(&mut cs_payload)?;
+                        init_keys.push(KemPublicKey::from_slice(&key.to_bytes()));
                     }
-                    _ => {
-                        let _pub_key: Vec<u8> = decode_vec_u16(&mut cs_payload)?;
-                        return Err(CodecError::DecodingError);
+                    AES128GCM_CURVE25519_SHA256
+                    | CHACHA20POLY1305_CURVE25519_SHA256
+                    | AES256GCM_CURVE25519_SHA512 => {
+                        let key = X25519PublicKey::decode(&mut cs_payload)?;
+                        init_keys.push(KemPublicKey::from_slice(&key.to_bytes()));
                     }
+                    // Kept as they are, so that the signature still verifies
+                    _ => init_keys.push(KemPublicKey::decode(&mut cs_payload)?),
                 }
             }
         } else {
             return Err(CodecError::DecodingError);
         }
 
-        if x25519_key.is_none() {
-            return Err(CodecError::DecodingError);
-        }
-
-        let init_keys: Vec<X25519PublicKey> = vec![x25519_key.unwrap()];
-        let algorithm = SignatureScheme::decode(cursor)?;
-
-        if algorithm != ED25519 {
-            return Err(CodecError::DecodingError);
-        }
-        let identity_key = SignaturePublicKey::decode(cursor)?;
+        let credential = Credential::decode(cursor)?;
         let signature = Signature::decode(cursor)?;
         Ok(UserInitKey {
             cipher_suites,
             init_keys,
-            identity_key,
-            algorithm,
+            credential,
             signature,
         })
     }
@@ -371,34 +963,63 @@ impl Codec for UserInitKey {
 
 pub struct UserInitKeyBundle {
     pub init_key: UserInitKey,
-    _private_keys: Vec<X25519PrivateKey>,
+    private_keys: Vec<KemPrivateKey>,
 }
 
 impl UserInitKeyBundle {
-    pub fn new(identity: &Identity) -> Self {
-        let kp = X25519KeyPair::new_random();
-        let private_keys = vec![kp.private_key];
-        let public_keys = [kp.public_key];
-        let init_key = UserInitKey::new(&public_keys, identity);
-        UserInitKeyBundle {
-            init_key,
-            _private_keys: private_keys,
+    // Offers all supported cipher suites
+    pub fn new(
+        signer: &dyn Signer,
+        credential: Credential,
+        rng: &mut dyn CryptoRng,
+    ) -> Result<Self, UserInitKeyError> {
+        let cipher_suites = [
+            AES128GCM_CURVE25519_SHA256,
+            AES128GCM_P256_SHA256,
+            CHACHA20POLY1305_CURVE25519_SHA256,
+            AES256GCM_CURVE25519_SHA512,
+        ];
+        let mut private_keys = Vec::new();
+        let mut public_keys = Vec::new();
+        for &cs in cipher_suites.iter() {
+            let (private_key, public_key) = CipherSuiteProvider::from_id(cs)
+                .unwrap()
+                .kem
+                .generate_key_pair(rng);
+            private_keys.push(private_key);
+            public_keys.push(public_key);
         }
+        let init_key = UserInitKey::new(&cipher_suites, &public_keys, credential, signer)?;
+        Ok(UserInitKeyBundle {
+            init_key,
+            private_keys,
+        })
+    }
+    pub fn get_private_key(&self, cipher_suite: CipherSuite) -> Option<&KemPrivateKey> {
+        let position = self
+            .init_key
+            .cipher_suites
+            .iter()
+            .position(|&cs| cs == cipher_suite)?;
+        self.private_keys.get(position)
     }
 }
 
 impl Codec for UserInitKeyBundle {
     fn encode(&self, buffer: &mut Vec<u8>) {
         self.init_key.encode(buffer);
-        encode_vec_u16(buffer, &self._private_keys);
+        encode_vec_u16(buffer, &self.private_keys);
     }
 
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
         let init_key: UserInitKey = UserInitKey::decode(cursor)?;
-        let _private_keys: Vec<X25519PrivateKey> = decode_vec_u16(cursor)?;
+        let private_keys: Vec<KemPrivateKey> = decode_vec_u16(cursor)?;
+        if private_keys.len() != init_key.cipher_suites.len() {
+            return Err(CodecError::DecodingError);
+        }
         Ok(UserInitKeyBundle {
             init_key,
-            _private_keys,
+            private_keys,
         })
     }
 }
@@ -422,8 +1043,11 @@ ratchet_frontier: Vec<X25519PublicKey>, /* <0..2^16-1>; */
 
 #[test]
 fn test_constants() {
-    use sodiumoxide::crypto::hash::sha256::*;
-    assert_eq!(DIGESTBYTES, NODESECRETBYTES);
+    let mut rng = test_rng("test_constants");
+    let node_secret = NodeSecret::new_random(&SUITE_CURVE25519_AES128GCM_SHA256, &mut rng);
+    assert_eq!(32, node_secret.0.len());
+    let node_secret = NodeSecret::new_random(&SUITE_CURVE25519_AES256GCM_SHA512, &mut rng);
+    assert_eq!(64, node_secret.0.len());
 }
 
 #[test]
@@ -431,23 +1055,68 @@ fn test_signature() {
     use utils::*;
 
     let payload = vec![0, 1, 2, 3];
-    let pk = SignaturePublicKey::from_slice(&hex_to_bytes(
-        "6f8a35bff581235d8757b2f3cea6e6bfa7c5005852ac8ccf3c63a2c45c514d0d",
-    ))
+    let pk = SignaturePublicKey::from_slice(
+        ED25519,
+        &hex_to_bytes("6f8a35bff581235d8757b2f3cea6e6bfa7c5005852ac8ccf3c63a2c45c514d0d"),
+    )
     .unwrap();
-    let sig = Signature::from_slice(&hex_to_bytes("4d51569eb56fc808cad8d8707110bcbf5c3daae9d394af77d48e840b2750ab15ea04c0fd30658625a20d0446fbd8ae09c6cc67f1004ed8c79818b74bef4fa107")).unwrap();
-    assert!(ed25519::verify_detached(&sig, &payload, &pk));
+    let sig = Signature::from_slice(&hex_to_bytes("4d51569eb56fc808cad8d8707110bcbf5c3daae9d394af77d48e840b2750ab15ea04c0fd30658625a20d0446fbd8ae09c6cc67f1004ed8c79818b74bef4fa107"));
+    assert!(pk.verify(&payload, &sig));
+}
+
+#[test]
+fn test_ecdsa_signature() {
+    let mut rng = test_rng("test_ecdsa_signature");
+    let identity = Identity::random_for_scheme(ECDSA_SECP256R1_SHA256, &mut rng).unwrap();
+    let payload = vec![0, 1, 2, 3];
+    let signature = identity.sign(&payload);
+    assert_eq!(signature.as_slice()[0], 0x30);
+    assert!(identity.verify(&payload, &signature));
+    assert!(!identity.verify(&[0, 1, 2], &signature));
+
+    // Keys and signatures of the two schemes are not interchangeable
+    let other = Identity::random(&mut rng);
+    assert!(!other.verify(&payload, &signature));
+    assert!(!identity.verify(&payload, &other.sign(&payload)));
+
+    let decoded = Identity::decode_detached(&identity.encode_detached()).unwrap();
+    assert_eq!(decoded.public_key, identity.public_key);
+    assert_eq!(decoded.get_scheme(), ECDSA_SECP256R1_SHA256);
+    assert!(decoded.public_key.verify(&payload, &decoded.sign(&payload)));
+
+    // An ECDSA identity can sign its UserInitKey
+    let credential = Credential::Basic(BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: identity.public_key,
+    });
+    let bundle = UserInitKeyBundle::new(&identity, credential, &mut rng).unwrap();
+    let uik = UserInitKey::decode_detached(&bundle.init_key.encode_detached()).unwrap();
+    assert_eq!(uik.identity_key().scheme(), ECDSA_SECP256R1_SHA256);
+    assert!(uik.self_verify());
+
+    // Compressed and truncated points are not decoded
+    let point = identity.public_key.as_slice();
+    let mut compressed = vec![0x02 | (point[P256PUBLICKEYBYTES - 1] & 1)];
+    compressed.extend_from_slice(&point[1..33]);
+    assert!(p256::ecdsa::VerifyingKey::from_sec1_bytes(&compressed).is_ok());
+    for bytes in &[compressed, point[..33].to_vec(), vec![0x04]] {
+        let mut buffer = Vec::new();
+        ECDSA_SECP256R1_SHA256.encode(&mut buffer);
+        encode_vec_u16(&mut buffer, bytes);
+        assert!(SignaturePublicKey::decode_detached(&buffer).is_err());
+    }
 }
 
 #[test]
 fn generate_user_init_key() {
-    let (signature_public_key, signature_private_key) = ed25519::gen_keypair();
+    let mut rng = test_rng("generate_user_init_key");
+    let signature_private_key = Ed25519PrivateKey::random(&mut rng);
     println!(
         "Signature: Private key: {:?}, public key: {:?}",
         bytes_to_hex(&signature_private_key.0),
-        bytes_to_hex(&signature_public_key.0)
+        bytes_to_hex(&signature_private_key.public_key().0)
     );
-    let dh_kp = X25519KeyPair::new_random();
+    let dh_kp = X25519KeyPair::new_random(&mut rng);
     println!(
         "X25519: Private key: {:?}, Public key: {:?}",
         bytes_to_hex(&dh_kp.private_key.0),
@@ -460,44 +1129,143 @@ fn test_user_init_key() {
     let signature_private_key_hex =
         "AA5A90D1AA3DEECB657F43630680A0001FC910506DC8D3D363095E5E7A7D1B6C5F334D034259E2D6670D6CA8F5A937EA7CE9438259292F8872AEA6C7BB8AA2C0";
     let signature_private_key =
-        ed25519::SecretKey::from_slice(&hex_to_bytes(signature_private_key_hex)).unwrap();
+        Ed25519PrivateKey::from_slice(&hex_to_bytes(signature_private_key_hex)).unwrap();
     let signature_public_key_hex =
         "5F334D034259E2D6670D6CA8F5A937EA7CE9438259292F8872AEA6C7BB8AA2C0";
     let signature_public_key =
-        ed25519::PublicKey::from_slice(&hex_to_bytes(signature_public_key_hex)).unwrap();
+        Ed25519PublicKey::from_slice(&hex_to_bytes(signature_public_key_hex)).unwrap();
 
     let dh_private_key_hex = "EC332FA1FFEF173E1807B2896D86F25A85231070993A3542AE582D2D563ED42C";
-    let _dh_private_key = X25519PrivateKey::from_slice(&hex_to_bytes(dh_private_key_hex));
+    let _dh_private_key = X25519PrivateKey::from_slice(&hex_to_bytes(dh_private_key_hex)).unwrap();
 
     let dh_public_key_hex = "3CB3FC6B9271B308EFEDC029502278DED42FC4AF181A44E31549F53B9BF7436C";
-    let dh_public_key = X25519PublicKey::from_slice(&hex_to_bytes(dh_public_key_hex));
+    let dh_public_key = KemPublicKey::from_slice(&hex_to_bytes(dh_public_key_hex));
 
-    let empty_signature_inner: [u8; ed25519::SIGNATUREBYTES] = [0u8; ed25519::SIGNATUREBYTES];
-    let empty_signature = ed25519::Signature::from_slice(&empty_signature_inner).unwrap();
+    let empty_signature_inner: [u8; ED25519SIGNATUREBYTES] = [0u8; ED25519SIGNATUREBYTES];
+    let empty_signature = Signature::from_slice(&empty_signature_inner);
 
     let mut uik = UserInitKey {
         cipher_suites: vec![AES128GCM_CURVE25519_SHA256],
         init_keys: vec![dh_public_key],
-        algorithm: ED25519,
-        identity_key: signature_public_key,
+        credential: Credential::Basic(BasicCredential {
+            identity: "Alice".as_bytes().to_vec(),
+            public_key: SignaturePublicKey::Ed25519(signature_public_key),
+        }),
         signature: empty_signature,
     };
 
-    let signature = ed25519::sign_detached(&uik.unsigned_payload(), &signature_private_key);
-    uik.signature = signature;
+    let signature = signature_private_key.sign(&uik.unsigned_payload());
+    uik.signature = Signature::from_slice(&signature);
 
     let mut buffer = Vec::new();
     uik.encode(&mut buffer);
 
-    let uik_hex = "020001002200203CB3FC6B9271B308EFEDC029502278DED42FC4AF181A44E31549F53B9BF7436C080700205F334D034259E2D6670D6CA8F5A937EA7CE9438259292F8872AEA6C7BB8AA2C000407DF11F6392DC7F1BD6FAFB34AA220C5457D2E58A2BB2C21DA4878A3E8AB8B0BA2AF2A87E7102D23DE169F880E38688406B34E582B6E978867755E37FB352DB0C";
+    let uik_hex = "020001002200203CB3FC6B9271B308EFEDC029502278DED42FC4AF181A44E31549F53B9BF7436C0005416C696365080700205F334D034259E2D6670D6CA8F5A937EA7CE9438259292F8872AEA6C7BB8AA2C0004027505D9E55A38E22876CD6A4DB08C6CC861D0EBA81D271426B0A7F36323577757315ABB9983AE53C09D22C015DC7C60130F2C5EAE912987E53A0D2969C1D2005";
 
     assert_eq!(bytes_to_hex(&buffer), uik_hex);
+    assert!(uik.self_verify());
+}
+
+#[test]
+fn test_p256_key_agreement() {
+    let mut rng = test_rng("test_p256_key_agreement");
+    let alice = P256KeyPair::new_random(&mut rng);
+    let bob = P256KeyPair::new_random(&mut rng);
+    assert_eq!(
+        alice.private_key.shared_secret(&bob.public_key).unwrap(),
+        bob.private_key.shared_secret(&alice.public_key).unwrap()
+    );
+
+    let encoded = alice.public_key.encode_detached();
+    assert_eq!(encoded.len(), 2 + P256PUBLICKEYBYTES);
+    assert_eq!(encoded[2], 0x04);
+    assert_eq!(
+        P256PublicKey::decode_detached(&encoded).unwrap(),
+        alice.public_key
+    );
+
+    // Scalars outside of the group order are rejected
+    assert!(P256PrivateKey::from_slice(&[0xFFu8; P256PRIVATEKEYBYTES]).is_none());
+    assert!(P256PrivateKey::from_slice(&[0u8; P256PRIVATEKEYBYTES]).is_none());
+    let kp = P256KeyPair::new_from_secret(&[0xFFu8; P256PRIVATEKEYBYTES]);
+    assert_eq!(kp.public_key, kp.private_key.derive_public_key());
+}
+
+#[test]
+fn test_user_init_key_with_p256() {
+    let mut rng = test_rng("test_user_init_key_with_p256");
+    let identity = Identity::random(&mut rng);
+    let credential = Credential::Basic(BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: identity.public_key,
+    });
+    // The credential has to be the one of the signer
+    assert_eq!(
+        UserInitKeyBundle::new(&Identity::random(&mut rng), credential.clone(), &mut rng).err(),
+        Some(UserInitKeyError::SignerError(SignerError::KeyMismatch))
+    );
+    let bundle = UserInitKeyBundle::new(&identity, credential, &mut rng).unwrap();
+    let encoded = bundle.init_key.encode_detached();
+    let decoded = UserInitKey::decode_detached(&encoded).unwrap();
+    assert!(decoded.self_verify());
+    assert_eq!(decoded.cipher_suites, bundle.init_key.cipher_suites);
+    assert_eq!(
+        decoded.get_init_key(AES128GCM_P256_SHA256).unwrap(),
+        bundle.init_key.get_init_key(AES128GCM_P256_SHA256).unwrap()
+    );
+    assert_eq!(
+        decoded
+            .get_init_key(AES128GCM_P256_SHA256)
+            .unwrap()
+            .as_slice()
+            .len(),
+        P256PUBLICKEYBYTES
+    );
+}
+
+#[test]
+fn test_user_init_key_with_unknown_cipher_suites() {
+    let mut rng = test_rng("test_user_init_key_with_unknown_cipher_suites");
+    let identity = Identity::random(&mut rng);
+    let credential = Credential::Basic(BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: identity.public_key,
+    });
+    let x25519_key =
+        KemPublicKey::from_slice(&X25519KeyPair::new_random(&mut rng).public_key.to_bytes());
+    let unknown_key = KemPublicKey::from_slice(&[0x2A; 48]);
+    assert_eq!(
+        UserInitKey::new(
+            &[AES128GCM_CURVE25519_SHA256, 0xF0FF],
+            ::std::slice::from_ref(&x25519_key),
+            credential.clone(),
+            &identity
+        )
+        .err(),
+        Some(UserInitKeyError::InitKeyCountMismatch)
+    );
+
+    // Init keys of suites we do not know are skipped, not rejected
+    let init_key = UserInitKey::new(
+        &[0xF0FF, AES128GCM_CURVE25519_SHA256],
+        &[unknown_key.clone(), x25519_key.clone()],
+        credential,
+        &identity,
+    )
+    .unwrap();
+    let decoded = UserInitKey::decode_detached(&init_key.encode_detached()).unwrap();
+    assert!(decoded.self_verify());
+    assert_eq!(decoded.get_init_key(0xF0FF), Some(&unknown_key));
+    assert_eq!(
+        decoded.get_init_key(AES128GCM_CURVE25519_SHA256),
+        Some(&x25519_key)
+    );
 }
 
 #[test]
 fn test_uik_interop() {
     //let uik_hex = "0400000001006500410435d35a5a3c4a18cf5ca7987fd15052d3001188b9c61d40a584b1fb0fe211fbcb9e549ed1d8ca4a3f8e418a769dfca8ba8be66b0cd8e4ead5d4e7b02ae283600c00201d6ed559fdeb33dd0949173cdd3edbc255df7f63eff729d1932e0438e10d371e004104f789b44019f509ee6d7f5a30548f95da8968ec5492bb9d007ed40766032a22f046e6b2906b03907279e8548866a7461c13e139c2dda31ca2c6600d1b8e9c464f000000473045022019ea04a6ba35093a0993fdf57ca6ecbec700e8584b7a8cd197ccd080b1cca4dc022100ed1816942ac9511180bc63ee03dd2de1523307c35de3e46d234c9c8eb8fa765d";
-    let uik_hex = "020001002200203CB3FC6B9271B308EFEDC029502278DED42FC4AF181A44E31549F53B9BF7436C080700205F334D034259E2D6670D6CA8F5A937EA7CE9438259292F8872AEA6C7BB8AA2C000407DF11F6392DC7F1BD6FAFB34AA220C5457D2E58A2BB2C21DA4878A3E8AB8B0BA2AF2A87E7102D23DE169F880E38688406B34E582B6E978867755E37FB352DB0C";
+    let uik_hex = "020001002200203CB3FC6B9271B308EFEDC029502278DED42FC4AF181A44E31549F53B9BF7436C0005416C696365080700205F334D034259E2D6670D6CA8F5A937EA7CE9438259292F8872AEA6C7BB8AA2C0004027505D9E55A38E22876CD6A4DB08C6CC861D0EBA81D271426B0A7F36323577757315ABB9983AE53C09D22C015DC7C60130F2C5EAE912987E53A0D2969C1D2005";
     let uik_bytes = hex_to_bytes(uik_hex);
     let mut cursor = Cursor::new(&uik_bytes);
 
@@ -530,6 +1298,13 @@ fn test_uik_interop() {
             }
         }
     }
+    let credential_type = u8::decode(&mut cursor).unwrap();
+    assert_eq!(credential_type, CredentialType::Basic as u8);
+    let identity: Vec<u8> = decode_vec_u8(&mut cursor).unwrap();
+    println!(
+        "Found basic credential: identity: {}",
+        String::from_utf8_lossy(&identity)
+    );
     let algorithm = SignatureScheme::decode(&mut cursor).unwrap();
     println!("Found algorithm: {}", algorithm);
     let identity_key: Vec<u8> = decode_vec_u16(&mut cursor).unwrap();
@@ -554,3 +1329,70 @@ fn test_uik_interop() {
 
     assert_eq!(uik_bytes, buffer);
 }
+
+#[test]
+fn test_private_keys_are_zeroized() {
+    let mut rng = test_rng("test_private_keys_are_zeroized");
+    let mut x25519_private_key = X25519KeyPair::new_random(&mut rng).private_key;
+    x25519_private_key.zeroize();
+    assert!(is_zeroized(x25519_private_key.as_slice()));
+
+    let mut p256_private_key = P256KeyPair::new_random(&mut rng).private_key;
+    p256_private_key.zeroize();
+    assert!(is_zeroized(p256_private_key.as_slice()));
+
+    let mut kem_private_key = KemPrivateKey::from_slice(&[0x2A; 32]);
+    kem_private_key.zeroize();
+    assert!(is_zeroized(kem_private_key.as_slice()));
+
+    let mut ed25519_private_key = Ed25519PrivateKey::random(&mut rng);
+    ed25519_private_key.zeroize();
+    assert!(is_zeroized(&ed25519_private_key.0));
+}
+
+#[test]
+fn test_low_order_x25519_keys_are_rejected() {
+    let mut rng = test_rng("test_low_order_x25519_keys_are_rejected");
+    let kp = X25519KeyPair::new_random(&mut rng);
+    let public_key = X25519PublicKey::decode_detached(&kp.public_key.encode_detached()).unwrap();
+    assert_eq!(public_key, kp.public_key);
+
+    for point in X25519_LOW_ORDER_POINTS.iter() {
+        let mut buffer = Vec::new();
+        encode_vec_u16(&mut buffer, point);
+        assert!(X25519PublicKey::decode_detached(&buffer).is_err());
+        // The most significant bit is ignored by X25519
+        let mut high_bit = *point;
+        high_bit[31] |= 0x80;
+        assert!(!X25519PublicKey::from_slice(&high_bit).is_valid());
+        assert!(kp
+            .private_key
+            .shared_secret(&X25519PublicKey::from_slice(point))
+            .is_err());
+    }
+    let mut buffer = Vec::new();
+    encode_vec_u16(&mut buffer, &[0x2Au8; 31]);
+    assert!(X25519PublicKey::decode_detached(&buffer).is_err());
+
+    let identity = Identity::random(&mut rng);
+    let credential = Credential::Basic(BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: identity.public_key,
+    });
+    let init_key = UserInitKey::new(
+        &[AES128GCM_CURVE25519_SHA256],
+        &[KemPublicKey::from_slice(&X25519_LOW_ORDER_POINTS[1])],
+        credential.clone(),
+        &identity,
+    )
+    .unwrap();
+    assert!(!init_key.self_verify());
+    let init_key = UserInitKey::new(
+        &[AES128GCM_CURVE25519_SHA256],
+        &[KemPublicKey::from_slice(&kp.public_key.to_bytes())],
+        credential,
+        &identity,
+    )
+    .unwrap();
+    assert!(init_key.self_verify());
+}
@@ -14,17 +14,42 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see http://www.gnu.org/licenses/.
 
-extern crate libsodium_sys;
+#[cfg(feature = "rust-crypto")]
+extern crate aes_gcm;
+#[cfg(feature = "rust-crypto")]
+extern crate argon2;
+#[cfg(feature = "rust-crypto")]
+extern crate chacha20poly1305;
+#[cfg(feature = "rust-crypto")]
+extern crate ed25519_dalek;
+#[cfg(feature = "rust-crypto")]
+extern crate getrandom;
+#[cfg(feature = "rust-crypto")]
+extern crate hmac;
+extern crate p256;
+extern crate pkcs8;
+#[cfg(feature = "sodium")]
 extern crate ring;
+#[cfg(feature = "rust-crypto")]
+extern crate sha2;
+#[cfg(feature = "sodium")]
 extern crate sodiumoxide;
+#[cfg(feature = "rust-crypto")]
+extern crate x25519_dalek;
+extern crate x509_cert;
 
+pub mod authentication;
 pub mod codec;
 pub mod crypto;
 pub mod group;
+pub mod keyformat;
 pub mod keys;
 pub mod messages;
 pub mod mp;
 pub mod roster;
+pub mod secrettree;
+pub mod signer;
 pub mod tree;
 pub mod treemath;
 pub mod utils;
+pub mod x509;
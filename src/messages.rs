@@ -15,17 +15,59 @@
 // along with this program. If not, see http://www.gnu.org/licenses/.
 
 use codec::*;
-use crypto::eckem::X25519AESCiphertext;
-use crypto::schedule::InitSecret;
+use crypto::aesgcm::AesError;
+use crypto::ciphersuite::*;
+use crypto::eckem::EcKemError;
+use crypto::hkdf::HashAlgorithm;
+use crypto::rng::CryptoRng;
+use crypto::schedule::JoinerSecret;
 use group::*;
 use keys::*;
 use std::convert::From;
 use tree::*;
+use utils::*;
 
 pub enum MessageError {
     UnknownOperation,
 }
 
+#[derive(Debug, PartialEq)]
+pub enum HandshakeError {
+    Duplicate,
+    StaleEpoch,
+    FutureEpoch,
+    Invalid,
+    InvalidInitKey,
+    // The Welcome does not describe a group we are a member of
+    InvalidWelcome,
+    // The credential does not match the init key or is not trusted
+    InvalidCredential,
+    // The authentication service rejected the credential
+    AuthenticationFailed,
+    TreeError(TreeError),
+}
+
+impl From<TreeError> for HandshakeError {
+    fn from(e: TreeError) -> Self {
+        HandshakeError::TreeError(e)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum WelcomeError {
+    // The init key has no key for the cipher suite of the group
+    UnsupportedCipherSuite,
+    EcKemError(EcKemError),
+}
+
+impl From<EcKemError> for WelcomeError {
+    fn from(e: EcKemError) -> Self {
+        WelcomeError::EcKemError(e)
+    }
+}
+
+// Welcomes carry secrets, they are only sent encrypted to the joiner and
+// never as a handshake
 pub const HANDSHAKE_WELCOME: u8 = 1;
 pub const HANDSHAKE_UPDATE: u8 = 2;
 pub const HANDSHAKE_ADD: u8 = 3;
@@ -33,7 +75,6 @@ pub const HANDSHAKE_REMOVE: u8 = 4;
 
 #[derive(Clone)]
 pub enum GroupOperationValue {
-    Welcome(Box<Welcome>),
     Update(Update),
     Add(Add),
     Remove(Remove),
@@ -42,10 +83,6 @@ pub enum GroupOperationValue {
 impl Codec for GroupOperationValue {
     fn encode(&self, buffer: &mut Vec<u8>) {
         match self {
-            GroupOperationValue::Welcome(welcome) => {
-                HANDSHAKE_WELCOME.encode(buffer);
-                welcome.encode(buffer);
-            }
             GroupOperationValue::Update(update) => {
                 HANDSHAKE_UPDATE.encode(buffer);
                 update.encode(buffer);
@@ -63,9 +100,6 @@ impl Codec for GroupOperationValue {
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
         let handshake_type = cursor.take(1)?[0];
         match handshake_type {
-            HANDSHAKE_WELCOME => Ok(GroupOperationValue::Welcome(Box::new(Welcome::decode(
-                cursor,
-            )?))),
             HANDSHAKE_UPDATE => Ok(GroupOperationValue::Update(Update::decode(cursor)?)),
             HANDSHAKE_ADD => Ok(GroupOperationValue::Add(Add::decode(cursor)?)),
             HANDSHAKE_REMOVE => Ok(GroupOperationValue::Remove(Remove::decode(cursor)?)),
@@ -146,13 +180,40 @@ impl Signable for Handshake {
     }
 }
 
+#[derive(Clone, PartialEq, Debug)]
+pub struct HandshakeDigest(pub Vec<u8>);
+
+impl Codec for HandshakeDigest {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        encode_vec_u8(buffer, &self.0);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let digest = decode_vec_u8(cursor)?;
+        // The group checks the length against its own cipher suite
+        if digest.len() != HashAlgorithm::Sha256.output_length()
+            && digest.len() != HashAlgorithm::Sha512.output_length()
+        {
+            return Err(CodecError::DecodingError);
+        }
+        Ok(HandshakeDigest(digest))
+    }
+}
+
+impl Handshake {
+    // The signature is left out, so that a re-signed copy of the same
+    // operation is still recognized as a duplicate.
+    pub fn digest(&self, cipher_suite: &CipherSuiteProvider) -> HandshakeDigest {
+        HandshakeDigest(cipher_suite.kdf.hash(&self.unsigned_payload()))
+    }
+}
+
 impl Codec for Handshake {
     fn encode(&self, buffer: &mut Vec<u8>) {
         self.prior_epoch.encode(buffer);
         self.operation.encode(buffer);
         self.signer_index.encode(buffer);
         self.algorithm.encode(buffer);
-        self.signature.unwrap().encode(buffer);
+        self.signature.as_ref().unwrap().encode(buffer);
     }
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
         let prior_epoch = GroupEpoch::decode(cursor)?;
@@ -170,14 +231,14 @@ impl Codec for Handshake {
     }
 }
 
-#[derive(Clone)]
 pub struct Welcome {
     pub group_id: GroupId,
     pub epoch: GroupEpoch,
-    pub roster: Vec<BasicCredential>,
-    pub tree: Vec<X25519PublicKey>,
+    pub cipher_suite: &'static CipherSuiteProvider,
+    pub roster: Vec<Credential>,
+    pub tree: Vec<KemPublicKey>,
     pub transcript: Vec<GroupOperationValue>,
-    pub init_secret: InitSecret,
+    pub joiner_secret: JoinerSecret,
     pub leaf_secret: NodeSecret,
 }
 
@@ -185,54 +246,199 @@ impl Codec for Welcome {
     fn encode(&self, buffer: &mut Vec<u8>) {
         self.group_id.encode(buffer);
         self.epoch.encode(buffer);
-        encode_vec_u16(buffer, &self.roster);
+        self.cipher_suite.encode(buffer);
+        encode_vec_u32(buffer, &self.roster);
         encode_vec_u16(buffer, &self.tree);
         encode_vec_u16(buffer, &self.transcript);
-        self.init_secret.encode(buffer);
+        self.joiner_secret.encode(buffer);
         self.leaf_secret.encode(buffer);
     }
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
         let group_id = GroupId::decode(cursor)?;
         let epoch = GroupEpoch::decode(cursor)?;
-        let roster = decode_vec_u16(cursor)?;
+        let cipher_suite = <&CipherSuiteProvider>::decode(cursor)?;
+        let roster = decode_vec_u32(cursor)?;
         let tree = decode_vec_u16(cursor)?;
         let transcript = decode_vec_u16(cursor)?;
-        let init_secret = InitSecret::decode(cursor)?;
+        let joiner_secret = JoinerSecret::decode(cursor)?;
         let leaf_secret = NodeSecret::decode(cursor)?;
         Ok(Welcome {
             group_id,
             epoch,
+            cipher_suite,
             roster,
             tree,
             transcript,
-            init_secret,
+            joiner_secret,
             leaf_secret,
         })
     }
 }
 
+// HPKE info for Welcome messages encrypted to a UserInitKey
+pub const WELCOME_INFO: &[u8] = b"MLS 1.0 welcome";
+
+impl Welcome {
+    // Encrypts the Welcome to the joiner's init key for the group's cipher
+    // suite. The cipher suite is bound as associated data.
+    pub fn encrypt(
+        &self,
+        init_key: &UserInitKey,
+        rng: &mut dyn CryptoRng,
+    ) -> Result<EncryptedWelcome, WelcomeError> {
+        let public_key = match init_key.get_init_key(self.cipher_suite.id) {
+            Some(public_key) => public_key,
+            None => return Err(WelcomeError::UnsupportedCipherSuite),
+        };
+        let mut payload = self.encode_detached();
+        let ciphertext = self.cipher_suite.kem.encrypt(
+            public_key,
+            WELCOME_INFO,
+            &self.cipher_suite.encode_detached(),
+            &payload,
+            rng,
+        );
+        erase(&mut payload);
+        let ciphertext = ciphertext?;
+        Ok(EncryptedWelcome {
+            cipher_suite: self.cipher_suite,
+            enc: ciphertext.public_key,
+            ciphertext: ciphertext.sealed_box,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct EncryptedWelcome {
+    pub cipher_suite: &'static CipherSuiteProvider,
+    pub enc: KemPublicKey,
+    pub ciphertext: Vec<u8>,
+}
+
+impl EncryptedWelcome {
+    pub fn decrypt(&self, init_key_bundle: &UserInitKeyBundle) -> Result<Welcome, WelcomeError> {
+        let private_key = match init_key_bundle.get_private_key(self.cipher_suite.id) {
+            Some(private_key) => private_key,
+            None => return Err(WelcomeError::UnsupportedCipherSuite),
+        };
+        let ciphertext = KemCiphertext {
+            public_key: self.enc.clone(),
+            sealed_box: self.ciphertext.clone(),
+        };
+        let mut plaintext = self.cipher_suite.kem.decrypt(
+            private_key,
+            WELCOME_INFO,
+            &self.cipher_suite.encode_detached(),
+            &ciphertext,
+        )?;
+        let welcome = Welcome::decode_detached(&plaintext);
+        erase(&mut plaintext);
+        match welcome {
+            Ok(ref welcome) if welcome.cipher_suite != self.cipher_suite => {
+                Err(AesError::DecryptionError.into())
+            }
+            Ok(welcome) => Ok(welcome),
+            Err(_) => Err(AesError::DecryptionError.into()),
+        }
+    }
+}
+
+impl Codec for EncryptedWelcome {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.cipher_suite.encode(buffer);
+        self.enc.encode(buffer);
+        encode_vec_u32(buffer, &self.ciphertext);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let cipher_suite = <&CipherSuiteProvider>::decode(cursor)?;
+        let enc = KemPublicKey::decode(cursor)?;
+        let ciphertext = decode_vec_u32(cursor)?;
+        Ok(EncryptedWelcome {
+            cipher_suite,
+            enc,
+            ciphertext,
+        })
+    }
+}
+
 #[derive(Clone, Hash)]
 pub struct Update {
-    pub nodes: Vec<X25519PublicKey>,
-    pub path: Vec<X25519AESCiphertext>,
+    pub nodes: Vec<KemPublicKey>,
+    pub path: Vec<KemCiphertext>,
+    pub identity_key: Option<IdentityKeyUpdate>,
 }
 
 impl Codec for Update {
     fn encode(&self, buffer: &mut Vec<u8>) {
         encode_vec_u16(buffer, &self.nodes);
         encode_vec_u16(buffer, &self.path);
+        self.identity_key.encode(buffer);
     }
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
         let nodes = decode_vec_u16(cursor)?;
         let path = decode_vec_u16(cursor)?;
-        Ok(Update { nodes, path })
+        let identity_key = Option::<IdentityKeyUpdate>::decode(cursor)?;
+        Ok(Update {
+            nodes,
+            path,
+            identity_key,
+        })
+    }
+}
+
+// Replaces the credential and identity key of the sender of an update. The
+// old and the new key both sign IdentityKeyUpdateContent, so that the
+// update stands on its own without the signature of the handshake.
+#[derive(Clone, Hash)]
+pub struct IdentityKeyUpdate {
+    pub credential: Credential,
+    pub old_key_signature: Signature,
+    pub new_key_signature: Signature,
+}
+
+impl Codec for IdentityKeyUpdate {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.credential.encode(buffer);
+        self.old_key_signature.encode(buffer);
+        self.new_key_signature.encode(buffer);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let credential = Credential::decode(cursor)?;
+        let old_key_signature = Signature::decode(cursor)?;
+        let new_key_signature = Signature::decode(cursor)?;
+        Ok(IdentityKeyUpdate {
+            credential,
+            old_key_signature,
+            new_key_signature,
+        })
+    }
+}
+
+// Binds the new credential to the group, the epoch and the new leaf key
+pub struct IdentityKeyUpdateContent {
+    pub group: Vec<u8>,
+    pub epoch: GroupEpoch,
+    pub sender: u32,
+    pub nodes: Vec<KemPublicKey>,
+    pub credential: Credential,
+}
+
+impl Signable for IdentityKeyUpdateContent {
+    fn unsigned_payload(&self) -> Vec<u8> {
+        let buffer = &mut Vec::new();
+        encode_vec_u8(buffer, &self.group);
+        self.epoch.encode(buffer);
+        self.sender.encode(buffer);
+        encode_vec_u16(buffer, &self.nodes);
+        self.credential.encode(buffer);
+        buffer.to_vec()
     }
 }
 
 #[derive(Clone)]
 pub struct Add {
-    pub nodes: Vec<X25519PublicKey>,
-    pub path: Vec<X25519AESCiphertext>,
+    pub nodes: Vec<KemPublicKey>,
+    pub path: Vec<KemCiphertext>,
     pub init_key: UserInitKey,
 }
 
@@ -257,8 +463,8 @@ impl Codec for Add {
 #[derive(Clone)]
 pub struct Remove {
     pub removed: usize,
-    pub nodes: Vec<X25519PublicKey>,
-    pub path: Vec<X25519AESCiphertext>,
+    pub nodes: Vec<KemPublicKey>,
+    pub path: Vec<KemCiphertext>,
 }
 
 impl Codec for Remove {
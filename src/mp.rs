@@ -15,63 +15,377 @@
 // along with this program. If not, see http://www.gnu.org/licenses/.
 
 use codec::*;
+use crypto::aesgcm::AesError;
+use crypto::ciphersuite::*;
+use crypto::rng::CryptoRng;
 use crypto::schedule::*;
-use crypto::{aesgcm, hkdf};
+use keys::*;
+use secrettree::SecretTreeError;
+use signer::SignerError;
+use std::cmp;
 use utils::*;
 
-const APPLICATION_SECRET_SIZE: usize = 32;
+pub const REUSE_GUARD_BYTES: usize = 4;
+
+pub type ContentType = u8;
+pub const CONTENT_TYPE_APPLICATION: ContentType = 1;
 
 pub struct ApplicationPlaintext {
-    content: Vec<u8>,   // opaque content<0..2^32-1>;
-    signature: Vec<u8>, // opaque signature<0..2^16-1>;
-    zeros: Vec<u8>,     // uint8 zeros[length_of_padding];
+    pub content: Vec<u8>,     // opaque content<0..2^32-1>;
+    pub signature: Signature, // opaque signature<0..2^16-1>;
+    pub zeros: Vec<u8>,       // uint8 zeros[length_of_padding];
+}
+
+impl ApplicationPlaintext {
+    pub fn pad(&mut self, policy: &PaddingPolicy) {
+        let length = self.encode_detached().len() - self.zeros.len();
+        self.zeros = vec![0u8; policy.padding_length(length)];
+    }
+    pub fn strip_padding(&mut self) -> Result<(), ApplicationMessageError> {
+        let nonzero = self.zeros.iter().fold(0u8, |acc, b| acc | b);
+        if nonzero != 0 {
+            return Err(ApplicationMessageError::InvalidPadding);
+        }
+        self.zeros.clear();
+        Ok(())
+    }
+}
+
+impl Codec for ApplicationPlaintext {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        encode_vec_u32(buffer, &self.content);
+        self.signature.encode(buffer);
+        buffer.extend_from_slice(&self.zeros);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let content = decode_vec_u32(cursor)?;
+        let signature = Signature::decode(cursor)?;
+        let zeros = cursor.read_to_end().to_vec();
+        Ok(ApplicationPlaintext {
+            content,
+            signature,
+            zeros,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PaddingPolicy {
+    None,
+    Multiple(u32),
+    PowerOfTwo(u32),
+}
+
+pub const PADDING_NONE: u8 = 0;
+pub const PADDING_MULTIPLE: u8 = 1;
+pub const PADDING_POWER_OF_TWO: u8 = 2;
+
+impl PaddingPolicy {
+    // Number of zero bytes to append to a plaintext of the given length.
+    // Power-of-two buckets are capped: above the cap, lengths are padded to a
+    // multiple of the cap instead.
+    pub fn padding_length(&self, length: usize) -> usize {
+        match *self {
+            PaddingPolicy::None => 0,
+            PaddingPolicy::Multiple(n) => round_up(length, n as usize) - length,
+            PaddingPolicy::PowerOfTwo(cap) => {
+                let bucket = length.next_power_of_two();
+                if cap > 0 && bucket > cap as usize {
+                    round_up(length, cap as usize) - length
+                } else {
+                    bucket - length
+                }
+            }
+        }
+    }
+}
+
+fn round_up(length: usize, multiple: usize) -> usize {
+    if multiple == 0 {
+        return length;
+    }
+    length.div_ceil(multiple) * multiple
+}
+
+impl Codec for PaddingPolicy {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        match *self {
+            PaddingPolicy::None => PADDING_NONE.encode(buffer),
+            PaddingPolicy::Multiple(n) => {
+                PADDING_MULTIPLE.encode(buffer);
+                n.encode(buffer);
+            }
+            PaddingPolicy::PowerOfTwo(cap) => {
+                PADDING_POWER_OF_TWO.encode(buffer);
+                cap.encode(buffer);
+            }
+        }
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        match u8::decode(cursor)? {
+            PADDING_NONE => Ok(PaddingPolicy::None),
+            PADDING_MULTIPLE => Ok(PaddingPolicy::Multiple(u32::decode(cursor)?)),
+            PADDING_POWER_OF_TWO => Ok(PaddingPolicy::PowerOfTwo(u32::decode(cursor)?)),
+            _ => Err(CodecError::DecodingError),
+        }
+    }
+}
+
+pub struct SenderData {
+    pub sender: u32,                          // uint32 sender;
+    pub generation: u32,                      // uint32 generation;
+    pub reuse_guard: [u8; REUSE_GUARD_BYTES], // uint8  reuse_guard[4];
+}
+
+impl SenderData {
+    pub fn new(sender: u32, generation: u32, rng: &mut dyn CryptoRng) -> Self {
+        let mut reuse_guard = [0u8; REUSE_GUARD_BYTES];
+        rng.fill_bytes(&mut reuse_guard);
+        SenderData {
+            sender,
+            generation,
+            reuse_guard,
+        }
+    }
+}
+
+impl Codec for SenderData {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.sender.encode(buffer);
+        self.generation.encode(buffer);
+        buffer.extend_from_slice(&self.reuse_guard);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let sender = u32::decode(cursor)?;
+        let generation = u32::decode(cursor)?;
+        let mut reuse_guard = [0u8; REUSE_GUARD_BYTES];
+        reuse_guard.copy_from_slice(cursor.take(REUSE_GUARD_BYTES)?);
+        Ok(SenderData {
+            sender,
+            generation,
+            reuse_guard,
+        })
+    }
 }
 
 pub struct ApplicationMessage {
-    group: Vec<u8>,             // uint8  group[32];
-    epoch: u32,                 // uint32 epoch;
-    generation: u32,            // uint32 generation;
-    sender: u32,                // uint32 sender;
-    encrypted_content: Vec<u8>, // opaque encrypted_content<0..2^32-1>;
+    pub group: Vec<u8>,                 // uint8  group[32];
+    pub epoch: u32,                     // uint32 epoch;
+    pub encrypted_sender_data: Vec<u8>, // opaque encrypted_sender_data<0..255>;
+    pub encrypted_content: Vec<u8>,     // opaque encrypted_content<0..2^32-1>;
+}
+
+impl Codec for ApplicationMessage {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        encode_vec_u8(buffer, &self.group);
+        self.epoch.encode(buffer);
+        encode_vec_u8(buffer, &self.encrypted_sender_data);
+        encode_vec_u32(buffer, &self.encrypted_content);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let group = decode_vec_u8(cursor)?;
+        let epoch = u32::decode(cursor)?;
+        let encrypted_sender_data = decode_vec_u8(cursor)?;
+        let encrypted_content = decode_vec_u32(cursor)?;
+        Ok(ApplicationMessage {
+            group,
+            epoch,
+            encrypted_sender_data,
+            encrypted_content,
+        })
+    }
 }
 
 pub struct SignatureContent {
-    group: Vec<u8>,   // uint8  group[32];
-    epoch: u32,       // uint32 epoch;
-    generation: u32,  // uint32 generation;
-    sender: u32,      // uint32 sender;
-    content: Vec<u8>, // opaque encrypted_content<0..2^32-1>;
+    pub group: Vec<u8>,   // uint8  group[32];
+    pub epoch: u32,       // uint32 epoch;
+    pub generation: u32,  // uint32 generation;
+    pub sender: u32,      // uint32 sender;
+    pub content: Vec<u8>, // opaque encrypted_content<0..2^32-1>;
 }
 
-impl ApplicationMessage {}
+impl Signable for SignatureContent {
+    fn unsigned_payload(&self) -> Vec<u8> {
+        let buffer = &mut Vec::new();
+        encode_vec_u8(buffer, &self.group);
+        self.epoch.encode(buffer);
+        self.generation.encode(buffer);
+        self.sender.encode(buffer);
+        encode_vec_u32(buffer, &self.content);
+        buffer.to_vec()
+    }
+}
 
-pub fn hkdf_expand_label(secret: &[u8], label: &str, context: &[u8], length: usize) -> Vec<u8> {
-    let mut prk_value = [0u8; APPLICATION_SECRET_SIZE];
-    prk_value.clone_from_slice(&secret[..32]);
-    let prk = hkdf::Prk(prk_value);
+#[derive(Debug)]
+pub enum ApplicationMessageError {
+    NoEpochSecrets,
+    WrongGroup,
+    WrongEpoch,
+    UnknownSender,
+    InvalidSignature,
+    InvalidPadding,
+    Replay,
+    StageError(StageError),
+    SecretTreeError(SecretTreeError),
+    AesError(AesError),
+    CodecError(CodecError),
+    SignerError(SignerError),
+}
 
-    let hkdf_label = HkdfLabel::new(context, label);
-    let state = &hkdf_label.serialize();
+impl From<StageError> for ApplicationMessageError {
+    fn from(e: StageError) -> Self {
+        ApplicationMessageError::StageError(e)
+    }
+}
 
-    println!("HKDFLabel for label '{}': {}", label, bytes_to_hex(&state));
+impl From<SecretTreeError> for ApplicationMessageError {
+    fn from(e: SecretTreeError) -> Self {
+        ApplicationMessageError::SecretTreeError(e)
+    }
+}
 
-    let info = hkdf::Info(state);
-    hkdf::expand(prk, info, length)
+impl From<AesError> for ApplicationMessageError {
+    fn from(e: AesError) -> Self {
+        ApplicationMessageError::AesError(e)
+    }
+}
+
+impl From<CodecError> for ApplicationMessageError {
+    fn from(e: CodecError) -> Self {
+        ApplicationMessageError::CodecError(e)
+    }
+}
+
+impl From<SignerError> for ApplicationMessageError {
+    fn from(e: SignerError) -> Self {
+        ApplicationMessageError::SignerError(e)
+    }
+}
+
+// The sender data key and nonce are derived from a sample of the encrypted
+// content, so that every message uses a fresh key/nonce pair without having
+// to transmit one.
+fn sender_data_secrets(
+    cipher_suite: &CipherSuiteProvider,
+    sender_data_secret: &[u8],
+    encrypted_content: &[u8],
+) -> (Vec<u8>, Vec<u8>) {
+    let sample_len = cmp::min(encrypted_content.len(), cipher_suite.kdf.hash_length());
+    let sample = &encrypted_content[..sample_len];
+    let key = expand_with_label(
+        cipher_suite,
+        sender_data_secret,
+        "sd key",
+        sample,
+        cipher_suite.aead.key_length(),
+    );
+    let nonce = expand_with_label(
+        cipher_suite,
+        sender_data_secret,
+        "sd nonce",
+        sample,
+        cipher_suite.aead.nonce_length(),
+    );
+    (key, nonce)
+}
+
+// Both the content and the sender data are bound to the group, the epoch
+// and the content type, so that a ciphertext cannot be replayed in another
+// context.
+fn message_aad(group: &[u8], epoch: u32, content_type: ContentType) -> Vec<u8> {
+    let mut aad = Vec::new();
+    encode_vec_u8(&mut aad, group);
+    epoch.encode(&mut aad);
+    content_type.encode(&mut aad);
+    aad
+}
+
+// The reuse guard is XORed into the first bytes of the content nonce, so that
+// a stage secret accidentally used twice does not lead to nonce reuse.
+fn content_nonce(stage_secrets: &StageSecrets, reuse_guard: &[u8; REUSE_GUARD_BYTES]) -> Vec<u8> {
+    let mut nonce = stage_secrets.nonce.clone();
+    for (n, g) in nonce.iter_mut().zip(reuse_guard.iter()) {
+        *n ^= *g;
+    }
+    nonce
+}
+
+impl ApplicationMessage {
+    pub fn new(
+        cipher_suite: &CipherSuiteProvider,
+        group: &[u8],
+        epoch: u32,
+        sender_data: &SenderData,
+        plaintext: &ApplicationPlaintext,
+        stage_secrets: &StageSecrets,
+        sender_data_secret: &[u8],
+    ) -> Result<Self, ApplicationMessageError> {
+        let aad = message_aad(group, epoch, CONTENT_TYPE_APPLICATION);
+        let nonce = content_nonce(stage_secrets, &sender_data.reuse_guard);
+        let encrypted_content = cipher_suite.aead.seal(
+            &stage_secrets.key,
+            &nonce,
+            &aad,
+            &plaintext.encode_detached(),
+        )?;
+
+        let (sd_key, sd_nonce) =
+            sender_data_secrets(cipher_suite, sender_data_secret, &encrypted_content);
+        let encrypted_sender_data =
+            cipher_suite
+                .aead
+                .seal(&sd_key, &sd_nonce, &aad, &sender_data.encode_detached())?;
+
+        Ok(ApplicationMessage {
+            group: group.to_vec(),
+            epoch,
+            encrypted_sender_data,
+            encrypted_content,
+        })
+    }
+    pub fn decrypt_sender_data(
+        &self,
+        cipher_suite: &CipherSuiteProvider,
+        sender_data_secret: &[u8],
+    ) -> Result<SenderData, ApplicationMessageError> {
+        let aad = message_aad(&self.group, self.epoch, CONTENT_TYPE_APPLICATION);
+        let (sd_key, sd_nonce) =
+            sender_data_secrets(cipher_suite, sender_data_secret, &self.encrypted_content);
+        let sender_data_bytes =
+            cipher_suite
+                .aead
+                .open(&sd_key, &sd_nonce, &aad, &self.encrypted_sender_data)?;
+        Ok(SenderData::decode_detached(&sender_data_bytes)?)
+    }
+    pub fn decrypt_content(
+        &self,
+        cipher_suite: &CipherSuiteProvider,
+        sender_data: &SenderData,
+        stage_secrets: &StageSecrets,
+    ) -> Result<ApplicationPlaintext, ApplicationMessageError> {
+        let aad = message_aad(&self.group, self.epoch, CONTENT_TYPE_APPLICATION);
+        let nonce = content_nonce(stage_secrets, &sender_data.reuse_guard);
+        let plaintext_bytes =
+            cipher_suite
+                .aead
+                .open(&stage_secrets.key, &nonce, &aad, &self.encrypted_content)?;
+        let mut plaintext = ApplicationPlaintext::decode_detached(&plaintext_bytes)?;
+        plaintext.strip_padding()?;
+        Ok(plaintext)
+    }
 }
 
-#[derive(Clone)]
 pub struct StageSecrets {
-    pub nonce: [u8; aesgcm::NONCEBYTES],
-    pub key: [u8; aesgcm::AES128KEYBYTES],
+    pub nonce: Vec<u8>,
+    pub key: Vec<u8>,
 }
 
 impl StageSecrets {
     pub fn new(nonce_bytes: &[u8], key_bytes: &[u8]) -> Self {
-        let mut nonce = [0u8; aesgcm::NONCEBYTES];
-        let mut key = [0u8; aesgcm::AES128KEYBYTES];
-        nonce.clone_from_slice(&nonce_bytes[..aesgcm::NONCEBYTES]);
-        key.clone_from_slice(&key_bytes[..aesgcm::AES128KEYBYTES]);
-        Self { nonce, key }
+        Self {
+            nonce: nonce_bytes.to_vec(),
+            key: key_bytes.to_vec(),
+        }
     }
 }
 
@@ -81,100 +395,458 @@ impl Codec for StageSecrets {
         encode_vec_u8(buffer, &self.key);
     }
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
-        let mut nonce = <[u8; aesgcm::NONCEBYTES]>::default();
-        let mut key = <[u8; aesgcm::AES128KEYBYTES]>::default();
-        nonce.clone_from_slice(&decode_vec_u8(cursor)?);
-        key.clone_from_slice(&decode_vec_u8(cursor)?);
+        let nonce = decode_vec_u8(cursor)?;
+        let key = decode_vec_u8(cursor)?;
         Ok(StageSecrets { nonce, key })
     }
 }
 
+impl ConstantTimeEq for StageSecrets {
+    fn ct_eq(&self, other: &Self) -> bool {
+        let nonce = constant_time_eq(&self.nonce, &other.nonce);
+        let key = constant_time_eq(&self.key, &other.key);
+        nonce & key
+    }
+}
+
+impl Zeroize for StageSecrets {
+    fn zeroize(&mut self) {
+        erase(&mut self.nonce);
+        erase(&mut self.key);
+    }
+}
+
+impl Drop for StageSecrets {
+    fn drop(&mut self) {
+        self.zeroize()
+    }
+}
+
 #[derive(Debug)]
 pub enum StageError {
     TooFarInThePast,
     TooFarInTheFuture,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RatchetConfig {
+    pub maximum_forward_distance: u32,
+    pub out_of_order_tolerance: u32,
+}
+
+impl Default for RatchetConfig {
+    fn default() -> Self {
+        RatchetConfig {
+            maximum_forward_distance: 1000,
+            out_of_order_tolerance: 32,
+        }
+    }
+}
+
+impl Codec for RatchetConfig {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.maximum_forward_distance.encode(buffer);
+        self.out_of_order_tolerance.encode(buffer);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let maximum_forward_distance = u32::decode(cursor)?;
+        let out_of_order_tolerance = u32::decode(cursor)?;
+        Ok(RatchetConfig {
+            maximum_forward_distance,
+            out_of_order_tolerance,
+        })
+    }
+}
+
 pub struct SenderApplicationSecret {
-    value: [u8; APPLICATION_SECRET_SIZE],
+    cipher_suite: &'static CipherSuiteProvider,
+    value: Vec<u8>,
     sender: u32,
     stage: usize,
+    config: RatchetConfig,
+    // Stage secrets that were skipped over, ordered by generation
+    skipped: Vec<(u32, StageSecrets)>,
 }
 
 impl SenderApplicationSecret {
-    pub fn from_bytes_for_sender(bytes: &[u8], sender: u32) -> Self {
-        let mut init_value = [0u8; APPLICATION_SECRET_SIZE];
-        init_value.clone_from_slice(&bytes[..APPLICATION_SECRET_SIZE]);
+    pub fn from_bytes_for_sender(
+        cipher_suite: &'static CipherSuiteProvider,
+        bytes: &[u8],
+        sender: u32,
+    ) -> Self {
         let stage = 0;
         let mut context = Vec::new();
-        (sender as u32).encode(&mut context);
-
-        let new_value = hkdf_expand_label(bytes, "app sender", &context, APPLICATION_SECRET_SIZE);
-        let mut value = [0u8; APPLICATION_SECRET_SIZE];
-        value.clone_from_slice(&new_value[..APPLICATION_SECRET_SIZE]);
+        sender.encode(&mut context);
+
+        let value = expand_with_label(
+            cipher_suite,
+            bytes,
+            "app sender",
+            &context,
+            cipher_suite.kdf.hash_length(),
+        );
         Self {
+            cipher_suite,
             value,
             sender,
             stage,
+            config: RatchetConfig::default(),
+            skipped: Vec::new(),
+        }
+    }
+    pub fn from_leaf_secret(
+        cipher_suite: &'static CipherSuiteProvider,
+        bytes: &[u8],
+        sender: u32,
+        config: RatchetConfig,
+    ) -> Self {
+        let value = bytes[..cipher_suite.kdf.hash_length()].to_vec();
+        Self {
+            cipher_suite,
+            value,
+            sender,
+            stage: 0,
+            config,
+            skipped: Vec::new(),
         }
     }
     pub fn get_secret_for_stage(&mut self, stage: usize) -> Result<StageSecrets, StageError> {
         if stage <= self.stage {
-            return Err(StageError::TooFarInThePast);
+            // Stage secrets are handed out at most once
+            return match self.skipped.iter().position(|s| s.0 as usize == stage) {
+                Some(position) => Ok(self.skipped.remove(position).1),
+                None => Err(StageError::TooFarInThePast),
+            };
+        }
+        self.check_forward_distance(stage)?;
+        loop {
+            let stage_secrets = self.derive_stage_secrets(&self.value);
+            let next_value = self.derive_next_value(&self.value);
+            erase(&mut self.value);
+            self.value = next_value;
+            self.stage += 1;
+            if self.stage == stage {
+                return Ok(stage_secrets);
+            }
+            self.skip(stage_secrets);
+        }
+    }
+    // Like get_secret_for_stage, but neither moves the ratchet nor uses up a
+    // cached stage, so that a message can be authenticated first. The stage
+    // secrets are only lent to `f` and never leave the ratchet.
+    pub fn peek_secret_for_stage<T, F>(&self, stage: usize, f: F) -> Result<T, StageError>
+    where
+        F: FnOnce(&StageSecrets) -> T,
+    {
+        if stage <= self.stage {
+            return match self.skipped.iter().find(|s| s.0 as usize == stage) {
+                Some(skipped) => Ok(f(&skipped.1)),
+                None => Err(StageError::TooFarInThePast),
+            };
         }
-        let steps = stage - self.stage;
-        if steps > 1000 {
+        self.check_forward_distance(stage)?;
+        let mut value = self.value.clone();
+        for _ in self.stage + 1..stage {
+            let next_value = self.derive_next_value(&value);
+            erase(&mut value);
+            value = next_value;
+        }
+        let stage_secrets = self.derive_stage_secrets(&value);
+        erase(&mut value);
+        Ok(f(&stage_secrets))
+    }
+    fn check_forward_distance(&self, stage: usize) -> Result<(), StageError> {
+        if stage - self.stage > self.config.maximum_forward_distance as usize {
             return Err(StageError::TooFarInTheFuture);
         }
+        Ok(())
+    }
+    fn derive_stage_secrets(&self, value: &[u8]) -> StageSecrets {
+        let cipher_suite = self.cipher_suite;
+        StageSecrets {
+            nonce: expand_with_label(
+                cipher_suite,
+                value,
+                "nonce",
+                &[],
+                cipher_suite.aead.nonce_length(),
+            ),
+            key: expand_with_label(
+                cipher_suite,
+                value,
+                "key",
+                &[],
+                cipher_suite.aead.key_length(),
+            ),
+        }
+    }
+    fn derive_next_value(&self, value: &[u8]) -> Vec<u8> {
+        let mut context = Vec::new();
+        self.sender.encode(&mut context);
+        expand_with_label(
+            self.cipher_suite,
+            value,
+            "app sender",
+            &context,
+            self.cipher_suite.kdf.hash_length(),
+        )
+    }
+    pub fn get_sender(&self) -> u32 {
+        self.sender
+    }
+    pub fn get_stage(&self) -> usize {
+        self.stage
+    }
+    // Keeps the stage secrets for the current stage around for messages that
+    // arrive out of order. The oldest entries are evicted first.
+    fn skip(&mut self, stage_secrets: StageSecrets) {
+        let tolerance = self.config.out_of_order_tolerance as usize;
+        if tolerance == 0 {
+            return;
+        }
+        if self.skipped.len() >= tolerance {
+            self.skipped.remove(0);
+        }
+        self.skipped.push((self.stage as u32, stage_secrets));
+    }
+}
 
-        let nonce_bytes = [0u8; aesgcm::NONCEBYTES];
-        let key_bytes = [0u8; aesgcm::AES128KEYBYTES];
-        let mut stage_secrets = StageSecrets::new(&nonce_bytes, &key_bytes);
+impl Zeroize for SenderApplicationSecret {
+    fn zeroize(&mut self) {
+        erase(&mut self.value);
+        for (_, stage_secrets) in self.skipped.iter_mut() {
+            stage_secrets.zeroize();
+        }
+    }
+}
 
-        for _ in 0..steps {
-            let mut context = Vec::new();
-            (self.sender as u32).encode(&mut context);
+impl Drop for SenderApplicationSecret {
+    fn drop(&mut self) {
+        self.zeroize()
+    }
+}
 
-            let nonce = hkdf_expand_label(&self.value, "nonce", &[], aesgcm::NONCEBYTES);
-            let key = hkdf_expand_label(&self.value, "key", &[], aesgcm::AES128KEYBYTES);
-            stage_secrets = StageSecrets::new(&nonce, &key);
-            let next_value =
-                hkdf_expand_label(&self.value, "app sender", &context, APPLICATION_SECRET_SIZE);
-            self.value.copy_from_slice(&next_value);
-            self.stage += 1;
-        }
+impl Codec for SenderApplicationSecret {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.cipher_suite.encode(buffer);
+        encode_vec_u8(buffer, &self.value);
+        self.sender.encode(buffer);
+        (self.stage as u32).encode(buffer);
+        self.config.encode(buffer);
+        encode_vec_u32(buffer, &self.skipped);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let cipher_suite = <&CipherSuiteProvider>::decode(cursor)?;
+        let value = decode_vec_u8(cursor)?;
+        let sender = u32::decode(cursor)?;
+        let stage = u32::decode(cursor)? as usize;
+        let config = RatchetConfig::decode(cursor)?;
+        let skipped = decode_vec_u32(cursor)?;
+        Ok(SenderApplicationSecret {
+            cipher_suite,
+            value,
+            sender,
+            stage,
+            config,
+            skipped,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConsumedGeneration {
+    pub epoch: u32,
+    pub sender: u32,
+    pub generation: u32,
+}
+
+impl Codec for ConsumedGeneration {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.epoch.encode(buffer);
+        self.sender.encode(buffer);
+        self.generation.encode(buffer);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let epoch = u32::decode(cursor)?;
+        let sender = u32::decode(cursor)?;
+        let generation = u32::decode(cursor)?;
+        Ok(ConsumedGeneration {
+            epoch,
+            sender,
+            generation,
+        })
+    }
+}
+
+// Remembers which generations were already decrypted. Only generations that
+// the ratchet could still hand out are kept, anything older is rejected by
+// the ratchet itself.
+#[derive(Clone, Default)]
+pub struct ReplayWindow {
+    consumed: Vec<ConsumedGeneration>,
+}
 
-        Ok(stage_secrets)
+impl ReplayWindow {
+    pub fn contains(&self, epoch: u32, sender: u32, generation: u32) -> bool {
+        let entry = ConsumedGeneration {
+            epoch,
+            sender,
+            generation,
+        };
+        self.consumed.contains(&entry)
+    }
+    pub fn insert(&mut self, epoch: u32, sender: u32, generation: u32, oldest_generation: u32) {
+        self.consumed.retain(|c| {
+            c.epoch == epoch && (c.sender != sender || c.generation >= oldest_generation)
+        });
+        self.consumed.push(ConsumedGeneration {
+            epoch,
+            sender,
+            generation,
+        });
+    }
+    pub fn len(&self) -> usize {
+        self.consumed.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.consumed.is_empty()
     }
 }
 
+impl Codec for ReplayWindow {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        encode_vec_u32(buffer, &self.consumed);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let consumed = decode_vec_u32(cursor)?;
+        Ok(ReplayWindow { consumed })
+    }
+}
+
+#[test]
+fn test_padding_policy() {
+    assert_eq!(PaddingPolicy::None.padding_length(100), 0);
+
+    assert_eq!(PaddingPolicy::Multiple(32).padding_length(100), 28);
+    assert_eq!(PaddingPolicy::Multiple(32).padding_length(128), 0);
+
+    assert_eq!(PaddingPolicy::PowerOfTwo(1024).padding_length(100), 28);
+    assert_eq!(PaddingPolicy::PowerOfTwo(1024).padding_length(1024), 0);
+    assert_eq!(PaddingPolicy::PowerOfTwo(1024).padding_length(1500), 548);
+
+    let policy = PaddingPolicy::PowerOfTwo(4096);
+    assert_eq!(
+        PaddingPolicy::decode_detached(&policy.encode_detached()).unwrap(),
+        policy
+    );
+}
+
 #[test]
+fn test_application_plaintext_padding() {
+    let mut rng = test_rng("test_application_plaintext_padding");
+    let identity = Identity::random(&mut rng);
+    let mut plaintext = ApplicationPlaintext {
+        content: b"Hello".to_vec(),
+        signature: identity.sign(b"Hello"),
+        zeros: Vec::new(),
+    };
+    plaintext.pad(&PaddingPolicy::Multiple(64));
+    let encoded = plaintext.encode_detached();
+    assert_eq!(encoded.len() % 64, 0);
+
+    let mut decoded = ApplicationPlaintext::decode_detached(&encoded).unwrap();
+    assert!(decoded.strip_padding().is_ok());
+    assert_eq!(decoded.content, b"Hello");
+    assert!(decoded.zeros.is_empty());
+
+    let mut tampered = encoded.clone();
+    let last = tampered.len() - 1;
+    tampered[last] = 1;
+    let mut decoded = ApplicationPlaintext::decode_detached(&tampered).unwrap();
+    assert!(decoded.strip_padding().is_err());
+}
 
+#[test]
+fn test_application_message_context() {
+    let mut rng = test_rng("test_application_message_context");
+    let cipher_suite = &SUITE_CURVE25519_AES128GCM_SHA256;
+    let identity = Identity::random(&mut rng);
+    let stage_secrets = StageSecrets::new(&[7u8; 12], &[9u8; 16]);
+    let sender_data_secret = [3u8; 32];
+    let sender_data = SenderData::new(1, 1, &mut rng);
+    let plaintext = ApplicationPlaintext {
+        content: b"Hello".to_vec(),
+        signature: identity.sign(b"Hello"),
+        zeros: Vec::new(),
+    };
+    let message = ApplicationMessage::new(
+        cipher_suite,
+        b"group",
+        1,
+        &sender_data,
+        &plaintext,
+        &stage_secrets,
+        &sender_data_secret,
+    )
+    .unwrap();
+
+    let decrypted = message
+        .decrypt_sender_data(cipher_suite, &sender_data_secret)
+        .unwrap();
+    assert_eq!(decrypted.sender, 1);
+    let decrypted = message
+        .decrypt_content(cipher_suite, &sender_data, &stage_secrets)
+        .unwrap();
+    assert_eq!(decrypted.content, b"Hello");
+
+    // A message moved to another group or epoch no longer decrypts
+    let mut moved = ApplicationMessage::decode_detached(&message.encode_detached()).unwrap();
+    moved.group = b"other".to_vec();
+    assert!(moved
+        .decrypt_sender_data(cipher_suite, &sender_data_secret)
+        .is_err());
+    assert!(moved
+        .decrypt_content(cipher_suite, &sender_data, &stage_secrets)
+        .is_err());
+    moved.group = b"group".to_vec();
+    moved.epoch = 2;
+    assert!(moved
+        .decrypt_sender_data(cipher_suite, &sender_data_secret)
+        .is_err());
+    assert!(moved
+        .decrypt_content(cipher_suite, &sender_data, &stage_secrets)
+        .is_err());
+}
+
+#[test]
 fn test_application_secret() {
     let init_app_secret =
         hex_to_bytes("7303BD1A1C6C1B90A9D4B79A179C081B59D7EDD268AC668BF8CFE309399E368F");
-    let mut app_secret_a = SenderApplicationSecret::from_bytes_for_sender(&init_app_secret, 1);
-    let mut app_secret_b = SenderApplicationSecret::from_bytes_for_sender(&init_app_secret, 2);
+    let cipher_suite = &SUITE_CURVE25519_AES128GCM_SHA256;
+    let mut app_secret_a =
+        SenderApplicationSecret::from_bytes_for_sender(cipher_suite, &init_app_secret, 1);
+    let mut app_secret_b =
+        SenderApplicationSecret::from_bytes_for_sender(cipher_suite, &init_app_secret, 2);
 
     let stage_secrets_a = app_secret_a.get_secret_for_stage(1).unwrap();
     let stage_secrets_b = app_secret_b.get_secret_for_stage(1).unwrap();
 
     assert_eq!(
         &stage_secrets_a.nonce,
-        &hex_to_bytes("52147E9EEDB9EE43FC3AD442")[..12]
+        &hex_to_bytes("740FEECBBB0073BCCC9064B2")[..12]
     );
     assert_eq!(
         &stage_secrets_a.key,
-        &hex_to_bytes("04AEA0121F37963206EE51416E271918")[..16]
+        &hex_to_bytes("67531842B2709FB11437E6F325F3A950")[..16]
     );
 
     assert_eq!(
         &stage_secrets_b.nonce,
-        &hex_to_bytes("CAD1630259E3D46FB5E0D14C")[..12]
+        &hex_to_bytes("C9839920580C17F4BFCDF9C3")[..12]
     );
     assert_eq!(
         &stage_secrets_b.key,
-        &hex_to_bytes("78CA38A129C945100928EC65068DA4A1")[..16]
+        &hex_to_bytes("946151E8497B379573A47183785B1447")[..16]
     );
 
     let stage_secrets_a = app_secret_a.get_secret_for_stage(2).unwrap();
@@ -182,20 +854,20 @@ fn test_application_secret() {
 
     assert_eq!(
         &stage_secrets_a.nonce,
-        &hex_to_bytes("B144419E67CF0F1D4BB5548F")[..12]
+        &hex_to_bytes("095141EB2E83D2B618FAD02D")[..12]
     );
     assert_eq!(
         &stage_secrets_a.key,
-        &hex_to_bytes("A4EED31CC1870466E2BA75E63341128C")[..16]
+        &hex_to_bytes("571712669282763E65574ECA89C2056A")[..16]
     );
 
     assert_eq!(
         &stage_secrets_b.nonce,
-        &hex_to_bytes("0F023A9ACE4B100DA040BF43")[..12]
+        &hex_to_bytes("BE83C4075D03A61FAFAD35F9")[..12]
     );
     assert_eq!(
         &stage_secrets_b.key,
-        &hex_to_bytes("05CAE0495941E1653F59E91301F1CC2E")[..16]
+        &hex_to_bytes("E5CC4AFE43290925801C82649DE881D7")[..16]
     );
 
     let stage_secrets_a = app_secret_a.get_secret_for_stage(3).unwrap();
@@ -203,19 +875,117 @@ fn test_application_secret() {
 
     assert_eq!(
         &stage_secrets_a.nonce,
-        &hex_to_bytes("04BF258E07E8D64A5D703CBF")[..12]
+        &hex_to_bytes("8EAD201C9CE9ECB3E933C2C2")[..12]
     );
     assert_eq!(
         &stage_secrets_a.key,
-        &hex_to_bytes("91EA5F7FBFC99B93CDA479372B9B7682")[..16]
+        &hex_to_bytes("E157AAC22DEC01055715A377FE27CA74")[..16]
     );
 
     assert_eq!(
         &stage_secrets_b.nonce,
-        &hex_to_bytes("D2C43110D4B5528283AF7E28")[..12]
+        &hex_to_bytes("FCDA3D240BA3D0BBE183CE8A")[..12]
     );
     assert_eq!(
         &stage_secrets_b.key,
-        &hex_to_bytes("CE2D2732428E9048459AFD939071D31A")[..16]
+        &hex_to_bytes("916C2C583FAA7930027354C5A2D9B7A1")[..16]
+    );
+}
+
+#[test]
+fn test_application_secret_out_of_order() {
+    let init_app_secret =
+        hex_to_bytes("7303BD1A1C6C1B90A9D4B79A179C081B59D7EDD268AC668BF8CFE309399E368F");
+    let config = RatchetConfig {
+        maximum_forward_distance: 10,
+        out_of_order_tolerance: 3,
+    };
+    let cipher_suite = &SUITE_CURVE25519_AES128GCM_SHA256;
+    let mut sender =
+        SenderApplicationSecret::from_leaf_secret(cipher_suite, &init_app_secret, 0, config);
+    let mut receiver =
+        SenderApplicationSecret::from_leaf_secret(cipher_suite, &init_app_secret, 0, config);
+
+    let mut sent = Vec::new();
+    for stage in 1..8 {
+        sent.push(sender.get_secret_for_stage(stage).unwrap());
+    }
+
+    // Stage 5 overtakes stages 1 to 4
+    assert_eq!(receiver.get_secret_for_stage(5).unwrap().key, sent[4].key);
+    // Stage 1 was evicted from the cache
+    assert!(receiver.get_secret_for_stage(1).is_err());
+    assert_eq!(receiver.get_secret_for_stage(3).unwrap().key, sent[2].key);
+    // A cached stage can only be used once
+    assert!(receiver.get_secret_for_stage(3).is_err());
+
+    // Peeking moves nothing
+    assert_eq!(
+        receiver
+            .peek_secret_for_stage(2, |s| s.key.clone())
+            .unwrap(),
+        sent[1].key
+    );
+    assert_eq!(
+        receiver
+            .peek_secret_for_stage(7, |s| s.key.clone())
+            .unwrap(),
+        sent[6].key
     );
+    assert_eq!(receiver.get_stage(), 5);
+
+    // Stages survive a serialization round trip
+    let mut receiver =
+        SenderApplicationSecret::decode_detached(&receiver.encode_detached()).unwrap();
+    assert_eq!(receiver.get_secret_for_stage(2).unwrap().key, sent[1].key);
+    assert_eq!(receiver.get_secret_for_stage(4).unwrap().key, sent[3].key);
+
+    // Jumping too far ahead is rejected
+    assert!(receiver.get_secret_for_stage(16).is_err());
+    assert!(receiver.get_secret_for_stage(15).is_ok());
+}
+
+#[test]
+fn test_ratchet_secrets_are_zeroized() {
+    let config = RatchetConfig {
+        maximum_forward_distance: 10,
+        out_of_order_tolerance: 3,
+    };
+    let cipher_suite = &SUITE_CURVE25519_AES128GCM_SHA256;
+    let mut ratchet =
+        SenderApplicationSecret::from_leaf_secret(cipher_suite, &[0x2A; 32], 0, config);
+    let mut stage_secrets = ratchet.get_secret_for_stage(3).unwrap();
+    assert_eq!(ratchet.skipped.len(), 2);
+
+    stage_secrets.zeroize();
+    assert!(is_zeroized(&stage_secrets.key));
+    assert!(is_zeroized(&stage_secrets.nonce));
+    ratchet.zeroize();
+    assert!(is_zeroized(&ratchet.value));
+    for (_, skipped) in ratchet.skipped.iter() {
+        assert!(is_zeroized(&skipped.key));
+        assert!(is_zeroized(&skipped.nonce));
+    }
+}
+
+#[test]
+fn test_replay_window() {
+    let mut window = ReplayWindow::default();
+    window.insert(1, 0, 5, 0);
+    window.insert(1, 1, 5, 0);
+    assert!(window.contains(1, 0, 5));
+    assert!(!window.contains(1, 0, 4));
+    assert!(!window.contains(2, 0, 5));
+
+    // Generations that fell out of the ratchet window are dropped
+    window.insert(1, 0, 40, 8);
+    assert!(!window.contains(1, 0, 5));
+    assert!(window.contains(1, 1, 5));
+
+    // So are generations from other epochs
+    window.insert(2, 0, 1, 0);
+    assert_eq!(window.len(), 1);
+
+    let decoded = ReplayWindow::decode_detached(&window.encode_detached()).unwrap();
+    assert!(decoded.contains(2, 0, 1));
 }
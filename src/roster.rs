@@ -20,9 +20,9 @@ impl Roster {
     pub fn len() -> usize {
         0
     }
-    pub fn get(index: usize) {}
+    pub fn get(_index: usize) {}
     pub fn add() {}
-    pub fn remove(index: usize) {}
+    pub fn remove(_index: usize) {}
 }
 
 // codec
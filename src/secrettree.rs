@@ -0,0 +1,320 @@
+// Wire
+// Copyright (C) 2019 Wire Swiss GmbH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see http://www.gnu.org/licenses/.
+
+use codec::*;
+use crypto::ciphersuite::*;
+use crypto::schedule::expand_with_label;
+use mp::*;
+use treemath;
+use utils::*;
+
+pub struct TreeSecret(Vec<u8>);
+
+impl TreeSecret {
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        TreeSecret(bytes.to_vec())
+    }
+    pub fn derive(&self, cipher_suite: &CipherSuiteProvider, label: &str, context: &[u8]) -> Self {
+        TreeSecret::from_bytes(&expand_with_label(
+            cipher_suite,
+            &self.0,
+            label,
+            context,
+            cipher_suite.kdf.hash_length(),
+        ))
+    }
+}
+
+impl ConstantTimeEq for TreeSecret {
+    fn ct_eq(&self, other: &Self) -> bool {
+        constant_time_eq(&self.0, &other.0)
+    }
+}
+
+impl Zeroize for TreeSecret {
+    fn zeroize(&mut self) {
+        erase(&mut self.0)
+    }
+}
+
+impl Drop for TreeSecret {
+    fn drop(&mut self) {
+        self.zeroize()
+    }
+}
+
+impl Codec for TreeSecret {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        encode_vec_u8(buffer, &self.0);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let bytes = decode_vec_u8(cursor)?;
+        Ok(TreeSecret::from_bytes(&bytes))
+    }
+}
+
+pub struct LeafRatchets {
+    pub handshake: SenderApplicationSecret,
+    pub application: SenderApplicationSecret,
+}
+
+impl Codec for LeafRatchets {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.handshake.encode(buffer);
+        self.application.encode(buffer);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let handshake = SenderApplicationSecret::decode(cursor)?;
+        let application = SenderApplicationSecret::decode(cursor)?;
+        Ok(LeafRatchets {
+            handshake,
+            application,
+        })
+    }
+}
+
+impl Zeroize for LeafRatchets {
+    fn zeroize(&mut self) {
+        self.handshake.zeroize();
+        self.application.zeroize();
+    }
+}
+
+#[derive(Debug)]
+pub enum SecretTreeError {
+    UnknownLeaf,
+    SecretConsumed,
+}
+
+// Secrets are only kept for nodes that have not been consumed yet. Deriving
+// a leaf walks down from its lowest populated ancestor, stores the secrets
+// of both children and deletes the parent, so that a compromise of the
+// current state does not reveal secrets that were already used.
+pub struct SecretTree {
+    cipher_suite: &'static CipherSuiteProvider,
+    nodes: Vec<Option<TreeSecret>>,
+    ratchets: Vec<Option<LeafRatchets>>,
+    size: usize,
+    config: RatchetConfig,
+}
+
+impl Codec for SecretTree {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.cipher_suite.encode(buffer);
+        encode_vec_u32(buffer, &self.nodes);
+        encode_vec_u32(buffer, &self.ratchets);
+        (self.size as u32).encode(buffer);
+        self.config.encode(buffer);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let cipher_suite = <&CipherSuiteProvider>::decode(cursor)?;
+        let nodes = decode_vec_u32(cursor)?;
+        let ratchets = decode_vec_u32(cursor)?;
+        let size = u32::decode(cursor)? as usize;
+        let config = RatchetConfig::decode(cursor)?;
+        Ok(SecretTree {
+            cipher_suite,
+            nodes,
+            ratchets,
+            size,
+            config,
+        })
+    }
+}
+
+impl Zeroize for SecretTree {
+    fn zeroize(&mut self) {
+        for node in self.nodes.iter_mut().flatten() {
+            node.zeroize();
+        }
+        for ratchets in self.ratchets.iter_mut().flatten() {
+            ratchets.zeroize();
+        }
+    }
+}
+
+impl SecretTree {
+    pub fn new(
+        cipher_suite: &'static CipherSuiteProvider,
+        encryption_secret: &[u8],
+        size: usize,
+        config: RatchetConfig,
+    ) -> Self {
+        let mut nodes: Vec<Option<TreeSecret>> =
+            (0..treemath::node_width(size)).map(|_| None).collect();
+        nodes[treemath::root(size)] = Some(TreeSecret::from_bytes(encryption_secret));
+        SecretTree {
+            cipher_suite,
+            nodes,
+            ratchets: (0..size).map(|_| None).collect(),
+            size,
+            config,
+        }
+    }
+    pub fn get_size(&self) -> usize {
+        self.size
+    }
+    pub fn get_application_ratchet(
+        &mut self,
+        sender: u32,
+    ) -> Result<&mut SenderApplicationSecret, SecretTreeError> {
+        Ok(&mut self.get_leaf_ratchets(sender)?.application)
+    }
+    pub fn get_handshake_ratchet(
+        &mut self,
+        sender: u32,
+    ) -> Result<&mut SenderApplicationSecret, SecretTreeError> {
+        Ok(&mut self.get_leaf_ratchets(sender)?.handshake)
+    }
+    fn get_leaf_ratchets(&mut self, sender: u32) -> Result<&mut LeafRatchets, SecretTreeError> {
+        let leaf = sender as usize;
+        if leaf >= self.size {
+            return Err(SecretTreeError::UnknownLeaf);
+        }
+        if self.ratchets[leaf].is_none() {
+            let leaf_secret = self.derive_leaf_secret(leaf)?;
+            let handshake = leaf_secret.derive(self.cipher_suite, "handshake", &[]);
+            let application = leaf_secret.derive(self.cipher_suite, "application", &[]);
+            self.ratchets[leaf] = Some(LeafRatchets {
+                handshake: SenderApplicationSecret::from_leaf_secret(
+                    self.cipher_suite,
+                    &handshake.0,
+                    sender,
+                    self.config,
+                ),
+                application: SenderApplicationSecret::from_leaf_secret(
+                    self.cipher_suite,
+                    &application.0,
+                    sender,
+                    self.config,
+                ),
+            });
+        }
+        Ok(self.ratchets[leaf].as_mut().unwrap())
+    }
+    fn derive_leaf_secret(&mut self, leaf: usize) -> Result<TreeSecret, SecretTreeError> {
+        // Path from the root down to the leaf
+        let mut path = treemath::dirpath(leaf * 2, self.size);
+        path.push(treemath::root(self.size));
+        path.reverse();
+
+        let start = match path.iter().rposition(|&x| self.nodes[x].is_some()) {
+            Some(start) => start,
+            None => return Err(SecretTreeError::SecretConsumed),
+        };
+        for &x in path[start..path.len() - 1].iter() {
+            let secret = self.nodes[x].take().unwrap();
+            let left = treemath::left(x);
+            let right = treemath::right(x, self.size);
+            self.nodes[left] = Some(secret.derive(self.cipher_suite, "tree", b"left"));
+            self.nodes[right] = Some(secret.derive(self.cipher_suite, "tree", b"right"));
+        }
+        Ok(self.nodes[leaf * 2].take().unwrap())
+    }
+}
+
+#[test]
+fn test_secret_tree() {
+    let encryption_secret =
+        hex_to_bytes("7303BD1A1C6C1B90A9D4B79A179C081B59D7EDD268AC668BF8CFE309399E368F");
+
+    let cipher_suite = &SUITE_CURVE25519_AES128GCM_SHA256;
+
+    for size in 1..10 {
+        let config = RatchetConfig::default();
+        let mut tree_a = SecretTree::new(cipher_suite, &encryption_secret, size, config);
+        let mut tree_b = SecretTree::new(cipher_suite, &encryption_secret, size, config);
+
+        // Leaves derived in a different order yield the same ratchets
+        let mut keys_a = Vec::new();
+        let mut keys_b = Vec::new();
+        for sender in 0..size {
+            let ratchet = tree_a.get_application_ratchet(sender as u32).unwrap();
+            keys_a.push(ratchet.get_secret_for_stage(1).unwrap().key.clone());
+        }
+        for sender in (0..size).rev() {
+            let ratchet = tree_b.get_application_ratchet(sender as u32).unwrap();
+            keys_b.insert(0, ratchet.get_secret_for_stage(1).unwrap().key.clone());
+        }
+        assert_eq!(keys_a, keys_b);
+
+        // Once all leaves are derived, no interior secret is left
+        assert!(tree_a.nodes.iter().all(|node| node.is_none()));
+        assert!(tree_a.get_application_ratchet(size as u32).is_err());
+    }
+}
+
+#[test]
+fn test_secret_tree_ratchets_are_separate() {
+    let encryption_secret = [0x2Au8; 32];
+    let mut tree = SecretTree::new(
+        &SUITE_CURVE25519_AES128GCM_SHA256,
+        &encryption_secret,
+        4,
+        RatchetConfig::default(),
+    );
+
+    let application = tree
+        .get_application_ratchet(2)
+        .unwrap()
+        .get_secret_for_stage(1)
+        .unwrap();
+    let handshake = tree
+        .get_handshake_ratchet(2)
+        .unwrap()
+        .get_secret_for_stage(1)
+        .unwrap();
+    assert_ne!(application.key, handshake.key);
+
+    let other = tree
+        .get_application_ratchet(3)
+        .unwrap()
+        .get_secret_for_stage(1)
+        .unwrap();
+    assert_ne!(application.key, other.key);
+
+    let decoded = SecretTree::decode_detached(&tree.encode_detached()).unwrap();
+    assert_eq!(decoded.get_size(), 4);
+}
+
+#[test]
+fn test_secret_tree_is_zeroized() {
+    let mut secret = TreeSecret::from_bytes(&[0x2A; 32]);
+    secret.zeroize();
+    assert!(is_zeroized(&secret.0));
+
+    let mut tree = SecretTree::new(
+        &SUITE_CURVE25519_AES128GCM_SHA256,
+        &[0x2A; 32],
+        4,
+        RatchetConfig::default(),
+    );
+    tree.get_application_ratchet(1).unwrap();
+    tree.zeroize();
+    assert!(tree.nodes.iter().flatten().all(|node| is_zeroized(&node.0)));
+    // A wiped ratchet holds the same value as one from an all-zero leaf secret
+    let ratchets = tree.ratchets[1].as_ref().unwrap();
+    assert_eq!(
+        ratchets.handshake.encode_detached(),
+        SenderApplicationSecret::from_leaf_secret(
+            &SUITE_CURVE25519_AES128GCM_SHA256,
+            &[0u8; 32],
+            1,
+            RatchetConfig::default()
+        )
+        .encode_detached()
+    );
+}
@@ -0,0 +1,293 @@
+// Wire
+// Copyright (C) 2019 Wire Swiss GmbH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see http://www.gnu.org/licenses/.
+
+use codec::*;
+use keys::*;
+#[cfg(unix)]
+use std::io::{Read, Write};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SignerError {
+    // The group was decoded and no signer was attached yet
+    NotAttached,
+    KeyMismatch,
+    AgentUnavailable,
+    AgentRefused,
+    InvalidResponse,
+}
+
+// Holder of an identity key. The private key never has to be in the
+// memory of this process, it can be kept by a signing agent.
+pub trait Signer: Send + Sync {
+    fn public_key(&self) -> SignaturePublicKey;
+    fn sign(&self, payload: &[u8]) -> Result<Signature, SignerError>;
+
+    fn scheme(&self) -> SignatureScheme {
+        self.public_key().scheme()
+    }
+}
+
+// The in-memory signer
+impl Signer for Identity {
+    fn public_key(&self) -> SignaturePublicKey {
+        self.public_key
+    }
+    fn sign(&self, payload: &[u8]) -> Result<Signature, SignerError> {
+        Ok(Identity::sign(self, payload))
+    }
+}
+
+// Stands in for the signer of a decoded group, as signers are not
+// persisted. Signing fails until the real signer is attached again.
+#[derive(Clone)]
+pub struct DetachedSigner(pub SignaturePublicKey);
+
+impl Signer for DetachedSigner {
+    fn public_key(&self) -> SignaturePublicKey {
+        self.0
+    }
+    fn sign(&self, _payload: &[u8]) -> Result<Signature, SignerError> {
+        Err(SignerError::NotAttached)
+    }
+}
+
+// Agent protocol: every message is framed as a vector with a u32 length.
+// Each request is sent over a new connection to the agent's socket.
+pub const AGENT_MAX_MESSAGE_BYTES: usize = 1 << 24;
+
+#[derive(Debug, PartialEq)]
+pub enum AgentRequest {
+    PublicKey,
+    Sign(Vec<u8>),
+}
+
+impl Codec for AgentRequest {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        match *self {
+            AgentRequest::PublicKey => 1u8.encode(buffer),
+            AgentRequest::Sign(ref payload) => {
+                2u8.encode(buffer);
+                encode_vec_u32(buffer, payload);
+            }
+        }
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        match u8::decode(cursor)? {
+            1 => Ok(AgentRequest::PublicKey),
+            2 => Ok(AgentRequest::Sign(decode_vec_u32(cursor)?)),
+            _ => Err(CodecError::DecodingError),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AgentResponse {
+    Refused,
+    PublicKey(SignaturePublicKey),
+    Signature(Signature),
+}
+
+impl Codec for AgentResponse {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        match *self {
+            AgentResponse::Refused => 0u8.encode(buffer),
+            AgentResponse::PublicKey(ref public_key) => {
+                1u8.encode(buffer);
+                public_key.encode(buffer);
+            }
+            AgentResponse::Signature(ref signature) => {
+                2u8.encode(buffer);
+                signature.encode(buffer);
+            }
+        }
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        match u8::decode(cursor)? {
+            0 => Ok(AgentResponse::Refused),
+            1 => Ok(AgentResponse::PublicKey(SignaturePublicKey::decode(
+                cursor,
+            )?)),
+            2 => Ok(AgentResponse::Signature(Signature::decode(cursor)?)),
+            _ => Err(CodecError::DecodingError),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn write_frame(stream: &mut UnixStream, message: &[u8]) -> Result<(), SignerError> {
+    let mut frame = Vec::with_capacity(4 + message.len());
+    encode_vec_u32(&mut frame, message);
+    stream
+        .write_all(&frame)
+        .map_err(|_| SignerError::AgentUnavailable)
+}
+
+#[cfg(unix)]
+fn read_frame(stream: &mut UnixStream) -> Result<Vec<u8>, SignerError> {
+    let mut length = [0u8; 4];
+    stream
+        .read_exact(&mut length)
+        .map_err(|_| SignerError::AgentUnavailable)?;
+    let length = u32::decode_detached(&length).unwrap() as usize;
+    if length > AGENT_MAX_MESSAGE_BYTES {
+        return Err(SignerError::InvalidResponse);
+    }
+    let mut message = vec![0u8; length];
+    stream
+        .read_exact(&mut message)
+        .map_err(|_| SignerError::AgentUnavailable)?;
+    Ok(message)
+}
+
+// Signer backed by an agent listening on a local socket
+#[cfg(unix)]
+#[derive(Clone)]
+pub struct AgentSigner {
+    path: PathBuf,
+    public_key: SignaturePublicKey,
+}
+
+#[cfg(unix)]
+impl AgentSigner {
+    // Asks the agent for its public key once, it is not expected to change
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<AgentSigner, SignerError> {
+        let path = path.as_ref().to_path_buf();
+        match AgentSigner::request(&path, &AgentRequest::PublicKey)? {
+            AgentResponse::PublicKey(public_key) => Ok(AgentSigner { path, public_key }),
+            AgentResponse::Refused => Err(SignerError::AgentRefused),
+            _ => Err(SignerError::InvalidResponse),
+        }
+    }
+    fn request(path: &Path, request: &AgentRequest) -> Result<AgentResponse, SignerError> {
+        let mut stream = UnixStream::connect(path).map_err(|_| SignerError::AgentUnavailable)?;
+        write_frame(&mut stream, &request.encode_detached())?;
+        let response = read_frame(&mut stream)?;
+        AgentResponse::decode_detached(&response).map_err(|_| SignerError::InvalidResponse)
+    }
+}
+
+#[cfg(unix)]
+impl Signer for AgentSigner {
+    fn public_key(&self) -> SignaturePublicKey {
+        self.public_key
+    }
+    // The signature is checked, so that a misbehaving agent cannot make us
+    // send handshakes that other members reject
+    fn sign(&self, payload: &[u8]) -> Result<Signature, SignerError> {
+        match AgentSigner::request(&self.path, &AgentRequest::Sign(payload.to_vec()))? {
+            AgentResponse::Signature(ref signature)
+                if self.public_key.verify(payload, signature) =>
+            {
+                Ok(signature.clone())
+            }
+            AgentResponse::Refused => Err(SignerError::AgentRefused),
+            _ => Err(SignerError::InvalidResponse),
+        }
+    }
+}
+
+// Answers the requests of one connection, for agents built on this crate
+#[cfg(unix)]
+pub fn serve_agent_connection(
+    stream: &mut UnixStream,
+    signer: &dyn Signer,
+) -> Result<(), SignerError> {
+    let request = read_frame(stream)?;
+    let response = match AgentRequest::decode_detached(&request) {
+        Ok(AgentRequest::PublicKey) => AgentResponse::PublicKey(signer.public_key()),
+        Ok(AgentRequest::Sign(payload)) => match signer.sign(&payload) {
+            Ok(signature) => AgentResponse::Signature(signature),
+            Err(_) => AgentResponse::Refused,
+        },
+        Err(_) => AgentResponse::Refused,
+    };
+    write_frame(stream, &response.encode_detached())
+}
+
+// Runs an agent for the identity in a background thread
+#[cfg(all(test, unix))]
+pub fn spawn_test_agent(name: &str, identity: Identity) -> PathBuf {
+    use std::os::unix::net::UnixListener;
+    use std::{env, fs, process, thread};
+
+    let path = env::temp_dir().join(format!("melissa-agent-{}-{}", process::id(), name));
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let _ = serve_agent_connection(&mut stream.unwrap(), &identity);
+        }
+    });
+    path
+}
+
+#[test]
+fn test_in_memory_signer() {
+    use utils::*;
+
+    let mut rng = test_rng("test_in_memory_signer");
+    for &scheme in [ED25519, ECDSA_SECP256R1_SHA256].iter() {
+        let identity = Identity::random_for_scheme(scheme, &mut rng).unwrap();
+        let public_key = identity.public_key;
+        let signer: Box<dyn Signer> = Box::new(identity);
+        assert_eq!(signer.public_key(), public_key);
+        assert_eq!(signer.scheme(), scheme);
+        let signature = signer.sign(b"payload").unwrap();
+        assert!(public_key.verify(b"payload", &signature));
+    }
+
+    let detached = DetachedSigner(Identity::random(&mut rng).public_key);
+    assert_eq!(detached.sign(b"payload"), Err(SignerError::NotAttached));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_agent_signer() {
+    use utils::*;
+
+    let mut rng = test_rng("test_agent_signer");
+    let identity = Identity::random(&mut rng);
+    let public_key = identity.public_key;
+    let path = spawn_test_agent("test_agent_signer", identity);
+
+    let signer = AgentSigner::connect(&path).unwrap();
+    assert_eq!(signer.public_key(), public_key);
+    let signature = signer.sign(b"payload").unwrap();
+    assert!(public_key.verify(b"payload", &signature));
+    assert!(signer.sign(&[0u8; 100_000]).is_ok());
+
+    // Signatures by another key are not accepted
+    let impostor = Identity::random(&mut rng);
+    let impostor_path = spawn_test_agent("test_agent_signer_impostor", impostor);
+    let forged = AgentSigner {
+        path: impostor_path,
+        public_key,
+    };
+    assert_eq!(forged.sign(b"payload"), Err(SignerError::InvalidResponse));
+
+    assert_eq!(
+        AgentSigner::connect(path.with_extension("missing")).err(),
+        Some(SignerError::AgentUnavailable)
+    );
+    assert_eq!(
+        AgentRequest::decode_detached(&AgentRequest::Sign(vec![1, 2]).encode_detached()).unwrap(),
+        AgentRequest::Sign(vec![1, 2])
+    );
+}
@@ -15,49 +15,76 @@
 // along with this program. If not, see http://www.gnu.org/licenses/.
 
 use codec::*;
-use crypto::eckem::*;
+use crypto::ciphersuite::*;
+use crypto::eckem::EcKemError;
+use crypto::rng::CryptoRng;
 use keys::*;
-use sodiumoxide::crypto::hash::sha256::*;
-use sodiumoxide::randombytes;
+use std::fmt;
 use treemath;
+use utils::*;
 
-pub const NODESECRETBYTES: usize = 32;
+// HPKE info for path secrets encrypted to copath nodes
+pub const PATH_SECRET_INFO: &[u8] = b"MLS 1.0 path secret";
 
-#[derive(PartialEq, Clone, Copy, Debug)]
-pub struct NodeSecret(pub [u8; NODESECRETBYTES]);
+// AEAD associated data for a path secret: the group context followed by the
+// index of the copath node it is encrypted to
+pub fn path_secret_aad(context: &[u8], node_index: usize) -> Vec<u8> {
+    let mut aad = context.to_vec();
+    (node_index as u32).encode(&mut aad);
+    aad
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TreeError {
+    InvalidPublicKey,
+    InvalidPath,
+    KemError(EcKemError),
+}
+
+impl From<EcKemError> for TreeError {
+    fn from(e: EcKemError) -> Self {
+        TreeError::KemError(e)
+    }
+}
+
+// Node secrets are as long as the hash output of the cipher suite
+pub struct NodeSecret(pub Vec<u8>);
+
+impl fmt::Debug for NodeSecret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "NodeSecret(..)")
+    }
+}
 
 impl NodeSecret {
-    pub fn new_random() -> Self {
-        let mut bytes = [0u8; NODESECRETBYTES];
-        bytes.clone_from_slice(randombytes::randombytes(NODESECRETBYTES).as_slice());
-        NodeSecret(bytes)
+    pub fn new_random(cipher_suite: &CipherSuiteProvider, rng: &mut dyn CryptoRng) -> Self {
+        NodeSecret(rng.random_bytes(cipher_suite.kdf.hash_length()))
     }
 
-    pub fn hash(&mut self) {
-        let digest = hash(&self.0[..]);
-        self.0 = NodeSecret::from(digest).0;
+    pub fn hash(&self, cipher_suite: &CipherSuiteProvider) -> Self {
+        NodeSecret(cipher_suite.kdf.hash(&self.0[..]))
     }
 
     pub fn from_bytes(bytes: &[u8]) -> Self {
-        let mut buffer = [0u8; NODESECRETBYTES];
-        buffer.clone_from_slice(&bytes[..NODESECRETBYTES]);
-        NodeSecret(buffer)
+        NodeSecret(bytes.to_vec())
     }
 }
 
-impl From<Digest> for NodeSecret {
-    fn from(d: Digest) -> NodeSecret {
-        let mut bytes = [0u8; DIGESTBYTES];
-        bytes.clone_from_slice(&d.0[..DIGESTBYTES]);
-        NodeSecret(bytes)
+impl ConstantTimeEq for NodeSecret {
+    fn ct_eq(&self, other: &Self) -> bool {
+        constant_time_eq(&self.0, &other.0)
     }
 }
 
-impl From<NodeSecret> for Digest {
-    fn from(n: NodeSecret) -> Digest {
-        let mut bytes = [0u8; NODESECRETBYTES];
-        bytes.clone_from_slice(&n.0[..NODESECRETBYTES]);
-        Digest(bytes)
+impl Zeroize for NodeSecret {
+    fn zeroize(&mut self) {
+        erase(&mut self.0)
+    }
+}
+
+impl Drop for NodeSecret {
+    fn drop(&mut self) {
+        self.zeroize()
     }
 }
 
@@ -71,11 +98,11 @@ impl Codec for NodeSecret {
     }
 }
 
-#[derive(PartialEq, Clone, Debug)]
+#[derive(Debug)]
 pub struct Node {
     pub secret: Option<NodeSecret>,
-    pub dh_public_key: Option<X25519PublicKey>,
-    pub dh_private_key: Option<X25519PrivateKey>,
+    pub dh_public_key: Option<KemPublicKey>,
+    pub dh_private_key: Option<KemPrivateKey>,
 }
 
 impl Codec for Node {
@@ -86,8 +113,8 @@ impl Codec for Node {
     }
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
         let secret = Option::<NodeSecret>::decode(cursor)?;
-        let dh_public_key = Option::<X25519PublicKey>::decode(cursor)?;
-        let dh_private_key = Option::<X25519PrivateKey>::decode(cursor)?;
+        let dh_public_key = Option::<KemPublicKey>::decode(cursor)?;
+        let dh_private_key = Option::<KemPrivateKey>::decode(cursor)?;
         Ok(Node {
             secret,
             dh_public_key,
@@ -97,20 +124,20 @@ impl Codec for Node {
 }
 
 impl Node {
-    pub fn from_secret(secret: &NodeSecret) -> Node {
-        let kp = X25519KeyPair::new_from_secret(&secret);
+    pub fn from_secret(cipher_suite: &CipherSuiteProvider, secret: NodeSecret) -> Node {
+        let (private_key, public_key) = cipher_suite.kem.derive_key_pair(&secret.0);
         Node {
-            secret: Some(*secret),
-            dh_public_key: Some(kp.public_key),
-            dh_private_key: Some(kp.private_key),
+            secret: Some(secret),
+            dh_public_key: Some(public_key),
+            dh_private_key: Some(private_key),
         }
     }
 
-    pub fn new_from_public_key(key: &X25519PublicKey) -> Node {
+    pub fn new_from_public_key(key: &KemPublicKey) -> Node {
         Node {
             secret: None,
             dh_private_key: None,
-            dh_public_key: Some(*key),
+            dh_public_key: Some(key.clone()),
         }
     }
 
@@ -122,34 +149,29 @@ impl Node {
         }
     }
 
-    pub fn get_public_key(&mut self) -> Option<X25519PublicKey> {
-        match self.dh_public_key {
-            Some(key) => Some(key),
-            None => match self.secret {
-                Some(secret) => {
-                    let kp = X25519KeyPair::new_from_secret(&secret);
-                    self.dh_private_key = Some(kp.private_key);
-                    self.dh_public_key = Some(kp.public_key);
-                    self.dh_public_key
-                }
-                None => None,
-            },
+    fn derive_keys(&mut self, cipher_suite: &CipherSuiteProvider) {
+        if self.dh_private_key.is_none() {
+            if let Some(ref secret) = self.secret {
+                let (private_key, public_key) = cipher_suite.kem.derive_key_pair(&secret.0);
+                self.dh_private_key = Some(private_key);
+                self.dh_public_key = Some(public_key);
+            }
         }
     }
 
-    pub fn get_private_key(&mut self) -> Option<X25519PrivateKey> {
-        match self.dh_private_key.clone() {
-            Some(key) => Some(key),
-            None => match self.secret {
-                Some(secret) => {
-                    let kp = X25519KeyPair::new_from_secret(&secret);
-                    self.dh_private_key = Some(kp.private_key);
-                    self.dh_public_key = Some(kp.public_key);
-                    self.dh_private_key.clone()
-                }
-                None => None,
-            },
+    pub fn get_public_key(&mut self, cipher_suite: &CipherSuiteProvider) -> Option<&KemPublicKey> {
+        if self.dh_public_key.is_none() {
+            self.derive_keys(cipher_suite);
         }
+        self.dh_public_key.as_ref()
+    }
+
+    pub fn get_private_key(
+        &mut self,
+        cipher_suite: &CipherSuiteProvider,
+    ) -> Option<&KemPrivateKey> {
+        self.derive_keys(cipher_suite);
+        self.dh_private_key.as_ref()
     }
 
     pub fn blank(&mut self) {
@@ -163,21 +185,24 @@ impl Node {
     }
 }
 
-#[derive(Clone)]
 pub struct Tree {
+    pub cipher_suite: &'static CipherSuiteProvider,
     pub nodes: Vec<Node>,
     pub own_leaf_index: usize,
 }
 
 impl Codec for Tree {
     fn encode(&self, buffer: &mut Vec<u8>) {
+        self.cipher_suite.encode(buffer);
         encode_vec_u32(buffer, &self.nodes);
         (self.own_leaf_index as u32).encode(buffer);
     }
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let cipher_suite = <&CipherSuiteProvider>::decode(cursor)?;
         let nodes = decode_vec_u32(cursor)?;
         let own_leaf_index = u32::decode(cursor)? as usize;
         Ok(Tree {
+            cipher_suite,
             nodes,
             own_leaf_index,
         })
@@ -185,30 +210,32 @@ impl Codec for Tree {
 }
 
 impl Tree {
-    pub fn new_from_leaf(leaf: &Node) -> Tree {
-        let mut tree = Tree {
-            nodes: vec![leaf.clone()],
+    pub fn new_from_leaf_secret(
+        cipher_suite: &'static CipherSuiteProvider,
+        leaf_secret: NodeSecret,
+    ) -> Tree {
+        // In a one member tree the leaf is also the root
+        Tree {
+            cipher_suite,
+            nodes: Tree::hash_up(cipher_suite, 0, 1, leaf_secret),
             own_leaf_index: 0,
-        };
-        let secret = leaf.secret.unwrap();
-        let new_nodes = Tree::hash_up(0, 1, &secret);
-        let copath = vec![0];
-        tree.merge(copath, &new_nodes);
-        tree
+        }
     }
 
     pub fn new_from_public_keys(
-        keys: &[X25519PublicKey],
+        cipher_suite: &'static CipherSuiteProvider,
+        keys: &[KemPublicKey],
         own_leaf_index: usize,
-        leaf_secret: &NodeSecret,
+        leaf_secret: NodeSecret,
     ) -> Tree {
         let mut nodes: Vec<Node> = Vec::new();
         for key in keys {
             nodes.push(Node::new_from_public_key(key));
         }
-        let own_node = Node::from_secret(leaf_secret);
+        let own_node = Node::from_secret(cipher_suite, leaf_secret);
         nodes[own_leaf_index] = own_node;
         Tree {
+            cipher_suite,
             nodes,
             own_leaf_index,
         }
@@ -218,9 +245,9 @@ impl Tree {
         self.nodes.len()
     }
 
-    pub fn get_root(&self) -> Node {
+    pub fn get_root(&self) -> &Node {
         let root_index = treemath::root(self.get_leaf_count());
-        self.nodes[root_index].clone()
+        &self.nodes[root_index]
     }
 
     pub fn set_root(&mut self, node: Node) {
@@ -228,32 +255,24 @@ impl Tree {
         self.nodes[index] = node;
     }
 
-    pub fn get_nodes_from_path(&self, path: Vec<usize>) -> Vec<Node> {
-        let mut nodes: Vec<Node> = Vec::new();
-        for i in path {
-            nodes.push(self.nodes[i].clone());
-        }
-        nodes
-    }
-
-    pub fn get_public_keys_from_path(&self, path: Vec<usize>) -> Vec<X25519PublicKey> {
+    pub fn get_public_keys_from_path(&self, path: Vec<usize>) -> Vec<KemPublicKey> {
         let mut keys = Vec::new();
         for index in path {
-            keys.push(self.nodes[index].clone().dh_public_key.unwrap());
+            keys.push(self.nodes[index].dh_public_key.clone().unwrap());
         }
         keys
     }
 
-    pub fn get_public_key_tree(&self) -> Vec<X25519PublicKey> {
+    pub fn get_public_key_tree(&self) -> Vec<KemPublicKey> {
         let mut tree = Vec::new();
         for node in self.nodes.iter() {
-            tree.push(node.clone().dh_public_key.unwrap());
+            tree.push(node.dh_public_key.clone().unwrap());
         }
         tree
     }
 
-    pub fn get_own_leaf(&self) -> Node {
-        self.nodes[self.own_leaf_index].clone()
+    pub fn get_own_leaf(&self) -> &Node {
+        &self.nodes[self.own_leaf_index]
     }
 
     pub fn get_own_leaf_index(&self) -> usize {
@@ -288,7 +307,7 @@ impl Tree {
         }
     }
 
-    pub fn merge(&mut self, path: Vec<usize>, nodes: &[Node]) {
+    pub fn merge(&mut self, path: Vec<usize>, nodes: Vec<Node>) {
         assert_eq!(path.len(), nodes.len());
         let mut max: usize = 0;
         for n in path.iter() {
@@ -297,38 +316,58 @@ impl Tree {
             }
         }
         if max >= self.nodes.len() {
-            self.nodes.resize(max + 1, Node::new_blank());
+            self.nodes.resize_with(max + 1, Node::new_blank);
         }
-        for (node, index) in nodes.iter().zip(path) {
-            self.nodes[index] = node.clone();
+        for (node, index) in nodes.into_iter().zip(path) {
+            self.nodes[index] = node;
         }
     }
 
-    pub fn hash_up(index: usize, size: usize, secret: &NodeSecret) -> Vec<Node> {
-        // Compute hashes up the tree
+    pub fn hash_up(
+        cipher_suite: &CipherSuiteProvider,
+        index: usize,
+        size: usize,
+        secret: NodeSecret,
+    ) -> Vec<Node> {
+        // Compute hashes up the tree, each node takes ownership of its secret
         let mut nodes = Vec::new();
-        let mut node_secret = *secret;
+        let mut node_secret = secret;
         let mut dirpath = treemath::dirpath(index, size);
         dirpath.push(treemath::root(size));
         for _ in dirpath {
-            let node = Node::from_secret(&node_secret);
-            nodes.push(node);
-            node_secret.hash();
+            let next_secret = node_secret.hash(cipher_suite);
+            nodes.push(Node::from_secret(cipher_suite, node_secret));
+            node_secret = next_secret;
         }
         nodes
     }
 
     pub fn kem_to(
-        dirpath_nodes: &mut [Node],
-        copath_nodes: &mut [Node],
-    ) -> Vec<X25519AESCiphertext> {
-        let mut path: Vec<X25519AESCiphertext> = Vec::new();
-        assert_eq!(dirpath_nodes.len(), copath_nodes.len());
-        for node_pair in dirpath_nodes.iter_mut().zip(copath_nodes.iter_mut()) {
-            let (mut dirpath_node, mut copath_node) = node_pair;
-            let public_key = copath_node.dh_public_key.unwrap();
-            let ciphertext =
-                X25519AES::encrypt(&public_key, &dirpath_node.secret.unwrap().0[..]).unwrap();
+        cipher_suite: &CipherSuiteProvider,
+        dirpath_nodes: &[Node],
+        copath_public_keys: &[KemPublicKey],
+        copath: &[usize],
+        context: &[u8],
+        rng: &mut dyn CryptoRng,
+    ) -> Vec<KemCiphertext> {
+        let mut path: Vec<KemCiphertext> = Vec::new();
+        assert_eq!(dirpath_nodes.len(), copath_public_keys.len());
+        assert_eq!(copath.len(), copath_public_keys.len());
+        for ((dirpath_node, public_key), node_index) in dirpath_nodes
+            .iter()
+            .zip(copath_public_keys.iter())
+            .zip(copath.iter())
+        {
+            let ciphertext = cipher_suite
+                .kem
+                .encrypt(
+                    public_key,
+                    PATH_SECRET_INFO,
+                    &path_secret_aad(context, *node_index),
+                    &dirpath_node.secret.as_ref().unwrap().0[..],
+                    rng,
+                )
+                .expect("node keys are validated before they are merged");
             path.push(ciphertext);
         }
         path
@@ -338,22 +377,28 @@ impl Tree {
         &self,
         index: usize,
         size: usize,
-        secret: NodeSecret,
-    ) -> (Vec<X25519PublicKey>, Vec<X25519AESCiphertext>) {
-        let node_secret = secret;
-        let mut nodes = Tree::hash_up(index, size, &node_secret);
-        let mut copath_nodes = self.get_nodes_from_path(treemath::copath(index, size));
+        path_nodes: &[Node],
+        context: &[u8],
+        rng: &mut dyn CryptoRng,
+    ) -> (Vec<KemPublicKey>, Vec<KemCiphertext>) {
+        let copath = treemath::copath(index, size);
+        let copath_public_keys = self.get_public_keys_from_path(copath.clone());
         // strip leaf
-        let leaf_node = nodes.remove(0);
-        assert_eq!(copath_nodes.len(), nodes.len());
-        let ciphertexts = Tree::kem_to(&mut nodes, &mut copath_nodes);
-        let mut public_keys: Vec<X25519PublicKey> = Vec::new();
-        public_keys.push(leaf_node.dh_public_key.unwrap());
-        for mut node in nodes {
-            public_keys.push(node.dh_public_key.unwrap());
-        }
+        let nodes = &path_nodes[1..];
+        assert_eq!(copath_public_keys.len(), nodes.len());
+        let ciphertexts = Tree::kem_to(
+            self.cipher_suite,
+            nodes,
+            &copath_public_keys,
+            &copath,
+            context,
+            rng,
+        );
         // strip root
-        public_keys.pop();
+        let public_keys = path_nodes[..path_nodes.len() - 1]
+            .iter()
+            .map(|node| node.dh_public_key.clone().unwrap())
+            .collect();
         (public_keys, ciphertexts)
     }
 
@@ -361,8 +406,12 @@ impl Tree {
         &self,
         size: usize,
         kem_path: &[usize],
-        ciphertexts: &[X25519AESCiphertext],
-    ) -> (Vec<usize>, Vec<Node>) {
+        ciphertexts: &[KemCiphertext],
+        context: &[u8],
+    ) -> Result<(Vec<usize>, Vec<Node>), TreeError> {
+        if ciphertexts.len() != kem_path.len() {
+            return Err(TreeError::InvalidPath);
+        }
         let own_path = treemath::dirpath(self.own_leaf_index, size);
         let mut own_path_index = 0;
         let mut kem_path_index = 0;
@@ -377,19 +426,32 @@ impl Tree {
         let mut merge_path = treemath::dirpath(treemath::parent(self.own_leaf_index, size), size);
         merge_path.push(treemath::root(size));
         merge_path.drain(0..own_path_index);
-        let intersect_ciphertext = ciphertexts[kem_path_index].clone();
-        let intersect_node = self.nodes[own_path[own_path_index]].clone();
-        let private_key = intersect_node.dh_private_key.unwrap();
-        let secret = X25519AES::decrypt(&private_key, &intersect_ciphertext).unwrap();
-        let node_secret = NodeSecret::from_bytes(secret.as_slice());
-        (
+        let intersect_ciphertext = &ciphertexts[kem_path_index];
+        let private_key = match self.nodes[own_path[own_path_index]].dh_private_key {
+            Some(ref private_key) => private_key,
+            None => return Err(TreeError::InvalidPath),
+        };
+        let secret = self.cipher_suite.kem.decrypt(
+            private_key,
+            PATH_SECRET_INFO,
+            &path_secret_aad(context, kem_path[kem_path_index]),
+            intersect_ciphertext,
+        )?;
+        // Node keys are derived from the secret, it has to be as long as the
+        // hash output
+        let node_secret = NodeSecret(secret);
+        if node_secret.0.len() != self.cipher_suite.kdf.hash_length() {
+            return Err(TreeError::InvalidPath);
+        }
+        Ok((
             merge_path,
             Tree::hash_up(
+                self.cipher_suite,
                 treemath::parent(own_path[own_path_index], size),
                 size,
-                &node_secret,
+                node_secret,
             ),
-        )
+        ))
     }
 
     pub fn apply_kem_path(
@@ -397,17 +459,27 @@ impl Tree {
         index: usize,
         size: usize,
         kem_path: &[usize],
-        ciphertext: &[X25519AESCiphertext],
-        public_keys: &[X25519PublicKey],
-    ) {
+        ciphertext: &[KemCiphertext],
+        public_keys: &[KemPublicKey],
+        context: &[u8],
+    ) -> Result<(), TreeError> {
         let public_merge_path = treemath::dirpath(index, size);
+        if public_keys.len() != public_merge_path.len()
+            || !public_keys
+                .iter()
+                .all(|key| self.cipher_suite.kem.is_valid_public_key(key))
+        {
+            return Err(TreeError::InvalidPublicKey);
+        }
+        // Nothing is merged unless the path secret can be decrypted
+        let (merge_path, nodes) = self.decrypt(size, kem_path, ciphertext, context)?;
         let mut public_nodes = Vec::new();
         for key in public_keys.iter() {
             public_nodes.push(Node::new_from_public_key(key));
         }
-        self.merge(public_merge_path, &public_nodes);
-        let (merge_path, nodes) = self.decrypt(size, &kem_path, ciphertext);
-        self.merge(merge_path, &nodes);
+        self.merge(public_merge_path, public_nodes);
+        self.merge(merge_path, nodes);
+        Ok(())
     }
 }
 
@@ -418,15 +490,16 @@ fn verify_binary_test_vector_resolution() {
     use std::io::Read;
     use treemath;
 
+    let mut rng = test_rng("verify_binary_test_vector_resolution");
     let mut file = File::open("test_vectors/resolution.bin").unwrap();
     let mut buffer = Vec::new();
     file.read_to_end(&mut buffer).unwrap();
 
     let mut cursor = Cursor::new(&buffer);
 
-    let node_secret = NodeSecret::new_random();
-    let blank_node = Node::new_blank();
-    let full_node = Node::from_secret(&node_secret);
+    let node_secret = NodeSecret::new_random(&SUITE_CURVE25519_AES128GCM_SHA256, &mut rng);
+    let full_node = Node::from_secret(&SUITE_CURVE25519_AES128GCM_SHA256, node_secret);
+    let public_key = full_node.dh_public_key.unwrap();
 
     let n_leaves = u32::decode(&mut cursor).unwrap() as usize;
     let cases: Vec<u8> = decode_vec_u32(&mut cursor).unwrap();
@@ -441,12 +514,13 @@ fn verify_binary_test_vector_resolution() {
         for node_index in 0..number_of_nodes {
             let is_blank = (case_index >> node_index) & 1 == 1;
             match is_blank {
-                true => nodes.push(full_node.clone()),
-                false => nodes.push(blank_node.clone()),
+                true => nodes.push(Node::new_from_public_key(&public_key)),
+                false => nodes.push(Node::new_blank()),
             }
         }
 
         let tree = Tree {
+            cipher_suite: &SUITE_CURVE25519_AES128GCM_SHA256,
             nodes,
             own_leaf_index: 0,
         };
@@ -476,7 +550,7 @@ fn test_node_key_derivation() {
     let node_secret_hex = "20E029FBE9DE859E7BD6AEA95AC258AE743A9EABCCDE9358420D8C975365938714";
     let mut cursor = Cursor::new(&hex_to_bytes(&node_secret_hex));
     let node_secret = NodeSecret::decode(&mut cursor).unwrap();
-    let node = Node::from_secret(&node_secret);
+    let node = Node::from_secret(&SUITE_CURVE25519_AES128GCM_SHA256, node_secret);
 
     let private_key_hex = "0020E029FBE9DE859E7BD6AEA95AC258AE743A9EABCCDE9358420D8C975365938714";
     let public_key_hex = "00206667B1715A0AD45B0510E850322A8D471D4485EBCBFCC0F3BCCE7BCAE7B44F7F";
@@ -490,3 +564,16 @@ fn test_node_key_derivation() {
         public_key_hex
     );
 }
+
+#[test]
+fn test_node_secret_is_zeroized() {
+    let cipher_suite = &SUITE_CURVE25519_AES128GCM_SHA256;
+    let mut node = Node::from_secret(cipher_suite, NodeSecret::from_bytes(&[0x2A; 32]));
+    assert_eq!(
+        node.get_private_key(cipher_suite).unwrap().as_slice().len(),
+        32
+    );
+
+    node.secret.as_mut().unwrap().zeroize();
+    assert!(is_zeroized(&node.secret.as_ref().unwrap().0));
+}
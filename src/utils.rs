@@ -14,10 +14,56 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see http://www.gnu.org/licenses/.
 
-use sodiumoxide::utils::memzero;
+use std::hint;
+use std::ptr;
+use std::sync::atomic::{compiler_fence, Ordering};
 
+// Volatile writes are not optimized away even if the buffer is freed next
 pub fn erase(ptr: &mut [u8]) {
-    memzero(ptr);
+    for byte in ptr.iter_mut() {
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+// Implemented by every type holding secret material. Drop impls call
+// zeroize(), which also lets tests check that the buffers are wiped.
+pub trait Zeroize {
+    fn zeroize(&mut self);
+}
+
+// Equality for secrets and MACs that does not short-circuit. Only the
+// lengths, which are public, are compared in variable time.
+pub trait ConstantTimeEq {
+    fn ct_eq(&self, other: &Self) -> bool;
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let difference = a
+        .iter()
+        .zip(b.iter())
+        .fold(0u8, |difference, (x, y)| difference | (x ^ y));
+    hint::black_box(difference) == 0
+}
+
+#[cfg(test)]
+pub fn assert_ct_eq<T: ConstantTimeEq>(a: &T, b: &T) {
+    assert!(a.ct_eq(b), "secrets differ");
+}
+
+#[cfg(test)]
+pub fn is_zeroized(bytes: &[u8]) -> bool {
+    bytes.iter().all(|b| *b == 0)
+}
+
+// Tests draw their randomness from a generator seeded with their name, so
+// that their transcripts are stable
+#[cfg(test)]
+pub fn test_rng(name: &str) -> ::crypto::rng::SeededRng {
+    ::crypto::rng::SeededRng::from_seed(name.as_bytes())
 }
 
 pub fn bytes_to_hex(bytes: &[u8]) -> String {
@@ -36,3 +82,11 @@ pub fn hex_to_bytes(hex: &str) -> Vec<u8> {
     }
     bytes
 }
+
+#[test]
+fn test_constant_time_eq() {
+    assert!(constant_time_eq(b"secret", b"secret"));
+    assert!(!constant_time_eq(b"secret", b"secreT"));
+    assert!(!constant_time_eq(b"secret", b"secret!"));
+    assert!(constant_time_eq(b"", b""));
+}